@@ -0,0 +1,4 @@
+// Migrations are built into the binary, so a new or changed one needs a rebuild
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+}
@@ -1 +1 @@
--- Add down migration script here
+DROP TABLE IF EXISTS playlists
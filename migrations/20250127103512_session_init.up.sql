@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS session (
+    id              INTEGER PRIMARY KEY NOT NULL CHECK(id = 0),
+    state           JSON NOT NULL
+)
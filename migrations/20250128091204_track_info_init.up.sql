@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS track_info (
+    uuid            TEXT PRIMARY KEY NOT NULL,
+    added_at        INTEGER NOT NULL
+);
+
+INSERT OR IGNORE INTO track_info (uuid, added_at)
+SELECT uuid, CAST(strftime('%s', 'now') AS INTEGER) FROM tracks;
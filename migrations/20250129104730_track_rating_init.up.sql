@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS track_rating (
+    uuid            TEXT PRIMARY KEY NOT NULL,
+    rating          INTEGER NOT NULL CHECK (rating BETWEEN 1 AND 5)
+);
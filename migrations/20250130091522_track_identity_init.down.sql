@@ -0,0 +1,2 @@
+DROP INDEX IF EXISTS track_identity_audio;
+DROP TABLE IF EXISTS track_identity;
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS track_identity (
+    uuid            TEXT PRIMARY KEY NOT NULL,
+    audio_hash      TEXT NOT NULL,
+    audio_size      INTEGER NOT NULL,
+    duration_ms     INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS track_identity_audio
+ON track_identity (audio_hash, audio_size, duration_ms);
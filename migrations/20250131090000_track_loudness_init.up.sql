@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS track_loudness (
+    uuid            TEXT PRIMARY KEY NOT NULL,
+    loudness        REAL,
+    peak            REAL,
+    album_loudness  REAL,
+    album_peak      REAL,
+    analyzed_at     INTEGER NOT NULL
+);
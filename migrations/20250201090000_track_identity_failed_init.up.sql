@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS track_identity_failed (
+    uuid            TEXT PRIMARY KEY NOT NULL,
+    modified        INTEGER NOT NULL
+);
@@ -0,0 +1,429 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    str::FromStr,
+    thread,
+};
+
+use iced::futures::{SinkExt, Stream};
+use lofty::{file::TaggedFileExt, probe::Probe, tag::Accessor};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{
+    loudness::{self, Measured, Meter},
+    models::track_model::TrackModel,
+    replaygain::ReplayGain,
+    store::LibraryStore,
+    tags, utils,
+};
+
+/// What the analysis job tells the app as it goes
+#[derive(Debug, Clone)]
+pub enum AnalysisEvent {
+    Started(usize),                              // Tracks to analyze
+    Analyzed(usize, Vec<(PathBuf, ReplayGain)>), // Tracks done so far, gains of the album just done
+    Finished,
+    Failed(String),
+}
+
+/// Where the job is, for settings
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum AnalysisState {
+    #[default]
+    Off,
+    Looking, // Finding tracks that have no loudness yet
+    Running {
+        done: usize,
+        total: usize,
+    },
+    Done,
+    Failed(String),
+}
+
+impl AnalysisState {
+    pub fn update(&mut self, event: &AnalysisEvent) {
+        *self = match event {
+            AnalysisEvent::Started(total) => AnalysisState::Running {
+                done: 0,
+                total: *total,
+            },
+            AnalysisEvent::Analyzed(done, _) => match self {
+                AnalysisState::Running { total, .. } => AnalysisState::Running {
+                    done: *done,
+                    total: *total,
+                },
+                _ => return,
+            },
+            AnalysisEvent::Finished => AnalysisState::Done,
+            AnalysisEvent::Failed(err) => AnalysisState::Failed(err.clone()),
+        };
+    }
+}
+
+/// Tracks whose loudness is measured together
+struct Album {
+    tracks: Vec<(Uuid, PathBuf)>,
+    tagged: bool, // Single tracks without album tag get no album gain
+}
+
+/// Measures every track of the library that wasn't measured yet, an album at
+/// a time. Each album is saved when it's done, so the job goes on where it
+/// stopped after a restart. Work is done on a thread of its own at low
+/// priority, which stops soon after the stream is dropped
+pub fn run(store: impl LibraryStore, write_tags: bool) -> impl Stream<Item = AnalysisEvent> {
+    iced::stream::channel(10, move |mut output| async move {
+        let result = analyze(store, write_tags, &mut output).await;
+        let event = match result {
+            Ok(()) => AnalysisEvent::Finished,
+            Err(err) => AnalysisEvent::Failed(err),
+        };
+        let _ = output.send(event).await;
+    })
+}
+
+async fn analyze(
+    store: impl LibraryStore,
+    write_tags: bool,
+    output: &mut iced::futures::channel::mpsc::Sender<AnalysisEvent>,
+) -> Result<(), String> {
+    let tracks = store.tracks().await.map_err(|e| e.to_string())?;
+    let analyzed = store.loudness().await.map_err(|e| e.to_string())?;
+    let (albums, skipped) = tokio::task::spawn_blocking(move || albums(&tracks, &analyzed))
+        .await
+        .map_err(|e| e.to_string())?;
+    // Saved without loudness, so their tags aren't read again on every start
+    if !skipped.is_empty() {
+        let skipped: Vec<_> = skipped.into_iter().map(|uuid| (uuid, None)).collect();
+        store
+            .save_loudness(&skipped)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    let total = albums.iter().map(|album| album.tracks.len()).sum();
+    if output.send(AnalysisEvent::Started(total)).await.is_err() {
+        return Ok(());
+    }
+
+    // Worker stops when this end is dropped
+    let (sender, mut results) = mpsc::channel(1);
+    thread::Builder::new()
+        .name("loudness".to_string())
+        .spawn(move || {
+            utils::lower_priority();
+            for album in albums {
+                let Some(measured) = measure_album(&album, write_tags, || !sender.is_closed())
+                else {
+                    return;
+                };
+                if sender.blocking_send(measured).is_err() {
+                    return;
+                }
+            }
+        })
+        .map_err(|e| e.to_string())?;
+
+    let mut done = 0;
+    while let Some(measured) = results.recv().await {
+        let saved: Vec<(Uuid, Option<Measured>)> = measured
+            .iter()
+            .map(|(uuid, _, measured)| (*uuid, *measured))
+            .collect();
+        store
+            .save_loudness(&saved)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        done += measured.len();
+        let gains = measured
+            .into_iter()
+            .filter_map(|(_, path, measured)| Some((path, measured?.replay_gain())))
+            .collect();
+        if output
+            .send(AnalysisEvent::Analyzed(done, gains))
+            .await
+            .is_err()
+        {
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Albums with a track that has no loudness yet. Albums are tracks with the
+/// same album tag in one folder, tracks without the tag are on their own.
+/// Measured tracks of an album are measured again, album gain needs them all.
+/// Tracks with ReplayGain tags play by them and are left out, unless their
+/// album has no album gain yet. Pending tracks left out come second
+fn albums(
+    tracks: &[TrackModel],
+    analyzed: &HashMap<Uuid, Option<Measured>>,
+) -> (Vec<Album>, Vec<Uuid>) {
+    let tracks: Vec<(Uuid, PathBuf)> = tracks
+        .iter()
+        .filter_map(|track| {
+            Some((
+                Uuid::from_str(&track.uuid).ok()?,
+                PathBuf::from(&track.path),
+            ))
+        })
+        .collect();
+
+    let pending: HashSet<Uuid> = tracks
+        .iter()
+        .map(|(uuid, _)| *uuid)
+        .filter(|uuid| !analyzed.contains_key(uuid))
+        .collect();
+    let folders: HashSet<&Path> = tracks
+        .iter()
+        .filter(|(uuid, _)| pending.contains(uuid))
+        .filter_map(|(_, path)| path.parent())
+        .collect();
+
+    // Only tags of tracks next to pending ones are read
+    let mut albums: BTreeMap<(PathBuf, String), Vec<(Uuid, PathBuf)>> = BTreeMap::new();
+    let mut needed = HashSet::new(); // Albums with a pending track to measure
+    let mut singles = vec![];
+    for (uuid, path) in &tracks {
+        let Some(folder) = path.parent().filter(|folder| folders.contains(folder)) else {
+            continue;
+        };
+        let pending = pending.contains(uuid);
+        let (album, gain) = tags_of(path);
+        match album {
+            Some(album) => {
+                let key = (folder.to_path_buf(), album);
+                if pending && gain.album_gain.is_none() {
+                    needed.insert(key.clone());
+                }
+                albums.entry(key).or_default().push((*uuid, path.clone()));
+            }
+            None if pending && !gain.has_gain() => singles.push((*uuid, path.clone())),
+            None => {}
+        }
+    }
+
+    let albums = albums
+        .into_iter()
+        .filter(|(key, _)| needed.contains(key))
+        .map(|(_, tracks)| Album {
+            tracks,
+            tagged: true,
+        });
+    let singles = singles.into_iter().map(|track| Album {
+        tracks: vec![track],
+        tagged: false,
+    });
+    let albums: Vec<Album> = albums.chain(singles).collect();
+
+    let measured: HashSet<Uuid> = albums
+        .iter()
+        .flat_map(|album| album.tracks.iter().map(|(uuid, _)| *uuid))
+        .collect();
+    let skipped = pending
+        .into_iter()
+        .filter(|uuid| !measured.contains(uuid))
+        .collect();
+    (albums, skipped)
+}
+
+/// Album tag and ReplayGain of a file, read once for both
+fn tags_of(path: &Path) -> (Option<String>, ReplayGain) {
+    let Ok(file) = Probe::open(path).and_then(|probe| probe.read()) else {
+        return (None, ReplayGain::default());
+    };
+    let album = file
+        .primary_tag()
+        .or_else(|| file.first_tag())
+        .and_then(|tag| tag.album().map(|album| album.trim().to_string()))
+        .filter(|album| !album.is_empty());
+    (album, ReplayGain::from_file(&file))
+}
+
+/// Loudness of every track, None for ones that couldn't be decoded or are
+/// silent. Gives nothing when stopped halfway, so the album is done again
+fn measure_album(
+    album: &Album,
+    write_tags: bool,
+    keep_going: impl Fn() -> bool,
+) -> Option<Vec<(Uuid, PathBuf, Option<Measured>)>> {
+    let mut meters = vec![];
+    for (_, path) in &album.tracks {
+        match loudness::measure(path, &keep_going) {
+            Ok(meter) => meters.push(Some(meter)),
+            Err(_) if !keep_going() => return None,
+            Err(err) => {
+                println!("Unable to measure {path:?}: {err}");
+                meters.push(None);
+            }
+        }
+    }
+
+    let measured_meters = || meters.iter().flatten();
+    let album_loudness = album
+        .tagged
+        .then(|| loudness::album_loudness(measured_meters()))
+        .flatten();
+    let album_peak = measured_meters().map(Meter::peak).reduce(f64::max);
+
+    let results = album
+        .tracks
+        .iter()
+        .zip(&meters)
+        .map(|((uuid, path), meter)| {
+            let measured = meter.as_ref().and_then(|meter| {
+                Some(Measured {
+                    loudness: meter.loudness()? as f32,
+                    peak: meter.peak() as f32,
+                    album_loudness: album_loudness.map(|l| l as f32),
+                    album_peak: album_loudness.and(album_peak).map(|p| p as f32),
+                })
+            });
+            if let Some(measured) = measured.filter(|_| write_tags) {
+                write_gain(path, &measured);
+            }
+            (*uuid, path.clone(), measured)
+        })
+        .collect();
+    Some(results)
+}
+
+/// Only files without ReplayGain tags get ours
+fn write_gain(path: &Path, measured: &Measured) {
+    if ReplayGain::read(path).has_gain() {
+        return;
+    }
+    if let Err(err) = tags::write_replay_gain(path, &measured.replay_gain()) {
+        println!("Unable to write ReplayGain to {path:?}: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::test_utils::{self, TempDir};
+    use lofty::{
+        config::WriteOptions,
+        tag::{ItemKey, Tag, TagExt, TagType},
+    };
+
+    use super::*;
+
+    /// Half a second of a tone with given peak, tagged with `album`
+    fn tone(dir: &Path, name: &str, amplitude: f32, album: Option<&str>) -> TrackModel {
+        let path = dir.join(name);
+        test_utils::tone(&path, 997.0, Duration::from_millis(500), amplitude);
+
+        if let Some(album) = album {
+            let mut tag = Tag::new(TagType::Id3v2);
+            tag.set_album(album.to_string());
+            tag.save_to_path(&path, WriteOptions::default()).unwrap();
+        }
+
+        TrackModel {
+            uuid: Uuid::new_v4().to_string(),
+            path: path.to_string_lossy().to_string(),
+            play_count: 0,
+            play_minutes: 0.0,
+            added_at: None,
+            rating: None,
+        }
+    }
+
+    /// Adds a ReplayGain tag next to the album tag
+    fn tag_gain(track: &TrackModel, album: &str, key: ItemKey) {
+        let mut tag = Tag::new(TagType::Id3v2);
+        tag.set_album(album.to_string());
+        tag.insert_text(key, "-6.00 dB".to_string());
+        tag.save_to_path(&track.path, WriteOptions::default())
+            .unwrap();
+    }
+
+    fn uuid(track: &TrackModel) -> Uuid {
+        Uuid::from_str(&track.uuid).unwrap()
+    }
+
+    fn sizes(albums: &[Album]) -> Vec<usize> {
+        albums.iter().map(|album| album.tracks.len()).collect()
+    }
+
+    #[test]
+    fn albums_with_a_new_track_are_measured_whole() {
+        let dir = TempDir::new();
+        let tracks = [
+            tone(&dir.0, "a.wav", 0.5, Some("First")),
+            tone(&dir.0, "b.wav", 0.5, Some("First")),
+            tone(&dir.0, "c.wav", 0.5, None),
+        ];
+
+        let (found, skipped) = albums(&tracks, &HashMap::new());
+        assert_eq!(sizes(&found), [2, 1]);
+        assert!(found[0].tagged && !found[1].tagged);
+        assert!(skipped.is_empty());
+
+        let analyzed = HashMap::from([(uuid(&tracks[0]), None), (uuid(&tracks[2]), None)]);
+        assert_eq!(sizes(&albums(&tracks, &analyzed).0), [2]);
+
+        let analyzed: HashMap<_, _> = tracks.iter().map(|t| (uuid(t), None)).collect();
+        assert!(albums(&tracks, &analyzed).0.is_empty());
+    }
+
+    #[test]
+    fn tracks_with_gain_tags_are_left_out() {
+        let dir = TempDir::new();
+        let single = tone(&dir.0, "single.wav", 0.5, None);
+        tag_gain(&single, "", ItemKey::ReplayGainTrackGain);
+        let (found, skipped) = albums(std::slice::from_ref(&single), &HashMap::new());
+        assert!(found.is_empty());
+        assert_eq!(skipped, [uuid(&single)]);
+
+        let tracks = [
+            tone(&dir.0, "a.wav", 0.5, Some("First")),
+            tone(&dir.0, "b.wav", 0.5, Some("First")),
+        ];
+        tag_gain(&tracks[0], "First", ItemKey::ReplayGainAlbumGain);
+        tag_gain(&tracks[1], "First", ItemKey::ReplayGainAlbumGain);
+        let (found, skipped) = albums(&tracks, &HashMap::new());
+        assert!(found.is_empty());
+        assert_eq!(skipped.len(), 2);
+
+        // Track gain alone leaves the album without album gain
+        tag_gain(&tracks[1], "First", ItemKey::ReplayGainTrackGain);
+        assert_eq!(sizes(&albums(&tracks, &HashMap::new()).0), [2]);
+    }
+
+    #[test]
+    fn album_gain_and_tags() {
+        let dir = TempDir::new();
+        let tracks = [
+            tone(&dir.0, "loud.wav", 0.5, Some("First")),
+            tone(&dir.0, "quiet.wav", 0.25, Some("First")),
+        ];
+        let album = &albums(&tracks, &HashMap::new()).0[0];
+
+        let measured = measure_album(album, true, || true).unwrap();
+        let loud = measured[0].2.unwrap();
+        let quiet = measured[1].2.unwrap();
+        // Stereo tone with peak at -6 dBFS is about -6 LUFS
+        assert!((loud.loudness + 6.0).abs() < 0.5, "{loud:?}");
+        assert!(
+            (quiet.loudness - loud.loudness + 6.0).abs() < 0.5,
+            "{quiet:?}"
+        );
+        assert_eq!(loud.album_loudness, quiet.album_loudness);
+        assert!(loud.album_loudness.unwrap() < loud.loudness);
+        assert!(loud.album_loudness.unwrap() > quiet.loudness);
+        assert_eq!(quiet.album_peak, Some(loud.peak));
+
+        let tagged = ReplayGain::read(&measured[1].1);
+        let gain = quiet.replay_gain();
+        assert!((tagged.track_gain.unwrap() - gain.track_gain.unwrap()).abs() < 0.01);
+        assert!((tagged.album_peak.unwrap() - loud.peak).abs() < 0.001);
+
+        // Stopped halfway gives nothing to save
+        assert!(measure_album(album, false, || false).is_none());
+    }
+}
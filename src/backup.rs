@@ -0,0 +1,479 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    ffi::{CStr, CString},
+    fs,
+    path::{Path, PathBuf},
+    ptr, thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use iced::{
+    widget::{button, column, container, horizontal_space, row, scrollable, text, Column},
+    Alignment, Element, Length,
+};
+use libsqlite3_sys as ffi;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::{
+    db::DbError, identity::Identity, models::track_model::TrackModel, playlist::Playlist,
+    store::LibraryStore, theme, track::Track, utils,
+};
+
+/// Automatic backups kept, older ones are deleted
+pub const KEEP_BACKUPS: usize = 7;
+// Automatic backup is made on start when the last one is older than this
+const BACKUP_EVERY: Duration = Duration::from_secs(24 * 60 * 60);
+// Format of export files, raised on changes old versions can't read
+pub const EXPORT_VERSION: u32 = 1;
+// Tracks matched by tags may differ this much in length, encoders pad differently
+const DURATION_SLACK_MS: i64 = 2000;
+
+#[derive(Debug, Clone)]
+pub enum BackupMessage {
+    BackUp,
+    Export,
+    Import,
+    Close,
+}
+
+/// Folder of database backups, in the data directory
+pub fn dir() -> PathBuf {
+    let data_dir = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .unwrap_or_default();
+
+    data_dir.join("music_player").join("backups")
+}
+
+/// Backups in `dir`, newest first
+pub fn list(dir: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+
+    let mut backups: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "sql"))
+        .collect();
+    backups.sort_by_key(|path| std::cmp::Reverse(created_at(path)));
+    backups
+}
+
+/// Copies the whole database into a new file in `dir` while it stays in use.
+/// `kind` starts the file name, automatic ones are rotated
+pub async fn back_up(pool: &SqlitePool, dir: &Path, kind: &str) -> Result<PathBuf, DbError> {
+    fs::create_dir_all(dir).map_err(|e| DbError::Backup(e.to_string()))?;
+
+    let now = unix_now();
+    let path = dir.join(format!("{kind}-{}-{now}.sql", utils::format_date(now)));
+    // Written next to the final name first, so a failed backup never looks like a good one
+    let partial = path.with_extension("partial");
+    let source = CString::new(
+        pool.connect_options()
+            .get_filename()
+            .to_string_lossy()
+            .as_bytes(),
+    )
+    .map_err(|e| DbError::Backup(e.to_string()))?;
+    let target = CString::new(partial.to_string_lossy().as_bytes())
+        .map_err(|e| DbError::Backup(e.to_string()))?;
+
+    // Copy blocks until it's done, so it runs off the async workers
+    let result = tokio::task::spawn_blocking(move || copy(&source, &target))
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|result| result);
+
+    match result.and_then(|_| fs::rename(&partial, &path).map_err(|e| e.to_string())) {
+        Ok(()) => Ok(path),
+        Err(err) => {
+            let _ = fs::remove_file(&partial);
+            Err(DbError::Backup(err))
+        }
+    }
+}
+
+/// Makes an automatic backup when the last one is old enough, then deletes
+/// ones past `KEEP_BACKUPS`. Returns the new backup, if one was made
+pub async fn auto_back_up(
+    store: &impl LibraryStore,
+    dir: &Path,
+) -> Result<Option<PathBuf>, DbError> {
+    let last = list(dir)
+        .into_iter()
+        .filter(|path| is_auto(path))
+        .map(|path| created_at(&path))
+        .max();
+    if last.is_some_and(|last| unix_now() - last < BACKUP_EVERY.as_secs() as i64) {
+        return Ok(None);
+    }
+
+    let path = store.back_up(dir, "auto").await?;
+    let old = list(dir)
+        .into_iter()
+        .filter(|path| is_auto(path))
+        .skip(KEEP_BACKUPS);
+    for path in old {
+        if let Err(err) = fs::remove_file(&path) {
+            println!("Unable to delete old backup {path:?}: {err}");
+        }
+    }
+
+    Ok(Some(path))
+}
+
+/// SQLite online backup from database file at `source` into file at `target`.
+/// Pages are copied all at once, retried while other connections write
+fn copy(source: &CStr, target: &CStr) -> Result<(), String> {
+    let mut from = ptr::null_mut();
+    let mut db = ptr::null_mut();
+
+    // SAFETY: both connections are opened here and used only by this thread,
+    // each is closed on every path and `backup` is finished before that
+    unsafe {
+        let flags = ffi::SQLITE_OPEN_READONLY;
+        if ffi::sqlite3_open_v2(source.as_ptr(), &mut from, flags, ptr::null()) != ffi::SQLITE_OK {
+            let err = error_message(from);
+            ffi::sqlite3_close(from);
+            return Err(err);
+        }
+
+        let flags = ffi::SQLITE_OPEN_READWRITE | ffi::SQLITE_OPEN_CREATE;
+        if ffi::sqlite3_open_v2(target.as_ptr(), &mut db, flags, ptr::null()) != ffi::SQLITE_OK {
+            let err = error_message(db);
+            ffi::sqlite3_close(db);
+            ffi::sqlite3_close(from);
+            return Err(err);
+        }
+
+        let main = c"main".as_ptr();
+        let backup = ffi::sqlite3_backup_init(db, main, from, main);
+        if backup.is_null() {
+            let err = error_message(db);
+            ffi::sqlite3_close(db);
+            ffi::sqlite3_close(from);
+            return Err(err);
+        }
+
+        let mut attempts = 0;
+        let code = loop {
+            match ffi::sqlite3_backup_step(backup, -1) {
+                ffi::SQLITE_BUSY | ffi::SQLITE_LOCKED if attempts < 20 => {
+                    attempts += 1;
+                    thread::sleep(Duration::from_millis(50));
+                }
+                code => break code,
+            }
+        };
+
+        ffi::sqlite3_backup_finish(backup);
+        let result = match code {
+            ffi::SQLITE_DONE => Ok(()),
+            _ => Err(error_message(db)),
+        };
+        ffi::sqlite3_close(db);
+        ffi::sqlite3_close(from);
+        result
+    }
+}
+
+/// # Safety
+/// `db` must be a connection handle from `sqlite3_open_v2`, even a failed one
+unsafe fn error_message(db: *mut ffi::sqlite3) -> String {
+    if db.is_null() {
+        return "Out of memory".to_string();
+    }
+    CStr::from_ptr(ffi::sqlite3_errmsg(db))
+        .to_string_lossy()
+        .to_string()
+}
+
+fn is_auto(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with("auto-"))
+}
+
+/// Unix time at the end of backup file name
+fn created_at(path: &Path) -> i64 {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.rsplit('-').next())
+        .and_then(|time| time.parse().ok())
+        .unwrap_or_default()
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// What user built up for a track, the part worth carrying to a new install.
+/// Listening history is only these totals, no log of single plays is kept
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Stats {
+    pub play_count: i64,
+    pub play_minutes: f64,
+    pub added_at: Option<i64>,
+    pub rating: Option<u8>,
+}
+
+/// Track in export. Found again in other library by its path under library
+/// folder, by its audio or by its tags, whichever matches first
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedTrack {
+    pub path: String, // Relative to library folder
+    pub title: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration_ms: i64,
+    pub audio_hash: Option<String>,
+    #[serde(flatten)]
+    pub stats: Stats,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedPlaylist {
+    pub title: String,
+    pub tracks: Vec<String>, // Paths of exported tracks, in order
+}
+
+/// All user data of a library as a JSON document, not tied to this machine
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Export {
+    pub version: u32,
+    pub tracks: Vec<ExportedTrack>,
+    pub playlists: Vec<ExportedPlaylist>,
+}
+
+/// Export matched with a library, ready to be saved
+#[derive(Debug, Clone, Default)]
+pub struct Import {
+    pub stats: Vec<(Uuid, Stats)>,
+    pub playlists: Vec<(String, Vec<Uuid>)>,
+    pub unmatched: usize, // Exported tracks that aren't in this library
+}
+
+/// Tags come from loaded tracks, stats from db rows. Tracks whose file
+/// couldn't be read aren't loaded and are left out
+pub fn export(
+    root: &Path,
+    tracks: &[Track],
+    models: Vec<TrackModel>,
+    identities: &HashMap<Uuid, Identity>,
+    playlists: &[Playlist],
+) -> Export {
+    let models: HashMap<String, TrackModel> = models
+        .into_iter()
+        .map(|model| (model.uuid.clone(), model))
+        .collect();
+
+    let mut paths = HashMap::new();
+    let tracks: Vec<ExportedTrack> = tracks
+        .iter()
+        .filter_map(|track| {
+            let model = models.get(&track.uuid.to_string())?;
+            let path = relative(root, &track.path);
+            paths.insert(track.uuid, path.clone());
+
+            Some(ExportedTrack {
+                path,
+                title: track.name.clone(),
+                artist: track.artist.clone(),
+                album: track.album.clone(),
+                duration_ms: track.duration.as_millis() as i64,
+                audio_hash: identities.get(&track.uuid).map(|i| i.audio_hash.clone()),
+                stats: Stats {
+                    play_count: model.play_count,
+                    play_minutes: model.play_minutes,
+                    added_at: model.added_at,
+                    rating: model.rating.map(|r| r.clamp(1, 5) as u8),
+                },
+            })
+        })
+        .collect();
+
+    let playlists = playlists
+        .iter()
+        .map(|playlist| ExportedPlaylist {
+            title: playlist.title.clone(),
+            tracks: playlist
+                .tracks
+                .iter()
+                .filter_map(|uuid| paths.get(uuid).cloned())
+                .collect(),
+        })
+        .collect();
+
+    Export {
+        version: EXPORT_VERSION,
+        tracks,
+        playlists,
+    }
+}
+
+/// Finds exported tracks in this library. Each library track takes at most
+/// one exported track
+pub fn plan_import(
+    export: Export,
+    root: &Path,
+    tracks: &[Track],
+    identities: &HashMap<Uuid, Identity>,
+) -> Result<Import, String> {
+    if export.version > EXPORT_VERSION {
+        return Err(format!(
+            "Export is from a newer version ({}) of the player",
+            export.version
+        ));
+    }
+
+    let by_path: HashMap<String, Uuid> = tracks
+        .iter()
+        .map(|track| (relative(root, &track.path), track.uuid))
+        .collect();
+    let mut by_hash: HashMap<&str, Vec<Uuid>> = HashMap::new();
+    for (uuid, identity) in identities {
+        by_hash.entry(&identity.audio_hash).or_default().push(*uuid);
+    }
+
+    let mut taken = HashSet::new();
+    let mut found: HashMap<String, Uuid> = HashMap::new();
+    let mut import = Import::default();
+
+    for exported in &export.tracks {
+        let free = |uuid: &&Uuid| !taken.contains(*uuid);
+        let uuid = by_path
+            .get(&exported.path)
+            .filter(free)
+            .or_else(|| {
+                let hash = exported.audio_hash.as_deref()?;
+                by_hash.get(hash)?.iter().find(free)
+            })
+            .or_else(|| {
+                tracks
+                    .iter()
+                    .filter(|track| same_tags(exported, track))
+                    .map(|track| &track.uuid)
+                    .find(free)
+            })
+            .copied();
+
+        match uuid {
+            Some(uuid) => {
+                taken.insert(uuid);
+                found.insert(exported.path.clone(), uuid);
+                import.stats.push((uuid, exported.stats.clone()));
+            }
+            None => import.unmatched += 1,
+        }
+    }
+
+    import.playlists = export
+        .playlists
+        .into_iter()
+        .map(|playlist| {
+            let tracks = playlist
+                .tracks
+                .iter()
+                .filter_map(|path| found.get(path).copied())
+                .collect();
+            (playlist.title, tracks)
+        })
+        .collect();
+
+    Ok(import)
+}
+
+fn same_tags(exported: &ExportedTrack, track: &Track) -> bool {
+    let same = |a: &str, b: &str| a.trim().eq_ignore_ascii_case(b.trim());
+    let same_album = match (&exported.album, &track.album) {
+        (Some(a), Some(b)) => same(a, b),
+        _ => true,
+    };
+
+    same(&exported.title, &track.name)
+        && same(
+            exported.artist.as_deref().unwrap_or_default(),
+            track.artist.as_deref().unwrap_or_default(),
+        )
+        && same_album
+        && (exported.duration_ms - track.duration.as_millis() as i64).abs() <= DURATION_SLACK_MS
+}
+
+/// Path with `/` separators under library folder, so exports move between systems
+fn relative(root: &Path, path: &Path) -> String {
+    let path = path.strip_prefix(root).unwrap_or(path);
+    path.components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Backups and moving user data between installs
+pub fn view<'a>(
+    backups: &'a [PathBuf],
+    status: Option<&'a str>,
+    busy: bool,
+) -> Element<'a, BackupMessage> {
+    let rows = backups.iter().map(|path| {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let size = fs::metadata(path).map(|m| m.len()).unwrap_or_default();
+
+        row![
+            text(name).width(Length::Fill),
+            text(format!("{} KB", size.div_ceil(1024)))
+                .size(12)
+                .style(theme::muted),
+        ]
+        .spacing(10)
+        .into()
+    });
+
+    let header = row![
+        text("Backups").size(20),
+        horizontal_space(),
+        button("x").on_press(BackupMessage::Close)
+    ];
+
+    let idle = |message| (!busy).then_some(message);
+    let actions = row![
+        button("Back up now").on_press_maybe(idle(BackupMessage::BackUp)),
+        horizontal_space(),
+        button("Export...")
+            .style(button::secondary)
+            .on_press_maybe(idle(BackupMessage::Export)),
+        button("Import...")
+            .style(button::secondary)
+            .on_press_maybe(idle(BackupMessage::Import)),
+    ]
+    .spacing(10)
+    .align_y(Alignment::Center);
+
+    let content = column![
+        header,
+        text(format!(
+            "Kept in {}, last {KEEP_BACKUPS} automatic ones",
+            dir().display()
+        ))
+        .size(12)
+        .style(theme::muted),
+        scrollable(Column::with_children(rows).spacing(5)).height(250),
+        text(status.unwrap_or_default()).size(12),
+        actions,
+    ]
+    .spacing(10);
+
+    container(content)
+        .padding(15)
+        .width(600)
+        .style(container::rounded_box)
+        .into()
+}
@@ -0,0 +1,356 @@
+use std::cmp::Ordering;
+
+use iced::{
+    mouse,
+    widget::{button, checkbox, column, container, mouse_area, row, text, Column, Row, Space},
+    Element, Length,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{theme, track::Track, utils::format_date};
+
+pub const DIVIDER_WIDTH: f32 = 4.0;
+const MIN_WIDTH: f32 = 40.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum TrackColumn {
+    Title,
+    Artist,
+    Album,
+    Year,
+    Genre,
+    Duration,
+    PlayCount,
+    Rating,
+    DateAdded,
+    Bitrate,
+    Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ColumnSpec {
+    pub column: TrackColumn,
+    pub width: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SortKey {
+    pub column: TrackColumn,
+    pub order: SortOrder,
+}
+
+/// Visible columns of the track list and how it is sorted.
+/// Saved in config for every view (library and each playlist)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnLayout {
+    pub columns: Vec<ColumnSpec>,
+    pub sort: Vec<SortKey>, // First key is primary, the rest break ties
+}
+
+#[derive(Debug, Clone)]
+pub enum ColumnMessage {
+    Sort(TrackColumn),
+    ToggleMenu,
+    ToggleColumn(TrackColumn, bool),
+    ResizeStart(TrackColumn),
+    CursorMoved(f32),
+    ResizeEnd,
+}
+
+/// Transient state of the header that is not worth saving
+#[derive(Debug, Clone, Default)]
+pub struct ColumnHeader {
+    pub show_menu: bool,
+    cursor_x: f32,
+    resizing: Option<(TrackColumn, f32, f32)>, // Column, cursor x and width on start
+}
+
+impl TrackColumn {
+    pub const ALL: [TrackColumn; 11] = [
+        TrackColumn::Title,
+        TrackColumn::Artist,
+        TrackColumn::Album,
+        TrackColumn::Year,
+        TrackColumn::Genre,
+        TrackColumn::Duration,
+        TrackColumn::PlayCount,
+        TrackColumn::Rating,
+        TrackColumn::DateAdded,
+        TrackColumn::Bitrate,
+        TrackColumn::Format,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            TrackColumn::Title => "Title",
+            TrackColumn::Artist => "Artist",
+            TrackColumn::Album => "Album",
+            TrackColumn::Year => "Year",
+            TrackColumn::Genre => "Genre",
+            TrackColumn::Duration => "Duration",
+            TrackColumn::PlayCount => "Plays",
+            TrackColumn::Rating => "Rating",
+            TrackColumn::DateAdded => "Added",
+            TrackColumn::Bitrate => "Bitrate",
+            TrackColumn::Format => "Format",
+        }
+    }
+
+    fn default_width(&self) -> f32 {
+        match self {
+            TrackColumn::Title => 300.0,
+            TrackColumn::Artist | TrackColumn::Album => 180.0,
+            TrackColumn::Genre | TrackColumn::DateAdded => 110.0,
+            _ => 70.0,
+        }
+    }
+
+    /// Text shown in the cell of this column for given track
+    pub fn cell(&self, track: &Track) -> String {
+        let opt = |value: Option<String>| value.unwrap_or_default();
+        match self {
+            TrackColumn::Title => track.name.clone(),
+            TrackColumn::Artist => opt(track.artist.clone()),
+            TrackColumn::Album => opt(track.album.clone()),
+            TrackColumn::Year => opt(track.year.map(|y| y.to_string())),
+            TrackColumn::Genre => opt(track.genre.clone()),
+            TrackColumn::Duration => track.duration_str.clone(),
+            TrackColumn::PlayCount => track.play_count.to_string(),
+            TrackColumn::Rating => opt(track.rating.map(|r| "*".repeat(r as usize))),
+            TrackColumn::DateAdded => opt(track.added_at.map(format_date)),
+            TrackColumn::Bitrate => opt(track.bitrate.map(|b| format!("{b} kbps"))),
+            TrackColumn::Format => track.format.clone(),
+        }
+    }
+
+    /// Compares by this column alone. Missing values go last
+    pub fn compare(&self, a: &Track, b: &Track) -> Ordering {
+        fn opt<T: Ord>(a: Option<T>, b: Option<T>) -> Ordering {
+            match (a, b) {
+                (Some(a), Some(b)) => a.cmp(&b),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            }
+        }
+        let lower = |s: &Option<String>| s.as_ref().map(|s| s.to_lowercase());
+
+        match self {
+            TrackColumn::Title => a.name.to_lowercase().cmp(&b.name.to_lowercase()),
+            TrackColumn::Artist => opt(lower(&a.artist), lower(&b.artist)),
+            TrackColumn::Album => opt(lower(&a.album), lower(&b.album)),
+            TrackColumn::Year => opt(a.year, b.year),
+            TrackColumn::Genre => opt(lower(&a.genre), lower(&b.genre)),
+            TrackColumn::Duration => a.duration.cmp(&b.duration),
+            TrackColumn::PlayCount => a.play_count.cmp(&b.play_count),
+            TrackColumn::Rating => opt(a.rating, b.rating),
+            TrackColumn::DateAdded => opt(a.added_at, b.added_at),
+            TrackColumn::Bitrate => opt(a.bitrate, b.bitrate),
+            TrackColumn::Format => a.format.cmp(&b.format),
+        }
+    }
+}
+
+impl Default for ColumnLayout {
+    fn default() -> Self {
+        let columns = [
+            TrackColumn::Title,
+            TrackColumn::Artist,
+            TrackColumn::Album,
+            TrackColumn::Duration,
+        ]
+        .into_iter()
+        .map(|column| ColumnSpec {
+            column,
+            width: column.default_width(),
+        })
+        .collect();
+
+        Self {
+            columns,
+            sort: vec![],
+        }
+    }
+}
+
+impl ColumnLayout {
+    /// Compares tracks by every sort key in turn
+    pub fn compare(&self, a: &Track, b: &Track) -> Ordering {
+        self.sort
+            .iter()
+            .map(|key| match key.order {
+                SortOrder::Asc => key.column.compare(a, b),
+                SortOrder::Desc => key.column.compare(a, b).reverse(),
+            })
+            .find(|ord| ord.is_ne())
+            .unwrap_or(Ordering::Equal)
+    }
+
+    /// Click on a header. Sorted column flips its order, any other becomes
+    /// primary key and pushes previous keys down to break ties
+    fn sort_by(&mut self, column: TrackColumn) {
+        if let Some(key) = self.sort.first_mut().filter(|key| key.column == column) {
+            key.order = match key.order {
+                SortOrder::Asc => SortOrder::Desc,
+                SortOrder::Desc => SortOrder::Asc,
+            };
+            return;
+        }
+
+        self.sort.retain(|key| key.column != column);
+        self.sort.insert(
+            0,
+            SortKey {
+                column,
+                order: SortOrder::Asc,
+            },
+        );
+        self.sort.truncate(3);
+    }
+
+    fn set_visible(&mut self, column: TrackColumn, visible: bool) {
+        // Title is what rows are selected by, so it always stays
+        if column == TrackColumn::Title {
+            return;
+        }
+
+        self.columns.retain(|spec| spec.column != column);
+        if visible {
+            // Keep columns in the same order as they are in the menu
+            let idx = self
+                .columns
+                .iter()
+                .position(|spec| {
+                    TrackColumn::ALL.iter().position(|c| *c == spec.column)
+                        > TrackColumn::ALL.iter().position(|c| *c == column)
+                })
+                .unwrap_or(self.columns.len());
+
+            self.columns.insert(
+                idx,
+                ColumnSpec {
+                    column,
+                    width: column.default_width(),
+                },
+            );
+        }
+    }
+
+    /// Track cells lined up with the header
+    pub fn row<'a, Message: 'a>(
+        &self,
+        track: &Track,
+        title: Element<'a, Message>,
+    ) -> Row<'a, Message> {
+        let mut title = Some(title);
+        let mut row = Row::new();
+        for spec in &self.columns {
+            let cell: Element<'a, Message> = match spec.column {
+                TrackColumn::Title => title.take().unwrap_or_else(|| text("").into()),
+                column => text(column.cell(track)).style(theme::muted).into(),
+            };
+
+            row = row
+                .push(container(cell).width(spec.width).clip(true))
+                .push(Space::with_width(DIVIDER_WIDTH));
+        }
+
+        row
+    }
+}
+
+impl ColumnHeader {
+    /// Returns true when layout changed and should be saved
+    pub fn update(&mut self, layout: &mut ColumnLayout, message: ColumnMessage) -> bool {
+        match message {
+            ColumnMessage::Sort(column) => {
+                layout.sort_by(column);
+                true
+            }
+            ColumnMessage::ToggleMenu => {
+                self.show_menu = !self.show_menu;
+                false
+            }
+            ColumnMessage::ToggleColumn(column, visible) => {
+                layout.set_visible(column, visible);
+                true
+            }
+            ColumnMessage::ResizeStart(column) => {
+                if let Some(spec) = layout.columns.iter().find(|spec| spec.column == column) {
+                    self.resizing = Some((column, self.cursor_x, spec.width));
+                }
+                false
+            }
+            ColumnMessage::CursorMoved(x) => {
+                self.cursor_x = x;
+                if let Some((column, start_x, start_width)) = self.resizing {
+                    if let Some(spec) = layout.columns.iter_mut().find(|s| s.column == column) {
+                        spec.width = (start_width + x - start_x).max(MIN_WIDTH);
+                    }
+                }
+                false
+            }
+            ColumnMessage::ResizeEnd => self.resizing.take().is_some(),
+        }
+    }
+
+    pub fn view<'a>(&'a self, layout: &'a ColumnLayout) -> Element<'a, ColumnMessage> {
+        let mut header = Row::new();
+        for spec in &layout.columns {
+            let arrow = match layout.sort.first() {
+                Some(key) if key.column == spec.column => match key.order {
+                    SortOrder::Asc => " ^",
+                    SortOrder::Desc => " v",
+                },
+                _ => "",
+            };
+
+            let title = button(text(format!("{}{arrow}", spec.column.label())))
+                .style(button::text)
+                .on_press(ColumnMessage::Sort(spec.column))
+                .width(spec.width);
+
+            let divider = mouse_area(
+                container(Space::new(DIVIDER_WIDTH, Length::Fill)).style(container::dark),
+            )
+            .on_press(ColumnMessage::ResizeStart(spec.column))
+            .interaction(mouse::Interaction::ResizingHorizontally);
+
+            header = header.push(title).push(divider);
+        }
+
+        let header = mouse_area(
+            row![
+                header.height(30),
+                button("Columns").on_press(ColumnMessage::ToggleMenu)
+            ]
+            .spacing(10),
+        )
+        .on_move(|point| ColumnMessage::CursorMoved(point.x))
+        .on_release(ColumnMessage::ResizeEnd);
+
+        let mut content = column![header];
+
+        if self.show_menu {
+            let toggles = TrackColumn::ALL.iter().map(|column| {
+                let visible = layout.columns.iter().any(|spec| spec.column == *column);
+                checkbox(column.label(), visible)
+                    .on_toggle_maybe(
+                        (*column != TrackColumn::Title)
+                            .then_some(|visible| ColumnMessage::ToggleColumn(*column, visible)),
+                    )
+                    .into()
+            });
+
+            content = content.push(Column::with_children(toggles).spacing(5).padding(5));
+        }
+
+        content.into()
+    }
+}
@@ -0,0 +1,77 @@
+use std::{collections::HashMap, env, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    columns::ColumnLayout, keybindings::Action, replaygain::GainSettings, theme::Appearance,
+};
+
+/// User preferences kept in `config.toml` in the config directory
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Also put ratings into files as POPM and FMPS_Rating
+    pub write_rating_tags: bool,
+    /// Where organizer moves files, relative to the library folder
+    pub organize_pattern: String,
+    /// Output device by name, default device when not set
+    pub output_device: Option<String>,
+    /// Level tracks by their ReplayGain tags
+    pub replay_gain: GainSettings,
+    /// Measure loudness of tracks in background, for ones without ReplayGain tags
+    pub analyze_loudness: bool,
+    /// Also put what analysis measured into files without ReplayGain tags
+    pub write_gain_tags: bool,
+
+    pub columns: HashMap<String, ColumnLayout>, // Keyed by view, see `Config::columns`
+    pub keys: HashMap<Action, String>,          // Only keys changed from defaults
+    pub appearance: Appearance,
+}
+
+impl Config {
+    pub fn path() -> PathBuf {
+        let config_dir = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+            .unwrap_or_default();
+
+        config_dir.join("music_player").join("config.toml")
+    }
+
+    /// Missing or broken config gives defaults, app should start anyway
+    pub fn load() -> Config {
+        let path = Self::path();
+        let Ok(content) = std::fs::read_to_string(&path) else {
+            return Config::default();
+        };
+
+        toml::from_str(&content)
+            .map_err(|e| eprintln!("Unable to read config {path:?}: {e}"))
+            .unwrap_or_default()
+    }
+
+    pub async fn save(self) -> Result<(), String> {
+        let path = Self::path();
+        let content = toml::to_string_pretty(&self).map_err(|e| e.to_string())?;
+
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir)
+                .await
+                .map_err(|e| e.to_string())?;
+        }
+        tokio::fs::write(&path, content)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    /// Column layout of given view. Views without own layout use the library one
+    pub fn columns(&self, view: &str) -> ColumnLayout {
+        self.columns
+            .get(view)
+            .or_else(|| self.columns.get(LIBRARY_VIEW))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+pub const LIBRARY_VIEW: &str = "library";
@@ -1,38 +1,102 @@
-use std::path::PathBuf;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use crate::{
-    models::{playlist_model::*, track_model::TrackModel},
-    playlist::Playlist,
-    track::Track,
-    utils::path_buf_vec_to_string,
+    backup::Import,
+    identity::{self, Identity},
+    loudness::Measured,
+    models::{playlist_model::*, session_model::SessionModel, track_model::TrackModel},
+    playlist::{Playlist, LIKED},
 };
 use serde_json::Value;
-use sqlx::{query, SqlitePool};
+use sqlx::{
+    migrate::{MigrateError, Migrator},
+    Sqlite, SqlitePool, Transaction,
+};
 use uuid::Uuid;
 
-pub async fn init(pool: &SqlitePool) {
-    let track_migration = include_str!("../migrations/20250124082845_track_init.up.sql");
-    let playlist_migration = include_str!("../migrations/20250124084234_playlist_init.up.sql");
+/// Anything that went wrong talking to db. Shown to user, never a crash
+#[derive(Debug)]
+pub enum DbError {
+    Sqlx(sqlx::Error),
+    Json(serde_json::Error), // Broken data in a JSON column
+    Corrupt(String),         // Row that can't be read, e.g. a uuid that isn't one
+    Migrate(MigrateError),
+    Backup(String),
+    NotFound(&'static str),
+}
 
-    sqlx::query(track_migration)
-        .execute(pool)
-        .await
-        .expect("Unable to init db");
-    sqlx::query(playlist_migration)
-        .execute(pool)
-        .await
-        .expect("Unable to init db");
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Sqlx(sqlx::Error::Database(err)) if err.message().contains("locked") => {
+                write!(f, "Library is busy in another program: {err}")
+            }
+            DbError::Sqlx(err) => write!(f, "Library error: {err}"),
+            DbError::Json(err) => write!(f, "Library data is broken: {err}"),
+            DbError::Corrupt(what) => write!(f, "Library data is broken: {what}"),
+            DbError::Migrate(err) => write!(f, "Unable to update library: {err}"),
+            DbError::Backup(err) => write!(f, "Unable to back up library: {err}"),
+            DbError::NotFound(what) => write!(f, "{what} not found in library"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+impl From<sqlx::Error> for DbError {
+    fn from(value: sqlx::Error) -> Self {
+        DbError::Sqlx(value)
+    }
+}
+
+impl From<MigrateError> for DbError {
+    fn from(value: MigrateError) -> Self {
+        DbError::Migrate(value)
+    }
+}
+
+impl From<serde_json::Error> for DbError {
+    fn from(value: serde_json::Error) -> Self {
+        DbError::Json(value)
+    }
+}
+
+/// Track uuids of `playlists.tracks`. Column comes back either as JSON text
+/// or, when queried with a type override, as the array itself
+pub fn playlist_tracks(tracks: Value) -> Result<Vec<Uuid>, DbError> {
+    let tracks = match tracks {
+        Value::String(text) => serde_json::from_str(&text)?,
+        value => serde_json::from_value(value)?,
+    };
+
+    Ok(tracks)
+}
+
+/// Migrations from `migrations/`, built into the binary. Applied versions are
+/// kept in `_sqlx_migrations`, so each one runs once per db. Libraries made
+/// before versions were kept have no such table and get every migration again,
+/// which is why they stay `IF NOT EXISTS`
+pub static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Brings schema up to date and makes sure the liked playlist exists
+pub async fn init(pool: &SqlitePool) -> Result<(), DbError> {
+    MIGRATOR.run(pool).await?;
 
     let liked_exists = sqlx::query_as!(
         PlaylistModel,
         r#"
             SELECT uuid, title, tracks AS "tracks: Value" FROM playlists WHERE title = $1 
         "#,
-        "Liked"
+        LIKED
     )
     .fetch_optional(pool)
-    .await
-    .unwrap()
+    .await?
     .is_some();
 
     if !liked_exists {
@@ -46,83 +110,379 @@ pub async fn init(pool: &SqlitePool) {
                 ($1, $2, $3)
             "#,
             uuid,
-            "Liked",
+            LIKED,
             "[]"
         )
         .execute(pool)
-        .await
-        .unwrap();
+        .await?;
     }
+
+    Ok(())
 }
 
-pub async fn update_track_state(pool: &SqlitePool, paths: &[PathBuf]) {
-    let mut transaction = pool.begin().await.unwrap();
-    for path in paths {
-        let path = path.to_str().unwrap();
-        let track = sqlx::query_as!(
-            TrackModel,
-            r#"
-                SELECT * FROM tracks WHERE path = $1
-            "#,
-            path
-        )
-        .fetch_optional(transaction.as_mut())
-        .await
-        .unwrap();
+/// Syncs tracks with files found by scan. New files get new rows, rows of
+/// files that are gone are deleted. A new file with the same audio as one
+/// that is gone is taken as moved and its row gets the new path. Rows under
+/// `unread`, folders the scan couldn't read, are kept as they are
+pub async fn update_track_state(
+    pool: &SqlitePool,
+    paths: &[PathBuf],
+    unread: &[PathBuf],
+) -> Result<(), DbError> {
+    let now = unix_now();
+    let scanned: HashSet<&str> = paths.iter().filter_map(|path| path.to_str()).collect();
+
+    // Hashing reads whole files, so it's kept out of the way of loading the
+    // library. Only new files that may be a vanished track moved are hashed:
+    // ones with the same audio size and length as its identity. Other tracks
+    // get theirs later from `identity::run`
+    let known = sqlx::query!(
+        r#"
+            SELECT tracks.path,
+            track_identity.audio_size AS "audio_size?",
+            track_identity.duration_ms AS "duration_ms?"
+            FROM tracks LEFT JOIN track_identity
+            ON tracks.uuid = track_identity.uuid
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+    let known_paths: HashSet<&str> = known.iter().map(|track| track.path.as_str()).collect();
+
+    let vanished: HashSet<(i64, i64)> = known
+        .iter()
+        .filter(|track| !is_kept(&track.path, &scanned, unread))
+        .filter_map(|track| Some((track.audio_size?, track.duration_ms?)))
+        .collect();
+    let new_paths: Vec<PathBuf> = paths
+        .iter()
+        .filter(|path| path.to_str().is_some_and(|p| !known_paths.contains(p)))
+        .cloned()
+        .collect();
+    let identities = match vanished.is_empty() || new_paths.is_empty() {
+        true => HashMap::new(),
+        false => identify_moved(new_paths, vanished).await,
+    };
+
+    let mut transaction = pool.begin().await?;
+
+    // Read again, rows may have changed while files were hashed
+    let known = sqlx::query!(
+        r#"
+            SELECT uuid, path FROM tracks
+        "#
+    )
+    .fetch_all(transaction.as_mut())
+    .await?;
 
-        if let None = track {
-            let uuid = Uuid::new_v4().to_string();
-            let a = sqlx::query_as!(
-                TrackModel,
+    let known_paths: HashSet<&str> = known.iter().map(|track| track.path.as_str()).collect();
+
+    let kept: HashSet<&str> = known
+        .iter()
+        .map(|track| track.path.as_str())
+        .filter(|path| is_kept(path, &scanned, unread))
+        .chain(scanned.iter().copied())
+        .collect();
+
+    let new_paths: Vec<PathBuf> = paths
+        .iter()
+        .filter(|path| path.to_str().is_some_and(|p| !known_paths.contains(p)))
+        .cloned()
+        .collect();
+
+    // Saved identities of rows whose file is gone, to find them among new files
+    let mut vanished: HashMap<Identity, String> = HashMap::new();
+    if !identities.is_empty() {
+        for track in known.iter().filter(|t| !kept.contains(t.path.as_str())) {
+            let identity = sqlx::query_as!(
+                Identity,
                 r#"
-                    INSERT INTO tracks
-                    (uuid, path, play_count, play_minutes)
-                    VALUES
-                    ($1,$2,$3,$4)
-                    RETURNING *
+                    SELECT audio_hash, audio_size, duration_ms
+                    FROM track_identity WHERE uuid = $1
                 "#,
-                uuid,
-                path,
-                0,
-                0.0,
+                track.uuid
             )
-            .fetch_one(transaction.as_mut())
-            .await
-            .unwrap();
-            println!("Inserted: {a:?}");
+            .fetch_optional(transaction.as_mut())
+            .await?;
+
+            if let Some(identity) = identity {
+                vanished.insert(identity, track.uuid.clone());
+            }
         }
     }
 
-    let paths = path_buf_vec_to_string(paths);
-    let qry = format!("DELETE FROM tracks WHERE path NOT IN ({})", paths);
+    for path_buf in &new_paths {
+        // Scan only gives paths that are valid text
+        let Some(path) = path_buf.to_str() else {
+            continue;
+        };
 
-    println!("{qry}");
+        // Files that weren't hashed get their identity later
+        let identity = identities.get(path_buf);
+        let moved = identity.and_then(|i| vanished.remove(i));
+        if let Some(uuid) = moved {
+            sqlx::query!(
+                r#"
+                    UPDATE tracks SET path = $1 WHERE uuid = $2
+                "#,
+                path,
+                uuid,
+            )
+            .execute(transaction.as_mut())
+            .await?;
+            println!("Relinked: {uuid} {path}");
+            continue;
+        }
 
-    sqlx::query(&qry)
+        let uuid = Uuid::new_v4().to_string();
+        sqlx::query!(
+            r#"
+                INSERT INTO tracks
+                (uuid, path, play_count, play_minutes)
+                VALUES
+                ($1,$2,$3,$4)
+            "#,
+            uuid,
+            path,
+            0,
+            0.0,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+
+        sqlx::query!(
+            r#"
+                INSERT OR REPLACE INTO track_info
+                (uuid, added_at)
+                VALUES
+                ($1, $2)
+            "#,
+            uuid,
+            now,
+        )
         .execute(transaction.as_mut())
-        .await
-        .unwrap();
+        .await?;
+
+        if let Some(identity) = identity {
+            insert_identity(&mut transaction, &uuid, identity).await?;
+        }
+        println!("Inserted: {uuid} {path}");
+    }
+
+    // Files found by this scan and kept rows go to a table of the connection,
+    // so rows can be matched against them in SQL however many there are.
+    // Temporary tables aren't in schema, so these are checked at runtime only
+    sqlx::query(
+        r#"
+            CREATE TEMP TABLE IF NOT EXISTS scanned (path TEXT PRIMARY KEY NOT NULL)
+        "#,
+    )
+    .execute(transaction.as_mut())
+    .await?;
+    sqlx::query(
+        r#"
+            DELETE FROM temp.scanned
+        "#,
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    for path in &kept {
+        sqlx::query(
+            r#"
+                INSERT OR IGNORE INTO temp.scanned (path) VALUES ($1)
+            "#,
+        )
+        .bind(*path)
+        .execute(transaction.as_mut())
+        .await?;
+    }
+
+    let deleted = sqlx::query(
+        r#"
+            DELETE FROM tracks WHERE path NOT IN (SELECT path FROM temp.scanned)
+        "#,
+    )
+    .execute(transaction.as_mut())
+    .await?
+    .rows_affected();
+    println!("Deleted: {deleted} tracks whose files are gone");
+
+    sqlx::query(
+        r#"
+            DROP TABLE temp.scanned
+        "#,
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM track_info WHERE uuid NOT IN (SELECT uuid FROM tracks)
+        "#
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM track_rating WHERE uuid NOT IN (SELECT uuid FROM tracks)
+        "#
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM track_identity WHERE uuid NOT IN (SELECT uuid FROM tracks)
+        "#
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM track_loudness WHERE uuid NOT IN (SELECT uuid FROM tracks)
+        "#
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM track_identity_failed WHERE uuid NOT IN (SELECT uuid FROM tracks)
+        "#
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Row whose file was found by scan, or is under a folder that couldn't be
+/// read and may still be there
+fn is_kept(path: &str, scanned: &HashSet<&str>, unread: &[PathBuf]) -> bool {
+    scanned.contains(path) || unread.iter().any(|dir| Path::new(path).starts_with(dir))
+}
+
+/// Identities of files whose audio size and length are in `vanished`. Only
+/// headers of the others are read. Runs off the async runtime
+async fn identify_moved(
+    paths: Vec<PathBuf>,
+    vanished: HashSet<(i64, i64)>,
+) -> HashMap<PathBuf, Identity> {
+    tokio::task::spawn_blocking(move || {
+        paths
+            .into_iter()
+            .filter(|path| {
+                identity::size_and_duration(path).is_ok_and(|key| vanished.contains(&key))
+            })
+            .filter_map(|path| match identity::identify(&path) {
+                Ok(identity) => Some((path, identity)),
+                Err(err) => {
+                    println!("Unable to identify {path:?}: {err}");
+                    None
+                }
+            })
+            .collect()
+    })
+    .await
+    .unwrap_or_default()
+}
+
+async fn insert_identity(
+    transaction: &mut Transaction<'_, Sqlite>,
+    uuid: &str,
+    identity: &Identity,
+) -> Result<(), DbError> {
+    sqlx::query!(
+        r#"
+            INSERT OR REPLACE INTO track_identity
+            (uuid, audio_hash, audio_size, duration_ms)
+            VALUES
+            ($1, $2, $3, $4)
+        "#,
+        uuid,
+        identity.audio_hash,
+        identity.audio_size,
+        identity.duration_ms,
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM track_identity_failed WHERE uuid = $1
+        "#,
+        uuid,
+    )
+    .execute(transaction.as_mut())
+    .await?;
 
-    transaction.commit().await.unwrap();
+    Ok(())
+}
+
+/// Remembers a file that couldn't be hashed, so it isn't read again on every
+/// scan. It's tried again once it changes
+async fn insert_identity_failed(
+    transaction: &mut Transaction<'_, Sqlite>,
+    uuid: &str,
+    modified: i64,
+) -> Result<(), DbError> {
+    sqlx::query!(
+        r#"
+            INSERT OR REPLACE INTO track_identity_failed
+            (uuid, modified)
+            VALUES
+            ($1, $2)
+        "#,
+        uuid,
+        modified,
+    )
+    .execute(transaction.as_mut())
+    .await?;
 
-    return ();
+    Ok(())
 }
 
-pub async fn get_tracks(pool: &SqlitePool) -> Vec<TrackModel> {
+pub async fn get_tracks(pool: &SqlitePool) -> Result<Vec<TrackModel>, DbError> {
     let tracks = sqlx::query_as!(
         TrackModel,
         r#"
-            SELECT * FROM tracks
+            SELECT tracks.*, track_info.added_at AS "added_at?", track_rating.rating AS "rating?"
+            FROM tracks
+            LEFT JOIN track_info USING(uuid)
+            LEFT JOIN track_rating USING(uuid)
         "#
     )
     .fetch_all(pool)
-    .await
-    .unwrap();
+    .await?;
+
+    Ok(tracks)
+}
+
+pub async fn get_track(pool: &SqlitePool, track_uuid: Uuid) -> Result<Option<TrackModel>, DbError> {
+    let uuid = track_uuid.to_string();
+    let track = sqlx::query_as!(
+        TrackModel,
+        r#"
+            SELECT tracks.*, track_info.added_at AS "added_at?", track_rating.rating AS "rating?"
+            FROM tracks
+            LEFT JOIN track_info USING(uuid)
+            LEFT JOIN track_rating USING(uuid)
+            WHERE tracks.uuid = $1
+        "#,
+        uuid
+    )
+    .fetch_optional(pool)
+    .await?;
 
-    return tracks;
+    Ok(track)
 }
 
-pub async fn get_playlists(pool: &SqlitePool) -> Vec<PlaylistModel> {
+pub async fn get_playlists(pool: &SqlitePool) -> Result<Vec<PlaylistModel>, DbError> {
     let playlists = sqlx::query_as!(
         PlaylistModel,
         r#"
@@ -130,14 +490,17 @@ pub async fn get_playlists(pool: &SqlitePool) -> Vec<PlaylistModel> {
         "#
     )
     .fetch_all(pool)
-    .await
-    .unwrap();
+    .await?;
 
-    return playlists;
+    Ok(playlists)
 }
 
-pub async fn get_tracks_from_playlist(pool: &SqlitePool, playlist_uuid: Uuid) -> Vec<TrackModel> {
-    let mut transaction = pool.begin().await.unwrap();
+/// Tracks of a playlist in its order. Entries whose track is gone are left out
+pub async fn get_tracks_from_playlist(
+    pool: &SqlitePool,
+    playlist_uuid: Uuid,
+) -> Result<Vec<TrackModel>, DbError> {
+    let mut transaction = pool.begin().await?;
     let uuid = playlist_uuid.to_string();
     let playlist = sqlx::query_as!(
         PlaylistModel,
@@ -146,11 +509,11 @@ pub async fn get_tracks_from_playlist(pool: &SqlitePool, playlist_uuid: Uuid) ->
         "#,
         uuid
     )
-    .fetch_one(transaction.as_mut())
-    .await
-    .unwrap();
+    .fetch_optional(transaction.as_mut())
+    .await?
+    .ok_or(DbError::NotFound("Playlist"))?;
 
-    let uuids: Vec<Uuid> = serde_json::from_value(playlist.tracks).unwrap();
+    let uuids = playlist_tracks(playlist.tracks)?;
 
     let mut tracks: Vec<TrackModel> = vec![];
 
@@ -159,25 +522,35 @@ pub async fn get_tracks_from_playlist(pool: &SqlitePool, playlist_uuid: Uuid) ->
         let track = sqlx::query_as!(
             TrackModel,
             r#"
-                SELECT * FROM tracks WHERE uuid = $1
+                SELECT tracks.*, track_info.added_at AS "added_at?", track_rating.rating AS "rating?"
+                FROM tracks
+                LEFT JOIN track_info USING(uuid)
+                LEFT JOIN track_rating USING(uuid)
+                WHERE uuid = $1
             "#,
             uuid
         )
-        .fetch_one(transaction.as_mut())
-        .await
-        .unwrap();
+        .fetch_optional(transaction.as_mut())
+        .await?;
 
-        tracks.push(track);
+        match track {
+            Some(track) => tracks.push(track),
+            None => println!("Track of playlist is not in library: {uuid}"),
+        }
     }
 
-    transaction.commit().await.unwrap();
+    transaction.commit().await?;
 
-    return tracks;
+    Ok(tracks)
 }
 
-pub async fn insert_into_playlist(pool: &SqlitePool, mut playlist: Playlist, track_uuid: Uuid) -> Vec<PlaylistModel> {
+pub async fn insert_into_playlist(
+    pool: &SqlitePool,
+    mut playlist: Playlist,
+    track_uuid: Uuid,
+) -> Result<Vec<PlaylistModel>, DbError> {
     playlist.tracks.push(track_uuid);
-    let tracks = serde_json::to_value(playlist.tracks).unwrap();
+    let tracks = serde_json::to_value(playlist.tracks)?;
     let uuid = playlist.uuid.to_string();
 
     sqlx::query!(
@@ -192,12 +565,92 @@ pub async fn insert_into_playlist(pool: &SqlitePool, mut playlist: Playlist, tra
         uuid,
     )
     .execute(pool)
-    .await
-    .unwrap();
+    .await?;
 
-    return get_playlists(pool).await;
+    get_playlists(pool).await
 }
-pub async fn delete_from_playlist(pool: &SqlitePool, mut playlist: Playlist, track_uuid: Uuid) -> Vec<PlaylistModel> {
+pub async fn create_playlist(
+    pool: &SqlitePool,
+    title: String,
+    tracks: Vec<Uuid>,
+) -> Result<Vec<PlaylistModel>, DbError> {
+    let uuid = Uuid::new_v4().to_string();
+    let tracks = serde_json::to_value(tracks)?;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO playlists
+            (uuid, title, tracks)
+            VALUES
+            ($1, $2, $3)
+        "#,
+        uuid,
+        title,
+        tracks,
+    )
+    .execute(pool)
+    .await?;
+
+    get_playlists(pool).await
+}
+
+pub async fn append_to_playlist(
+    pool: &SqlitePool,
+    mut playlist: Playlist,
+    tracks: Vec<Uuid>,
+) -> Result<Vec<PlaylistModel>, DbError> {
+    playlist.tracks.extend(tracks);
+    let tracks = serde_json::to_value(playlist.tracks)?;
+    let uuid = playlist.uuid.to_string();
+
+    sqlx::query!(
+        r#"
+            UPDATE playlists 
+            SET
+                tracks = $1
+            WHERE 
+                uuid = $2
+        "#,
+        tracks,
+        uuid,
+    )
+    .execute(pool)
+    .await?;
+
+    get_playlists(pool).await
+}
+
+pub async fn delete_many_from_playlist(
+    pool: &SqlitePool,
+    mut playlist: Playlist,
+    tracks: Vec<Uuid>,
+) -> Result<Vec<PlaylistModel>, DbError> {
+    playlist.tracks.retain(|uuid| !tracks.contains(uuid));
+    let tracks = serde_json::to_value(playlist.tracks)?;
+    let uuid = playlist.uuid.to_string();
+
+    sqlx::query!(
+        r#"
+            UPDATE playlists 
+            SET
+                tracks = $1
+            WHERE 
+                uuid = $2
+        "#,
+        tracks,
+        uuid,
+    )
+    .execute(pool)
+    .await?;
+
+    get_playlists(pool).await
+}
+
+pub async fn delete_from_playlist(
+    pool: &SqlitePool,
+    mut playlist: Playlist,
+    track_uuid: Uuid,
+) -> Result<Vec<PlaylistModel>, DbError> {
     for (i, uuid) in playlist.tracks.iter().enumerate() {
         if *uuid == track_uuid {
             playlist.tracks.remove(i);
@@ -205,7 +658,7 @@ pub async fn delete_from_playlist(pool: &SqlitePool, mut playlist: Playlist, tra
         }
     }
 
-    let tracks = serde_json::to_value(playlist.tracks).unwrap();
+    let tracks = serde_json::to_value(playlist.tracks)?;
     let uuid = playlist.uuid.to_string();
     sqlx::query!(
         r#"
@@ -219,8 +672,538 @@ pub async fn delete_from_playlist(pool: &SqlitePool, mut playlist: Playlist, tra
         uuid,
     )
     .execute(pool)
-    .await
-    .unwrap();
+    .await?;
+
+    get_playlists(pool).await
+}
+
+/// Points tracks at new files. Rows are updated in place so uuids, and with
+/// them playlists, play counts and ratings, stay as they are
+pub async fn set_track_paths(pool: &SqlitePool, paths: &[(Uuid, PathBuf)]) -> Result<(), DbError> {
+    let mut transaction = pool.begin().await?;
+
+    for (uuid, path) in paths {
+        let uuid = uuid.to_string();
+        let path = path.to_string_lossy().to_string();
+        sqlx::query!(
+            r#"
+                UPDATE tracks SET path = $1 WHERE uuid = $2
+            "#,
+            path,
+            uuid,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Saved identity of every track that has one
+pub async fn get_identities(pool: &SqlitePool) -> Result<HashMap<Uuid, Identity>, DbError> {
+    let rows = sqlx::query!(
+        r#"
+            SELECT uuid, audio_hash, audio_size, duration_ms FROM track_identity
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let uuid = Uuid::from_str(&row.uuid).ok()?;
+            let identity = Identity {
+                audio_hash: row.audio_hash,
+                audio_size: row.audio_size,
+                duration_ms: row.duration_ms,
+            };
+            Some((uuid, identity))
+        })
+        .collect())
+}
+
+/// Tracks that have no identity yet, with the modification time their file
+/// had when hashing it last failed
+pub async fn get_unidentified(
+    pool: &SqlitePool,
+) -> Result<Vec<(Uuid, PathBuf, Option<i64>)>, DbError> {
+    let rows = sqlx::query!(
+        r#"
+            SELECT tracks.uuid, tracks.path, track_identity_failed.modified AS "failed: i64"
+            FROM tracks LEFT JOIN track_identity_failed
+            ON tracks.uuid = track_identity_failed.uuid
+            WHERE tracks.uuid NOT IN (SELECT uuid FROM track_identity)
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let uuid = Uuid::from_str(&row.uuid).ok()?;
+            Some((uuid, PathBuf::from(row.path), row.failed))
+        })
+        .collect())
+}
+
+/// Saves identities found by `identity::run`. None is a file that couldn't be
+/// hashed, with its modification time. Tracks deleted meanwhile are left out
+pub async fn save_identities(
+    pool: &SqlitePool,
+    results: &[(Uuid, Option<Identity>, i64)],
+) -> Result<(), DbError> {
+    let mut transaction = pool.begin().await?;
+
+    for (uuid, identity, modified) in results {
+        let uuid = uuid.to_string();
+        let exists = sqlx::query!(
+            r#"
+                SELECT uuid FROM tracks WHERE uuid = $1
+            "#,
+            uuid
+        )
+        .fetch_optional(transaction.as_mut())
+        .await?
+        .is_some();
+        if !exists {
+            continue;
+        }
+
+        match identity {
+            Some(identity) => insert_identity(&mut transaction, &uuid, identity).await?,
+            None => insert_identity_failed(&mut transaction, &uuid, *modified).await?,
+        }
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Folds duplicates into the kept track. Plays are added up, the earliest
+/// added date and first rating found are kept, playlist entries point at the
+/// kept track. Rows of `others` stay, with their stats cleared
+pub async fn merge_tracks(pool: &SqlitePool, keep: Uuid, others: &[Uuid]) -> Result<(), DbError> {
+    let mut transaction = pool.begin().await?;
+    let keep_uuid = keep.to_string();
+
+    for other in others {
+        let other = other.to_string();
+        sqlx::query!(
+            r#"
+                UPDATE tracks
+                SET
+                    play_count = play_count + (SELECT play_count FROM tracks WHERE uuid = $2),
+                    play_minutes = play_minutes + (SELECT play_minutes FROM tracks WHERE uuid = $2)
+                WHERE uuid = $1
+            "#,
+            keep_uuid,
+            other,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+
+        sqlx::query!(
+            r#"
+                UPDATE tracks SET play_count = 0, play_minutes = 0.0 WHERE uuid = $1
+            "#,
+            other,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+
+        sqlx::query!(
+            r#"
+                UPDATE track_info
+                SET added_at = MIN(
+                    added_at,
+                    COALESCE((SELECT added_at FROM track_info WHERE uuid = $2), added_at)
+                )
+                WHERE uuid = $1
+            "#,
+            keep_uuid,
+            other,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+
+        sqlx::query!(
+            r#"
+                INSERT OR IGNORE INTO track_rating
+                (uuid, rating)
+                SELECT $1, rating FROM track_rating WHERE uuid = $2
+            "#,
+            keep_uuid,
+            other,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+
+        sqlx::query!(
+            r#"
+                DELETE FROM track_rating WHERE uuid = $1
+            "#,
+            other,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+    }
+
+    let playlists = sqlx::query_as!(
+        PlaylistModel,
+        r#"
+            SELECT * FROM playlists
+        "#
+    )
+    .fetch_all(transaction.as_mut())
+    .await?;
+
+    for playlist in playlists {
+        // Broken playlists are left as they are, like on load
+        let playlist = match Playlist::try_from(playlist) {
+            Ok(playlist) => playlist,
+            Err(err) => {
+                println!("Skipped playlist while merging: {err}");
+                continue;
+            }
+        };
+        if !playlist.tracks.iter().any(|uuid| others.contains(uuid)) {
+            continue;
+        }
+
+        // Copies turn into the kept track, which is listed once at its first place
+        let mut seen = HashSet::new();
+        let tracks: Vec<Uuid> = playlist
+            .tracks
+            .iter()
+            .map(|uuid| if others.contains(uuid) { keep } else { *uuid })
+            .filter(|uuid| *uuid != keep || seen.insert(*uuid))
+            .collect();
+
+        let tracks = serde_json::to_value(tracks)?;
+        let uuid = playlist.uuid.to_string();
+        sqlx::query!(
+            r#"
+                UPDATE playlists
+                SET
+                    tracks = $1
+                WHERE
+                    uuid = $2
+            "#,
+            tracks,
+            uuid,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Removes tracks from library, for files that were deleted
+pub async fn delete_tracks(pool: &SqlitePool, uuids: &[Uuid]) -> Result<(), DbError> {
+    let mut transaction = pool.begin().await?;
+
+    for uuid in uuids {
+        let uuid = uuid.to_string();
+        sqlx::query!(
+            r#"
+                DELETE FROM tracks WHERE uuid = $1
+            "#,
+            uuid,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+    }
+
+    sqlx::query!(
+        r#"
+            DELETE FROM track_info WHERE uuid NOT IN (SELECT uuid FROM tracks)
+        "#
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM track_rating WHERE uuid NOT IN (SELECT uuid FROM tracks)
+        "#
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM track_identity WHERE uuid NOT IN (SELECT uuid FROM tracks)
+        "#
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM track_loudness WHERE uuid NOT IN (SELECT uuid FROM tracks)
+        "#
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    sqlx::query!(
+        r#"
+            DELETE FROM track_identity_failed WHERE uuid NOT IN (SELECT uuid FROM tracks)
+        "#
+    )
+    .execute(transaction.as_mut())
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Puts imported user data into library. Plays and added dates are kept where
+/// library already has more or earlier ones, and ratings only fill in missing
+/// ones, so the same import twice changes nothing. Playlists join ones with
+/// the same title
+pub async fn import_user_data(pool: &SqlitePool, import: &Import) -> Result<(), DbError> {
+    let mut transaction = pool.begin().await?;
+
+    for (uuid, stats) in &import.stats {
+        let uuid = uuid.to_string();
+        sqlx::query!(
+            r#"
+                UPDATE tracks
+                SET
+                    play_count = MAX(play_count, $2),
+                    play_minutes = MAX(play_minutes, $3)
+                WHERE uuid = $1
+            "#,
+            uuid,
+            stats.play_count,
+            stats.play_minutes,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+
+        if let Some(added_at) = stats.added_at {
+            sqlx::query!(
+                r#"
+                    UPDATE track_info SET added_at = MIN(added_at, $2) WHERE uuid = $1
+                "#,
+                uuid,
+                added_at,
+            )
+            .execute(transaction.as_mut())
+            .await?;
+        }
+
+        if let Some(rating) = stats.rating {
+            let rating = rating.clamp(1, 5);
+            sqlx::query!(
+                r#"
+                    INSERT OR IGNORE INTO track_rating
+                    (uuid, rating)
+                    VALUES
+                    ($1, $2)
+                "#,
+                uuid,
+                rating,
+            )
+            .execute(transaction.as_mut())
+            .await?;
+        }
+    }
+
+    let playlists = sqlx::query_as!(
+        PlaylistModel,
+        r#"
+            SELECT * FROM playlists
+        "#
+    )
+    .fetch_all(transaction.as_mut())
+    .await?;
+    let playlists = playlists
+        .into_iter()
+        .map(Playlist::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for (title, tracks) in &import.playlists {
+        let (uuid, tracks) = match playlists.iter().find(|p| p.title == *title) {
+            Some(playlist) => {
+                let mut joined = playlist.tracks.clone();
+                for uuid in tracks {
+                    if !joined.contains(uuid) {
+                        joined.push(*uuid);
+                    }
+                }
+                (playlist.uuid.to_string(), joined)
+            }
+            None => (Uuid::new_v4().to_string(), tracks.clone()),
+        };
+
+        let tracks = serde_json::to_value(tracks)?;
+        sqlx::query!(
+            r#"
+                INSERT INTO playlists
+                (uuid, title, tracks)
+                VALUES
+                ($1, $2, $3)
+                ON CONFLICT(uuid) DO UPDATE SET tracks = excluded.tracks
+            "#,
+            uuid,
+            title,
+            tracks,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Rating of 0 clears it
+pub async fn set_rating(pool: &SqlitePool, track_uuid: Uuid, rating: u8) -> Result<(), DbError> {
+    let uuid = track_uuid.to_string();
+
+    if rating == 0 {
+        sqlx::query!(
+            r#"
+                DELETE FROM track_rating WHERE uuid = $1
+            "#,
+            uuid,
+        )
+        .execute(pool)
+        .await?;
+    } else {
+        let rating = rating.min(5);
+        sqlx::query!(
+            r#"
+                INSERT INTO track_rating
+                (uuid, rating)
+                VALUES
+                ($1, $2)
+                ON CONFLICT(uuid) DO UPDATE SET rating = excluded.rating
+            "#,
+            uuid,
+            rating,
+        )
+        .execute(pool)
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Loudness of every analyzed track. None for tracks that couldn't be measured,
+/// they are not tried again
+pub async fn get_loudness(pool: &SqlitePool) -> Result<HashMap<Uuid, Option<Measured>>, DbError> {
+    let rows = sqlx::query!(
+        r#"
+            SELECT uuid, loudness, peak, album_loudness, album_peak FROM track_loudness
+        "#
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let uuid = Uuid::from_str(&row.uuid).ok()?;
+            let measured = row.loudness.zip(row.peak).map(|(loudness, peak)| Measured {
+                loudness: loudness as f32,
+                peak: peak as f32,
+                album_loudness: row.album_loudness.map(|l| l as f32),
+                album_peak: row.album_peak.map(|p| p as f32),
+            });
+            Some((uuid, measured))
+        })
+        .collect())
+}
+
+/// Saves what analysis found, all tracks of an album at once
+pub async fn save_loudness(
+    pool: &SqlitePool,
+    results: &[(Uuid, Option<Measured>)],
+) -> Result<(), DbError> {
+    let mut transaction = pool.begin().await?;
+    let now = unix_now();
+
+    for (uuid, measured) in results {
+        let uuid = uuid.to_string();
+        let loudness = measured.map(|m| m.loudness as f64);
+        let peak = measured.map(|m| m.peak as f64);
+        let album_loudness = measured.and_then(|m| m.album_loudness).map(f64::from);
+        let album_peak = measured.and_then(|m| m.album_peak).map(f64::from);
+        sqlx::query!(
+            r#"
+                INSERT OR REPLACE INTO track_loudness
+                (uuid, loudness, peak, album_loudness, album_peak, analyzed_at)
+                VALUES
+                ($1, $2, $3, $4, $5, $6)
+            "#,
+            uuid,
+            loudness,
+            peak,
+            album_loudness,
+            album_peak,
+            now,
+        )
+        .execute(transaction.as_mut())
+        .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+pub async fn get_session(pool: &SqlitePool) -> Result<Option<SessionModel>, DbError> {
+    let state = sqlx::query_scalar!(
+        r#"
+            SELECT state AS "state: Value" FROM session WHERE id = 0
+        "#
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    // Session from older version or broken one is not worth a crash
+    Ok(state.and_then(|state| {
+        serde_json::from_value(state)
+            .map_err(|e| eprintln!("Unable to read session: {e:?}"))
+            .ok()
+    }))
+}
+
+pub async fn save_session(pool: &SqlitePool, session: SessionModel) -> Result<(), DbError> {
+    let state = serde_json::to_value(session)?;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO session
+            (id, state)
+            VALUES
+            (0, $1)
+            ON CONFLICT(id) DO UPDATE SET state = excluded.state
+        "#,
+        state,
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
 
-    return get_playlists(pool).await;
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
 }
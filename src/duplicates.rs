@@ -0,0 +1,538 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    path::PathBuf,
+    time::Duration,
+};
+
+use iced::{
+    widget::{
+        button, checkbox, column, container, horizontal_space, radio, row, scrollable, text, Column,
+    },
+    Alignment, Element, Length, Task,
+};
+use uuid::Uuid;
+
+use crate::{fingerprint::Fingerprint, identity::Identity, theme, track::Track};
+
+// Tracks with the same tags may differ this much in length, e.g. other silence at the end
+const TAG_DURATION_SLACK: Duration = Duration::from_secs(2);
+const SOUND_DURATION_SLACK: Duration = Duration::from_secs(3);
+// Share of fingerprint bits that has to match
+const SOUND_SIMILARITY: f32 = 0.9;
+const LOSSLESS: [&str; 5] = ["FLAC", "WAV", "AIFF", "APE", "WV"];
+
+/// Why tracks were put in one group
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Reason {
+    SameAudio,   // Same audio hash
+    SameTags,    // Same artist and title, about the same length
+    SoundsAlike, // Close acoustic fingerprints
+}
+
+/// Copies of one song
+#[derive(Debug, Clone)]
+pub struct Group {
+    pub tracks: Vec<Track>,
+    pub reasons: BTreeSet<Reason>,
+    pub keep: Uuid,  // Best copy unless changed in review
+    pub merge: bool, // Picked for merging in review
+}
+
+/// What merging a group does to the library
+#[derive(Debug, Clone)]
+pub struct Merge {
+    pub keep: Uuid,
+    pub others: Vec<(Uuid, PathBuf)>,
+}
+
+/// Finds duplicates and shows them for review
+#[derive(Debug, Clone)]
+pub struct DuplicateFinder {
+    pub reasons: BTreeSet<Reason>, // What to look for
+    pub groups: Option<Vec<Group>>,
+    pub delete_files: bool,
+    pub busy: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum DuplicatesMessage {
+    Toggle(Reason, bool),
+    Find,
+    Found(Result<Vec<Group>, String>),
+    Keep(usize, Uuid),
+    Include(usize, bool),
+    DeleteFiles(bool),
+    Merge,
+    Close,
+}
+
+impl Reason {
+    pub const ALL: [Reason; 3] = [Reason::SameAudio, Reason::SameTags, Reason::SoundsAlike];
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Reason::SameAudio => "Same audio",
+            Reason::SameTags => "Same artist, title and length",
+            Reason::SoundsAlike => "Sounds alike (slow)",
+        })
+    }
+}
+
+impl Default for DuplicateFinder {
+    fn default() -> Self {
+        Self {
+            reasons: BTreeSet::from([Reason::SameAudio, Reason::SameTags]),
+            groups: None,
+            delete_files: false,
+            busy: false,
+            error: None,
+        }
+    }
+}
+
+impl DuplicateFinder {
+    /// Groups picked for merging
+    pub fn merges(&self) -> Vec<Merge> {
+        self.groups
+            .iter()
+            .flatten()
+            .filter(|group| group.merge)
+            .map(|group| Merge {
+                keep: group.keep,
+                others: group
+                    .tracks
+                    .iter()
+                    .filter(|track| track.uuid != group.keep)
+                    .map(|track| (track.uuid, track.path.clone()))
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Finding, merging and closing are up to the player
+    pub fn update(&mut self, message: DuplicatesMessage) -> Task<DuplicatesMessage> {
+        match message {
+            DuplicatesMessage::Toggle(reason, on) => {
+                if on {
+                    self.reasons.insert(reason);
+                } else {
+                    self.reasons.remove(&reason);
+                }
+            }
+            DuplicatesMessage::Found(groups) => {
+                self.busy = false;
+                match groups {
+                    Ok(groups) => self.groups = Some(groups),
+                    Err(err) => self.error = Some(err),
+                }
+            }
+            DuplicatesMessage::Keep(i, uuid) => {
+                if let Some(group) = self.groups.iter_mut().flatten().nth(i) {
+                    group.keep = uuid;
+                }
+            }
+            DuplicatesMessage::Include(i, merge) => {
+                if let Some(group) = self.groups.iter_mut().flatten().nth(i) {
+                    group.merge = merge;
+                }
+            }
+            DuplicatesMessage::DeleteFiles(delete) => self.delete_files = delete,
+            DuplicatesMessage::Find | DuplicatesMessage::Merge | DuplicatesMessage::Close => {}
+        }
+        Task::none()
+    }
+
+    pub fn view(&self) -> Element<'_, DuplicatesMessage> {
+        let idle = !self.busy;
+
+        let options = Reason::ALL.iter().map(|reason| {
+            let reason = *reason;
+            checkbox(reason.to_string(), self.reasons.contains(&reason))
+                .on_toggle(move |on| DuplicatesMessage::Toggle(reason, on))
+                .into()
+        });
+
+        let find = row![
+            Column::with_children(options).spacing(5),
+            horizontal_space(),
+            button(if self.busy { "Working..." } else { "Find" }).on_press_maybe(
+                (idle && !self.reasons.is_empty()).then_some(DuplicatesMessage::Find)
+            ),
+        ]
+        .align_y(Alignment::Center);
+
+        let mut content = column![text("Duplicates").size(20), find].spacing(10);
+
+        if let Some(groups) = &self.groups {
+            let copies: usize = groups.iter().map(|group| group.tracks.len()).sum();
+            content = content.push(text(format!(
+                "{} songs with {} copies",
+                groups.len(),
+                copies
+            )));
+
+            let groups = groups
+                .iter()
+                .enumerate()
+                .map(|(i, group)| group_view(i, group));
+            content = content.push(
+                scrollable(Column::with_children(groups).spacing(15))
+                    .height(400)
+                    .width(Length::Fill),
+            );
+        }
+
+        if let Some(error) = &self.error {
+            content = content.push(text(error).style(text::danger));
+        }
+
+        let merges = self.merges().len();
+        let footer = row![
+            checkbox("Delete files of merged copies", self.delete_files)
+                .on_toggle(DuplicatesMessage::DeleteFiles),
+            horizontal_space(),
+            button("Close")
+                .style(button::secondary)
+                .on_press_maybe(idle.then_some(DuplicatesMessage::Close)),
+            button(text(format!("Merge {merges} groups")))
+                .style(if self.delete_files {
+                    button::danger
+                } else {
+                    button::primary
+                })
+                .on_press_maybe((idle && merges > 0).then_some(DuplicatesMessage::Merge)),
+        ]
+        .spacing(10)
+        .align_y(Alignment::Center);
+
+        container(content.push(footer))
+            .padding(15)
+            .width(800)
+            .style(container::rounded_box)
+            .into()
+    }
+}
+
+fn group_view(i: usize, group: &Group) -> Element<'_, DuplicatesMessage> {
+    let reasons: Vec<String> = group.reasons.iter().map(|r| r.to_string()).collect();
+    let header = checkbox(reasons.join(", "), group.merge)
+        .on_toggle(move |merge| DuplicatesMessage::Include(i, merge));
+
+    let copies = group.tracks.iter().map(|track| {
+        let details = format!(
+            "{} - {} {} kbps, {} plays",
+            track.artist.as_deref().unwrap_or("Unknown"),
+            track.format,
+            track.bitrate.unwrap_or_default(),
+            track.play_count,
+        );
+
+        row![
+            radio(
+                track.name.as_str(),
+                track.uuid,
+                Some(group.keep),
+                move |uuid| DuplicatesMessage::Keep(i, uuid)
+            ),
+            column![
+                text(details).style(theme::muted),
+                text(track.path.display().to_string())
+                    .size(12)
+                    .style(theme::muted)
+            ]
+        ]
+        .spacing(10)
+        .into()
+    });
+
+    column![
+        header,
+        Column::with_children(copies).spacing(5).padding([0, 25])
+    ]
+    .spacing(5)
+    .into()
+}
+
+/// Groups copies of the same song. `fingerprints` are only looked at when
+/// sounding alike is one of the reasons
+pub fn find(
+    tracks: &[Track],
+    reasons: &BTreeSet<Reason>,
+    identities: &HashMap<Uuid, Identity>,
+    fingerprints: &HashMap<Uuid, Fingerprint>,
+) -> Vec<Group> {
+    let mut sets = DisjointSets::new(tracks.len());
+
+    if reasons.contains(&Reason::SameAudio) {
+        let mut by_audio: HashMap<&Identity, usize> = HashMap::new();
+        for (i, track) in tracks.iter().enumerate() {
+            if let Some(identity) = identities.get(&track.uuid) {
+                match by_audio.get(identity) {
+                    Some(first) => sets.join(*first, i, Reason::SameAudio),
+                    None => {
+                        by_audio.insert(identity, i);
+                    }
+                }
+            }
+        }
+    }
+
+    if reasons.contains(&Reason::SameTags) {
+        let mut by_tags: HashMap<(String, String), Vec<usize>> = HashMap::new();
+        for (i, track) in tracks.iter().enumerate() {
+            // Name falls back to file name, that alone says nothing
+            let Some(artist) = track.artist.as_deref().map(normalize) else {
+                continue;
+            };
+            let title = normalize(&track.name);
+            if !artist.is_empty() && !title.is_empty() {
+                by_tags.entry((artist, title)).or_default().push(i);
+            }
+        }
+
+        for same in by_tags.values() {
+            join_close(
+                &mut sets,
+                tracks,
+                same,
+                TAG_DURATION_SLACK,
+                Reason::SameTags,
+                |_, _| true,
+            );
+        }
+    }
+
+    if reasons.contains(&Reason::SoundsAlike) {
+        let printed: Vec<usize> = (0..tracks.len())
+            .filter(|i| fingerprints.contains_key(&tracks[*i].uuid))
+            .collect();
+        join_close(
+            &mut sets,
+            tracks,
+            &printed,
+            SOUND_DURATION_SLACK,
+            Reason::SoundsAlike,
+            |a, b| fingerprints[&a.uuid].similarity(&fingerprints[&b.uuid]) >= SOUND_SIMILARITY,
+        );
+    }
+
+    sets.groups()
+        .into_iter()
+        .map(|(members, reasons)| {
+            let tracks: Vec<Track> = members.iter().map(|i| tracks[*i].clone()).collect();
+            Group {
+                keep: best(&tracks),
+                tracks,
+                reasons,
+                merge: true,
+            }
+        })
+        .collect()
+}
+
+/// Joins every two of `candidates` that are about as long and pass `same`
+fn join_close(
+    sets: &mut DisjointSets,
+    tracks: &[Track],
+    candidates: &[usize],
+    slack: Duration,
+    reason: Reason,
+    same: impl Fn(&Track, &Track) -> bool,
+) {
+    let mut by_length = candidates.to_vec();
+    by_length.sort_by_key(|i| tracks[*i].duration);
+
+    for (n, a) in by_length.iter().enumerate() {
+        for b in &by_length[n + 1..] {
+            let (ta, tb) = (&tracks[*a], &tracks[*b]);
+            if tb.duration - ta.duration > slack {
+                break;
+            }
+            if same(ta, tb) {
+                sets.join(*a, *b, reason);
+            }
+        }
+    }
+}
+
+/// Lossless first, then higher bitrate, then the one played more
+fn best(tracks: &[Track]) -> Uuid {
+    tracks
+        .iter()
+        .max_by_key(|track| {
+            (
+                LOSSLESS.contains(&track.format.as_str()),
+                track.bitrate.unwrap_or_default(),
+                track.play_count,
+            )
+        })
+        .map(|track| track.uuid)
+        .unwrap_or_default()
+}
+
+/// Lowercase letters and digits only, so `Don't Stop` and `dont stop` match
+fn normalize(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Union-find over track indices, remembering why sets were joined
+struct DisjointSets {
+    parent: Vec<usize>,
+    reasons: HashMap<usize, BTreeSet<Reason>>, // By root
+}
+
+impl DisjointSets {
+    fn new(len: usize) -> Self {
+        Self {
+            parent: (0..len).collect(),
+            reasons: HashMap::new(),
+        }
+    }
+
+    fn root(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    fn join(&mut self, a: usize, b: usize, reason: Reason) {
+        let (a, b) = (self.root(a), self.root(b));
+        if a != b {
+            self.parent[b] = a;
+            let moved = self.reasons.remove(&b).unwrap_or_default();
+            self.reasons.entry(a).or_default().extend(moved);
+        }
+        self.reasons.entry(a).or_default().insert(reason);
+    }
+
+    /// Sets with more than one member, in order of their first member
+    fn groups(mut self) -> Vec<(Vec<usize>, BTreeSet<Reason>)> {
+        let mut members: HashMap<usize, Vec<usize>> = HashMap::new();
+        let mut order = vec![];
+        for i in 0..self.parent.len() {
+            let root = self.root(i);
+            let set = members.entry(root).or_default();
+            if set.is_empty() {
+                order.push(root);
+            }
+            set.push(i);
+        }
+
+        order
+            .into_iter()
+            .filter_map(|root| {
+                let set = members.remove(&root)?;
+                let reasons = self.reasons.remove(&root)?;
+                (set.len() > 1).then_some((set, reasons))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils;
+
+    use super::*;
+
+    fn track(name: &str, artist: Option<&str>, secs: u64, format: &str, bitrate: u32) -> Track {
+        Track {
+            artist: artist.map(str::to_string),
+            bitrate: Some(bitrate),
+            format: format.to_string(),
+            duration: Duration::from_secs(secs),
+            path: PathBuf::from(format!("/music/{name}.{}", format.to_lowercase())),
+            ..test_utils::track(name)
+        }
+    }
+
+    fn reasons(reasons: &[Reason]) -> BTreeSet<Reason> {
+        reasons.iter().copied().collect()
+    }
+
+    fn uuids(group: &Group) -> Vec<Uuid> {
+        group.tracks.iter().map(|track| track.uuid).collect()
+    }
+
+    #[test]
+    fn names_match_without_case_and_punctuation() {
+        assert_eq!(normalize("Don't Stop!"), normalize("dont stop"));
+        assert_eq!(normalize("  Été 2  "), "été2");
+        assert_eq!(normalize("..."), "");
+    }
+
+    #[test]
+    fn same_tags_group_tracks_of_about_the_same_length() {
+        let tracks = vec![
+            track("Don't Stop", Some("Fleetwood Mac"), 193, "MP3", 320),
+            track("dont stop", Some("fleetwood mac"), 194, "FLAC", 900),
+            // Live version, same tags but much longer
+            track("Don't Stop", Some("Fleetwood Mac"), 260, "MP3", 320),
+            // No artist, the name alone says nothing
+            track("Don't Stop", None, 193, "MP3", 320),
+            track("Dreams", Some("Fleetwood Mac"), 257, "MP3", 320),
+        ];
+
+        let groups = find(
+            &tracks,
+            &reasons(&[Reason::SameTags]),
+            &HashMap::new(),
+            &HashMap::new(),
+        );
+        assert_eq!(groups.len(), 1);
+        assert_eq!(uuids(&groups[0]), vec![tracks[0].uuid, tracks[1].uuid]);
+        assert_eq!(groups[0].reasons, reasons(&[Reason::SameTags]));
+        assert_eq!(groups[0].keep, tracks[1].uuid);
+    }
+
+    #[test]
+    fn fingerprints_group_tracks_with_other_tags() {
+        let tracks = vec![
+            track("01 Track", None, 200, "MP3", 192),
+            track("Song", Some("Band"), 201, "MP3", 256),
+            track("Other", Some("Band"), 200, "MP3", 256),
+        ];
+        let bits: Vec<bool> = (0..100).map(|i| i % 3 == 0 || i % 7 == 0).collect();
+        let mut other = bits.clone();
+        other.iter_mut().for_each(|bit| *bit = !*bit);
+        let fingerprints = HashMap::from([
+            (tracks[0].uuid, Fingerprint::from_bits(bits.clone())),
+            (tracks[1].uuid, Fingerprint::from_bits(bits)),
+            (tracks[2].uuid, Fingerprint::from_bits(other)),
+        ]);
+
+        let all = reasons(&Reason::ALL);
+        let groups = find(&tracks, &all, &HashMap::new(), &fingerprints);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(uuids(&groups[0]), vec![tracks[0].uuid, tracks[1].uuid]);
+        assert_eq!(groups[0].reasons, reasons(&[Reason::SoundsAlike]));
+
+        // Not looked at unless asked for
+        let tags = reasons(&[Reason::SameAudio, Reason::SameTags]);
+        assert!(find(&tracks, &tags, &HashMap::new(), &fingerprints).is_empty());
+    }
+
+    #[test]
+    fn best_copy_is_lossless_then_higher_bitrate_then_played_more() {
+        let mut tracks = vec![
+            track("a", None, 200, "MP3", 320),
+            track("b", None, 200, "MP3", 128),
+            track("c", None, 200, "MP3", 320),
+        ];
+        tracks[2].play_count = 5;
+        assert_eq!(best(&tracks), tracks[2].uuid);
+
+        tracks.push(track("d", None, 200, "FLAC", 0));
+        assert_eq!(best(&tracks), tracks[3].uuid);
+    }
+}
@@ -0,0 +1,480 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use rodio::{source::Amplify, Decoder, Sink, Source};
+use tokio::{runtime::Handle, sync::mpsc::Receiver, time};
+
+use crate::{
+    output::{DeviceOutput, Output},
+    replaygain::{GainSettings, ReplayGain},
+};
+
+// How often the engine looks whether output still takes samples
+const CHECK_EVERY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Play(PathBuf),
+    Stop,
+    Load(PathBuf, Duration), // Prepare track paused at given position
+    ToggleTrack,
+    Seek(Duration),
+    SetVolume(f32),
+    SetDevice(Option<String>), // Output device by name, default one with None
+    SetGain(GainSettings),
+    AddGains(Vec<(PathBuf, ReplayGain)>), // Measured by analysis, used for tracks without tags
+}
+
+/// Plays tracks to an output, and moves them over when the output changes
+pub struct Engine {
+    output: Box<dyn Output>,
+    device: Option<String>, // Picked by user, default device is used when None
+    gain: GainSettings,
+    measured: HashMap<PathBuf, ReplayGain>,
+    track: Option<PathBuf>, // What is in the sink, to play it on another output
+    offset: Duration,       // Sink counts position from here, it starts at 0 after a skip
+    last_pos: Option<Duration>, // Position at last check, output is stuck when it stays
+}
+
+impl Engine {
+    pub fn new(output: Box<dyn Output>, device: Option<String>, gain: GainSettings) -> Self {
+        println!("Track Thread: Playing to {}", output.name());
+        Self {
+            output,
+            device,
+            gain,
+            measured: HashMap::new(),
+            track: None,
+            offset: Duration::ZERO,
+            last_pos: None,
+        }
+    }
+
+    pub fn sink(&self) -> &Sink {
+        self.output.sink()
+    }
+
+    /// Position in current track
+    pub fn position(&self) -> Duration {
+        self.offset + self.sink().get_pos()
+    }
+
+    pub fn handle(&mut self, command: Command) {
+        match command {
+            Command::Play(path) => match self.open(&path) {
+                Ok(source) => {
+                    let sink = self.sink();
+                    println!("Track Thread: Playing track");
+                    println!("Total duration = {:#?}", source.total_duration());
+                    sink.stop();
+                    sink.play();
+                    sink.append(source);
+                    self.track = Some(path);
+                    self.offset = Duration::ZERO;
+                }
+                Err(err) => println!("Track Thread: Unable to play {path:?}: {err}"),
+            },
+            Command::Load(path, pos) => match self.open(&path) {
+                Ok(source) => {
+                    let sink = self.sink();
+                    println!("Track Thread: Loading track at {pos:?}");
+                    sink.stop();
+                    sink.pause();
+                    sink.append(source.skip_duration(pos));
+                    self.track = Some(path);
+                    self.offset = pos;
+                }
+                Err(err) => println!("Track Thread: Unable to load {path:?}: {err}"),
+            },
+            Command::Stop => {
+                self.sink().stop();
+                self.track = None;
+            }
+            Command::ToggleTrack => {
+                let sink = self.sink();
+                if sink.is_paused() {
+                    sink.play();
+                    println!("Track resumed");
+                } else {
+                    sink.pause();
+                    println!("Track paused");
+                }
+            }
+            Command::Seek(pos) => match self.sink().try_seek(pos) {
+                // Position of sink is from the start of track after a seek
+                Ok(_) => self.offset = Duration::ZERO,
+                Err(err) => println!("Track Thread: Unable to seek to {pos:?}: {err}"),
+            },
+            Command::SetVolume(volume) => self.sink().set_volume(volume),
+            Command::SetDevice(device) => {
+                self.device = device;
+                match DeviceOutput::open(self.device.as_deref()) {
+                    Ok(output) => self.switch(Box::new(output)),
+                    Err(err) => println!("Track Thread: Unable to open {:?}: {err}", self.device),
+                }
+            }
+            Command::AddGains(gains) => self.measured.extend(gains),
+            Command::SetGain(gain) => {
+                self.gain = gain;
+                // Playing track is opened again for the new gain to apply
+                if let Some((path, position, paused)) = self.take_track() {
+                    self.resume(path, position, paused);
+                }
+            }
+        }
+    }
+
+    /// Plays to `output` from now on. What was playing goes on from the same
+    /// position, paused if it was paused
+    pub fn switch(&mut self, output: Box<dyn Output>) {
+        let volume = self.sink().volume();
+        let track = self.take_track();
+
+        println!("Track Thread: Playing to {}", output.name());
+        self.output = output;
+        self.last_pos = None;
+
+        self.sink().set_volume(volume);
+        if let Some((path, position, paused)) = track {
+            self.resume(path, position, paused);
+        }
+    }
+
+    /// Stops what is in the sink, giving its path, position and whether it was paused
+    fn take_track(&mut self) -> Option<(PathBuf, Duration, bool)> {
+        let position = self.position();
+        let sink = self.output.sink();
+        let paused = sink.is_paused();
+        let track = self.track.take().filter(|_| !sink.empty());
+        sink.stop();
+        track.map(|path| (path, position, paused))
+    }
+
+    fn resume(&mut self, path: PathBuf, position: Duration, paused: bool) {
+        match self.open(&path) {
+            Ok(source) => {
+                let sink = self.sink();
+                if paused {
+                    sink.pause();
+                }
+                sink.append(source.skip_duration(position));
+                self.track = Some(path);
+                self.offset = position;
+            }
+            Err(err) => println!("Track Thread: Unable to move {path:?}: {err}"),
+        }
+    }
+
+    /// Track with its ReplayGain applied
+    fn open(&self, path: &Path) -> Result<Amplify<Decoder<BufReader<File>>>, String> {
+        let tagged = ReplayGain::read(path);
+        let gain = match self.measured.get(path) {
+            Some(measured) if !tagged.has_gain() => measured,
+            _ => &tagged,
+        };
+        let factor = gain.factor(self.gain);
+        if factor != 1.0 {
+            println!("Track Thread: Gain {:+.2} dB", 20.0 * factor.log10());
+        }
+        Ok(decode(path)?.amplify(factor))
+    }
+
+    /// Picked device that stopped taking samples is taken as gone,
+    /// default device plays instead
+    fn check_output(&mut self) {
+        let sink = self.sink();
+        let playing = !sink.empty() && !sink.is_paused();
+        let pos = playing.then(|| sink.get_pos());
+        let stuck = pos.is_some() && pos == self.last_pos;
+        self.last_pos = pos;
+
+        let on_picked = self
+            .device
+            .as_ref()
+            .is_some_and(|device| *device == self.output.name());
+        if !stuck || !on_picked {
+            return;
+        }
+
+        println!("Track Thread: {} stopped playing", self.output.name());
+        match DeviceOutput::open(None) {
+            Ok(output) => self.switch(Box::new(output)),
+            Err(err) => println!("Track Thread: Unable to open default device: {err}"),
+        }
+    }
+}
+
+/// Plays commands until every sender is gone. Blocks, so it gets a thread of its own
+/// in the tokio runtime
+pub fn run(
+    output: Box<dyn Output>,
+    device: Option<String>,
+    gain: GainSettings,
+    mut commands: Receiver<Command>,
+) {
+    let mut engine = Engine::new(output, device, gain);
+    let runtime = Handle::current();
+    loop {
+        match runtime.block_on(time::timeout(CHECK_EVERY, commands.recv())) {
+            Ok(Some(command)) => engine.handle(command),
+            Ok(None) => break,
+            Err(_) => engine.check_output(),
+        }
+    }
+    dbg!("Engine died");
+}
+
+fn decode(path: &Path) -> Result<Decoder<BufReader<File>>, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    Decoder::new(BufReader::new(file)).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use lofty::{
+        config::WriteOptions,
+        tag::{ItemKey, Tag, TagExt, TagType},
+    };
+
+    use crate::{
+        output::{NullOutput, WavOutput},
+        replaygain::GainMode,
+        test_utils::{self, finishes, TempDir},
+    };
+
+    use super::*;
+
+    fn engine(output: impl Output + 'static) -> Engine {
+        Engine::new(Box::new(output), None, GainSettings::default())
+    }
+
+    /// One second of a tone
+    fn tone(dir: &Path) -> PathBuf {
+        let path = dir.join("tone.wav");
+        test_utils::tone(&path, 440.0, Duration::from_secs(1), 0.5);
+        path
+    }
+
+    fn length(path: &Path) -> Duration {
+        let samples = decode(path).unwrap().count();
+        Duration::from_secs_f64(samples as f64 / 2.0 / 44_100.0)
+    }
+
+    #[test]
+    fn file_output_writes_what_is_played() {
+        let dir = TempDir::new();
+        let tone = tone(&dir.0);
+        let tone_length = length(&tone);
+        assert!(tone_length >= Duration::from_secs(1));
+
+        let copy = dir.0.join("copy.wav");
+        let mut engine = engine(WavOutput::create(&copy, 2, 44_100).unwrap());
+        engine.handle(Command::Play(tone));
+        assert!(finishes(engine.sink(), Duration::from_secs(10)));
+        drop(engine);
+
+        // Track may end in the middle of a piece, the rest of it is silence
+        let length = length(&copy);
+        assert!(length >= tone_length, "{length:?}");
+        assert!(
+            length <= tone_length + Duration::from_millis(30),
+            "{length:?}"
+        );
+    }
+
+    #[test]
+    fn null_output_plays_faster_than_real_time() {
+        let dir = TempDir::new();
+        let tone = tone(&dir.0);
+
+        let mut engine = engine(NullOutput::new(20.0));
+        let start = Instant::now();
+        engine.handle(Command::Play(tone));
+        assert!(!engine.sink().empty());
+        assert!(finishes(engine.sink(), Duration::from_secs(1)));
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn null_output_keeps_real_time() {
+        let dir = TempDir::new();
+        let tone = tone(&dir.0);
+
+        let mut engine = engine(NullOutput::new(1.0));
+        engine.handle(Command::Play(tone));
+        assert!(!finishes(engine.sink(), Duration::from_millis(500)));
+
+        // Seeking close to the end leaves little to play
+        engine.handle(Command::Seek(Duration::from_millis(900)));
+        assert!(engine.position() >= Duration::from_millis(900));
+        assert!(finishes(engine.sink(), Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn paused_track_stays_until_resumed() {
+        let dir = TempDir::new();
+        let tone = tone(&dir.0);
+
+        let mut engine = engine(NullOutput::new(20.0));
+        engine.handle(Command::Play(tone.clone()));
+        // Loading stops what plays, which needs the output running while paused
+        engine.handle(Command::Load(tone, Duration::from_millis(300)));
+        assert!(engine.sink().is_paused());
+        assert!(!finishes(engine.sink(), Duration::from_millis(200)));
+        assert_eq!(engine.position(), Duration::from_millis(300));
+
+        engine.handle(Command::ToggleTrack);
+        assert!(finishes(engine.sink(), Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn stop_and_bad_files_leave_sink_empty() {
+        let dir = TempDir::new();
+        let tone = tone(&dir.0);
+
+        let mut engine = engine(NullOutput::new(1.0));
+        engine.handle(Command::Play(tone));
+        engine.handle(Command::Stop);
+        assert!(finishes(engine.sink(), Duration::from_millis(200)));
+
+        engine.handle(Command::Play(dir.0.join("missing.mp3")));
+        assert!(engine.sink().empty());
+    }
+
+    #[test]
+    fn switching_output_goes_on_from_same_position() {
+        let dir = TempDir::new();
+        let tone = tone(&dir.0);
+        let tone_length = length(&tone);
+
+        let mut engine = engine(NullOutput::new(1.0));
+        engine.handle(Command::SetVolume(0.5));
+        engine.handle(Command::Play(tone));
+        thread::sleep(Duration::from_millis(400));
+
+        let copy = dir.0.join("copy.wav");
+        let moved_at = engine.position();
+        engine.switch(Box::new(WavOutput::create(&copy, 2, 44_100).unwrap()));
+        assert_eq!(engine.sink().volume(), 0.5);
+        assert!(finishes(engine.sink(), Duration::from_secs(10)));
+        drop(engine);
+
+        // Only the rest of the track gets to the new output
+        let rest = tone_length - moved_at;
+        let length = length(&copy);
+        assert!(
+            length >= rest - Duration::from_millis(30),
+            "{length:?} {rest:?}"
+        );
+        assert!(
+            length <= rest + Duration::from_millis(30),
+            "{length:?} {rest:?}"
+        );
+    }
+
+    #[test]
+    fn switching_keeps_track_paused() {
+        let dir = TempDir::new();
+        let tone = tone(&dir.0);
+
+        let mut engine = engine(NullOutput::new(1.0));
+        engine.handle(Command::Load(tone, Duration::from_millis(200)));
+        engine.switch(Box::new(NullOutput::new(20.0)));
+
+        assert!(engine.sink().is_paused());
+        assert_eq!(engine.position(), Duration::from_millis(200));
+        engine.handle(Command::ToggleTrack);
+        assert!(finishes(engine.sink(), Duration::from_secs(1)));
+    }
+
+    /// Loudest sample of a file
+    fn peak(path: &Path) -> f32 {
+        decode(path)
+            .unwrap()
+            .map(|sample| (sample as f32 / i16::MAX as f32).abs())
+            .fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn replaygain_is_applied_to_tagged_tracks() {
+        let dir = TempDir::new();
+        let tone = tone(&dir.0);
+        let mut tag = Tag::new(TagType::Id3v2);
+        tag.insert_text(ItemKey::ReplayGainTrackGain, "-6.02 dB".to_string());
+        tag.save_to_path(&tone, WriteOptions::default()).unwrap();
+
+        let play = |gain: GainSettings| {
+            let copy = dir.0.join("copy.wav");
+            let output = WavOutput::create(&copy, 2, 44_100).unwrap();
+            let mut engine = Engine::new(Box::new(output), None, gain);
+            engine.handle(Command::Play(tone.clone()));
+            assert!(finishes(engine.sink(), Duration::from_secs(10)));
+            drop(engine);
+            peak(&copy)
+        };
+
+        let original = peak(&tone);
+        let leveled = play(GainSettings::default());
+        assert!((leveled - original / 2.0).abs() < 0.01, "{leveled}");
+        let off = play(GainSettings {
+            mode: GainMode::Off,
+            preamp: 0.0,
+        });
+        assert!((off - original).abs() < 0.01, "{off}");
+    }
+
+    #[test]
+    fn measured_gain_is_used_for_untagged_tracks() {
+        let dir = TempDir::new();
+        let tone = tone(&dir.0);
+        let copy = dir.0.join("copy.wav");
+
+        let mut engine = engine(WavOutput::create(&copy, 2, 44_100).unwrap());
+        let measured = ReplayGain {
+            track_gain: Some(-6.02),
+            ..Default::default()
+        };
+        engine.handle(Command::AddGains(vec![(tone.clone(), measured)]));
+        engine.handle(Command::Play(tone.clone()));
+        assert!(finishes(engine.sink(), Duration::from_secs(10)));
+        drop(engine);
+
+        let leveled = peak(&copy);
+        assert!((leveled - peak(&tone) / 2.0).abs() < 0.01, "{leveled}");
+    }
+
+    #[test]
+    fn changing_gain_keeps_track_where_it_was() {
+        let dir = TempDir::new();
+        let tone = tone(&dir.0);
+
+        let mut engine = engine(NullOutput::new(20.0));
+        engine.handle(Command::Load(tone, Duration::from_millis(200)));
+        engine.handle(Command::SetGain(GainSettings {
+            mode: GainMode::Album,
+            preamp: 3.0,
+        }));
+
+        assert!(engine.sink().is_paused());
+        assert_eq!(engine.position(), Duration::from_millis(200));
+        engine.handle(Command::ToggleTrack);
+        assert!(finishes(engine.sink(), Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn switching_with_nothing_playing_stays_empty() {
+        let mut engine = engine(NullOutput::new(1.0));
+        engine.switch(Box::new(NullOutput::new(1.0)));
+        assert!(engine.sink().empty());
+    }
+}
@@ -0,0 +1,113 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use rodio::{Decoder, Source};
+
+// Only the start of a track is listened to
+const LENGTH_SECS: u32 = 60;
+// Loudness is measured in steps of this many per second
+const STEPS_PER_SEC: u32 = 10;
+// Steps two tracks may be shifted by, encoders add some silence at the start
+const MAX_SHIFT: usize = 3;
+
+/// Rough acoustic fingerprint: whether loudness goes up or down from one
+/// tenth of a second to the next. Same recording in other format or bitrate
+/// gives nearly the same bits, other songs don't
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fingerprint {
+    bits: Vec<bool>,
+}
+
+impl Fingerprint {
+    /// Decodes the start of the file, which takes a while
+    pub fn compute(path: &Path) -> Result<Fingerprint, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let decoder = Decoder::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+
+        let channels = decoder.channels().max(1) as usize;
+        let step = (decoder.sample_rate() / STEPS_PER_SEC) as usize * channels;
+        if step == 0 {
+            return Err("File has no samples".to_string());
+        }
+
+        let samples = decoder.take(step * (LENGTH_SECS * STEPS_PER_SEC) as usize);
+        let mut energy = vec![];
+        let (mut sum, mut count) = (0.0, 0);
+        for sample in samples {
+            let sample = sample as f64 / i16::MAX as f64;
+            sum += sample * sample;
+            count += 1;
+            if count == step {
+                energy.push(sum / count as f64);
+                (sum, count) = (0.0, 0);
+            }
+        }
+
+        let bits = energy.windows(2).map(|pair| pair[1] > pair[0]).collect();
+        Ok(Fingerprint { bits })
+    }
+
+    /// Share of matching bits, 0.0 to 1.0, at the best shift of one against the other
+    pub fn similarity(&self, other: &Fingerprint) -> f32 {
+        (0..=MAX_SHIFT)
+            .flat_map(|shift| {
+                [
+                    matching(&self.bits[shift.min(self.bits.len())..], &other.bits),
+                    matching(&self.bits, &other.bits[shift.min(other.bits.len())..]),
+                ]
+            })
+            .fold(0.0, f32::max)
+    }
+}
+
+fn matching(a: &[bool], b: &[bool]) -> f32 {
+    let len = a.len().min(b.len());
+    // Too short to tell anything
+    if len < STEPS_PER_SEC as usize * 5 {
+        return 0.0;
+    }
+
+    let same = a.iter().zip(b).filter(|(a, b)| a == b).count();
+    same as f32 / len as f32
+}
+
+#[cfg(test)]
+impl Fingerprint {
+    /// Print with the given ups and downs, without decoding a file
+    pub fn from_bits(bits: Vec<bool>) -> Fingerprint {
+        Fingerprint { bits }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(len: usize) -> Vec<bool> {
+        (0..len).map(|i| i % 3 == 0 || i % 7 == 0).collect()
+    }
+
+    #[test]
+    fn same_bits_match_at_a_small_shift() {
+        let print = Fingerprint::from_bits(bits(100));
+        assert_eq!(print.similarity(&print), 1.0);
+
+        // Two steps of silence more at the start of the other copy
+        let mut shifted = vec![false, false];
+        shifted.extend(bits(98));
+        assert_eq!(print.similarity(&Fingerprint::from_bits(shifted)), 1.0);
+
+        let mut too_far = vec![false; MAX_SHIFT + 2];
+        too_far.extend(bits(100 - MAX_SHIFT - 2));
+        assert!(print.similarity(&Fingerprint::from_bits(too_far)) < 0.9);
+    }
+
+    #[test]
+    fn other_and_short_prints_dont_match() {
+        let print = Fingerprint::from_bits(bits(100));
+        let inverted = Fingerprint::from_bits(bits(100).iter().map(|bit| !bit).collect());
+        assert!(print.similarity(&inverted) < 0.9);
+
+        let short = Fingerprint::from_bits(bits(20));
+        assert_eq!(short.similarity(&short), 0.0);
+    }
+}
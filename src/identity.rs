@@ -0,0 +1,423 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    thread,
+    time::UNIX_EPOCH,
+};
+
+use iced::futures::{SinkExt, Stream};
+use lofty::{file::AudioFile, probe::Probe};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::{store::LibraryStore, utils};
+
+// Header packets at the start of an Ogg stream, comments are one of them
+const VORBIS_HEADERS: usize = 3;
+const OPUS_HEADERS: usize = 2;
+// Files hashed between saves of the background job
+const BATCH: usize = 20;
+
+/// What a file is, no matter where it is or what its tags say. Same audio
+/// moved or renamed outside the app gives the same identity
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub audio_hash: String, // Hex sha256 of the audio, tags left out
+    pub audio_size: i64,    // Bytes of the audio, tags left out
+    pub duration_ms: i64,
+}
+
+/// Hashes the whole audio part of the file, so it reads all of it. Tags are
+/// skipped so writing them keeps the identity: ID3v2, ID3v1 and APEv2 at the
+/// ends, FLAC metadata blocks, WAV chunks other than the samples and Ogg
+/// header packets. Other formats are hashed whole
+pub fn identify(path: &Path) -> Result<Identity, String> {
+    let duration_ms = duration_ms(path)?;
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let (audio_hash, audio_size) = hash_audio(&mut file).map_err(|e| e.to_string())?;
+
+    Ok(Identity {
+        audio_hash,
+        audio_size: audio_size as i64,
+        duration_ms,
+    })
+}
+
+/// Audio size and duration `identify` would give, from headers only. Files
+/// whose ones differ can't have the same audio and aren't worth hashing
+pub fn size_and_duration(path: &Path) -> Result<(i64, i64), String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let ranges = audio_ranges(&mut file).map_err(|e| e.to_string())?;
+    let size: u64 = ranges.iter().map(|(start, end)| end - start).sum();
+    Ok((size as i64, duration_ms(path)?))
+}
+
+fn duration_ms(path: &Path) -> Result<i64, String> {
+    let duration = Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map_err(|e| e.to_string())?
+        .properties()
+        .duration();
+    Ok(duration.as_millis() as i64)
+}
+
+/// Modification time of a file in seconds, 0 when it can't be read
+pub fn modified(path: &Path) -> i64 {
+    fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Fills in identities of tracks that have none yet, so scans can find them
+/// when they're moved. Runs once the library is loaded instead of holding it
+/// up, on a thread of its own at low priority, which stops soon after the
+/// stream is dropped. Saved a batch at a time, so a new run goes on where the
+/// last one stopped. Files that couldn't be hashed are tried again once they
+/// change. Gives the error it stopped on, if any
+pub fn run(store: impl LibraryStore) -> impl Stream<Item = String> {
+    iced::stream::channel(1, move |mut output| async move {
+        if let Err(err) = fill_in(store).await {
+            let _ = output.send(err).await;
+        }
+    })
+}
+
+async fn fill_in(store: impl LibraryStore) -> Result<(), String> {
+    let tracks: Vec<(Uuid, PathBuf, Option<i64>)> =
+        store.unidentified().await.map_err(|e| e.to_string())?;
+    if tracks.is_empty() {
+        return Ok(());
+    }
+
+    // Worker stops when this end is dropped
+    let (sender, mut results) = mpsc::channel(1);
+    thread::Builder::new()
+        .name("identity".to_string())
+        .spawn(move || {
+            utils::lower_priority();
+            for batch in tracks.chunks(BATCH) {
+                let mut identified = vec![];
+                for (uuid, path, failed) in batch {
+                    if sender.is_closed() {
+                        return;
+                    }
+                    let modified = modified(path);
+                    if *failed == Some(modified) {
+                        continue;
+                    }
+                    let identity = identify(path)
+                        .map_err(|e| println!("Unable to identify {path:?}: {e}"))
+                        .ok();
+                    identified.push((*uuid, identity, modified));
+                }
+                if sender.blocking_send(identified).is_err() {
+                    return;
+                }
+            }
+        })
+        .map_err(|e| e.to_string())?;
+
+    while let Some(identified) = results.recv().await {
+        store
+            .save_identities(&identified)
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+/// Hex hash and byte count of the audio ranges of a file
+fn hash_audio(file: &mut (impl Read + Seek)) -> io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut buf = vec![0; 64 * 1024];
+    for (start, end) in audio_ranges(file)? {
+        file.seek(SeekFrom::Start(start))?;
+        let mut audio = file.by_ref().take(end - start);
+        loop {
+            let read = audio.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+        }
+        size += end - start;
+    }
+
+    let hash = hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    Ok((hash, size))
+}
+
+/// Byte ranges with the audio, in order
+fn audio_ranges(file: &mut (impl Read + Seek)) -> io::Result<Vec<(u64, u64)>> {
+    let (start, end) = untagged_range(file)?;
+
+    let mut magic = [0; 4];
+    if end - start >= 4 {
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut magic)?;
+    }
+
+    match &magic {
+        b"fLaC" => Ok(vec![(flac_frames(file, start, end)?, end)]),
+        b"RIFF" => Ok(vec![wav_samples(file, start, end)?]),
+        b"OggS" => ogg_packets(file, start, end),
+        _ => Ok(vec![(start, end)]),
+    }
+}
+
+/// Byte range of the file between tags at its start and end
+fn untagged_range(file: &mut (impl Read + Seek)) -> io::Result<(u64, u64)> {
+    let mut start = 0;
+    let mut end = file.seek(SeekFrom::End(0))?;
+
+    // ID3v2 at the start, can be repeated. Size is syncsafe, 7 bits per byte
+    let mut header = [0; 10];
+    while end - start >= 10 {
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut header)?;
+        if &header[..3] != b"ID3" {
+            break;
+        }
+
+        let size = header[6..]
+            .iter()
+            .fold(0, |size, byte| (size << 7) | (*byte as u64 & 0x7f));
+        let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+        start = (start + 10 + size + footer).min(end);
+    }
+
+    // ID3v1 is the last 128 bytes
+    let mut tag = [0; 3];
+    if end - start >= 128 {
+        file.seek(SeekFrom::Start(end - 128))?;
+        file.read_exact(&mut tag)?;
+        if &tag == b"TAG" {
+            end -= 128;
+        }
+    }
+
+    // APEv2 ends with a footer, size in it counts the footer but not the header
+    let mut footer = [0; 32];
+    if end - start >= 32 {
+        file.seek(SeekFrom::Start(end - 32))?;
+        file.read_exact(&mut footer)?;
+        if &footer[..8] == b"APETAGEX" {
+            let size = u32::from_le_bytes(footer[12..16].try_into().unwrap()) as u64;
+            let flags = u32::from_le_bytes(footer[20..24].try_into().unwrap());
+            let header = if flags & 0x8000_0000 != 0 { 32 } else { 0 };
+            end = end.saturating_sub(size + header).max(start);
+        }
+    }
+
+    Ok((start, end))
+}
+
+/// Start of the frames, after the metadata blocks that follow `fLaC`. Each
+/// block has a flag for the last one and a 24 bit size
+fn flac_frames(file: &mut (impl Read + Seek), start: u64, end: u64) -> io::Result<u64> {
+    let mut pos = start + 4;
+    let mut header = [0; 4];
+    while end - pos.min(end) >= 4 {
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header)?;
+        let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as u64;
+        pos += 4 + size;
+        if header[0] & 0x80 != 0 {
+            break;
+        }
+    }
+
+    Ok(pos.min(end))
+}
+
+/// Range of the `data` chunk. Tags are kept in other chunks of the file,
+/// which may come before or after it
+fn wav_samples(file: &mut (impl Read + Seek), start: u64, end: u64) -> io::Result<(u64, u64)> {
+    let mut pos = start + 12;
+    let mut header = [0; 8];
+    while end - pos.min(end) >= 8 {
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header)?;
+        let size = u32::from_le_bytes(header[4..].try_into().unwrap()) as u64;
+        if &header[..4] == b"data" {
+            return Ok((pos + 8, (pos + 8 + size).min(end)));
+        }
+        // Chunks are padded to an even size
+        pos += 8 + size + (size & 1);
+    }
+
+    Ok((start, end))
+}
+
+/// Payload of the pages that come after the header packets of the first
+/// stream. Writing comments can change how pages are split, payload stays
+fn ogg_packets(file: &mut (impl Read + Seek), start: u64, end: u64) -> io::Result<Vec<(u64, u64)>> {
+    let mut ranges: Vec<(u64, u64)> = vec![];
+    let mut headers = None;
+    let mut serial = None;
+    let mut packets = 0;
+    let mut pos = start;
+    let mut page = [0; 27];
+    while end - pos.min(end) >= 27 {
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut page)?;
+        if &page[..4] != b"OggS" {
+            break;
+        }
+
+        let mut lacing = vec![0; page[26] as usize];
+        file.read_exact(&mut lacing)?;
+        let payload = pos + 27 + lacing.len() as u64;
+        let size: u64 = lacing.iter().map(|lace| *lace as u64).sum();
+        pos = payload + size;
+
+        // Other streams of the file are left out
+        let page_serial = u32::from_le_bytes(page[14..18].try_into().unwrap());
+        if *serial.get_or_insert(page_serial) != page_serial {
+            continue;
+        }
+
+        let headers = match headers {
+            Some(headers) => headers,
+            None => {
+                let mut codec = [0; 8];
+                file.read_exact(&mut codec)?;
+                let found = if codec[..7] == *b"\x01vorbis" {
+                    VORBIS_HEADERS
+                } else if codec == *b"OpusHead" {
+                    OPUS_HEADERS
+                } else {
+                    // Unknown codec, hashed whole
+                    return Ok(vec![(start, end)]);
+                };
+                *headers.insert(found)
+            }
+        };
+
+        let mut segment = payload;
+        for lace in lacing {
+            let next = (segment + lace as u64).min(end);
+            if packets >= headers {
+                match ranges.last_mut() {
+                    Some((_, last)) if *last == segment => *last = next,
+                    _ => ranges.push((segment, next)),
+                }
+            }
+            // Packets end at the first segment shorter than 255
+            if lace < 255 {
+                packets += 1;
+            }
+            segment = next;
+        }
+    }
+
+    Ok(ranges)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn hash(bytes: Vec<u8>) -> (String, u64) {
+        hash_audio(&mut Cursor::new(bytes)).unwrap()
+    }
+
+    fn flac(comment: &[u8]) -> Vec<u8> {
+        let mut bytes = b"fLaC".to_vec();
+        bytes.extend([0x00, 0, 0, 4]); // Stream info
+        bytes.extend([1, 2, 3, 4]);
+        bytes.extend([0x84, 0, 0, comment.len() as u8]); // Last block, comments
+        bytes.extend(comment);
+        bytes.extend(b"frames");
+        bytes
+    }
+
+    fn wav(list: &[u8]) -> Vec<u8> {
+        let mut bytes = b"RIFF\0\0\0\0WAVE".to_vec();
+        bytes.extend(b"fmt \x04\0\0\0");
+        bytes.extend([1, 2, 3, 4]);
+        bytes.extend(b"LIST");
+        bytes.extend((list.len() as u32).to_le_bytes());
+        bytes.extend(list);
+        if list.len() % 2 == 1 {
+            bytes.push(0);
+        }
+        bytes.extend(b"data\x07\0\0\0samples");
+        bytes
+    }
+
+    fn ogg_page(sequence: u32, packets: &[&[u8]]) -> Vec<u8> {
+        let mut lacing = vec![];
+        for packet in packets {
+            lacing.extend(vec![255; packet.len() / 255]);
+            lacing.push((packet.len() % 255) as u8);
+        }
+
+        let mut bytes = b"OggS\0\0".to_vec();
+        bytes.extend([0; 8]); // Granule position
+        bytes.extend(7u32.to_le_bytes()); // Serial
+        bytes.extend(sequence.to_le_bytes());
+        bytes.extend([0; 4]); // Checksum
+        bytes.push(lacing.len() as u8);
+        bytes.extend(lacing);
+        packets.iter().for_each(|packet| bytes.extend(*packet));
+        bytes
+    }
+
+    fn vorbis(comment: &[u8]) -> Vec<u8> {
+        let mut bytes = ogg_page(0, &[b"\x01vorbis id"]);
+        bytes.extend(ogg_page(1, &[comment, b"\x05vorbis setup"]));
+        bytes.extend(ogg_page(2, &[b"audio 1", b"audio 2"]));
+        bytes
+    }
+
+    #[test]
+    fn tags_at_the_ends_are_skipped() {
+        let mut tagged = b"ID3\x04\0\0\0\0\0\x02ab".to_vec();
+        tagged.extend(b"frames");
+        assert_eq!(hash(tagged), hash(b"frames".to_vec()));
+    }
+
+    #[test]
+    fn flac_metadata_is_skipped() {
+        let (short, size) = hash(flac(b"TITLE=a"));
+        assert_eq!(size, 6);
+        assert_eq!(hash(flac(b"TITLE=a longer title")).0, short);
+        assert_ne!(hash(b"fLaC other".to_vec()).0, short);
+    }
+
+    #[test]
+    fn only_wav_samples_are_hashed() {
+        let (short, size) = hash(wav(b"INFOINAM\x01\0\0\0a"));
+        assert_eq!(size, 7);
+        assert_eq!(hash(wav(b"INFOINAM\x02\0\0\0ab")).0, short);
+    }
+
+    #[test]
+    fn ogg_header_packets_are_skipped() {
+        let (short, size) = hash(vorbis(b"\x03vorbis comment"));
+        assert_eq!(size, 14);
+
+        // Longer comments take more segments, audio packets stay the same
+        let long = [b"\x03vorbis".as_slice(), &[b'x'; 600]].concat();
+        assert_eq!(hash(vorbis(&long)).0, short);
+
+        let mut other = ogg_page(0, &[b"\x01vorbis id"]);
+        other.extend(ogg_page(1, &[b"\x03vorbis", b"\x05vorbis setup"]));
+        other.extend(ogg_page(2, &[b"audio 1", b"audio 3"]));
+        assert_ne!(hash(other).0, short);
+    }
+}
@@ -0,0 +1,283 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use iced::{
+    keyboard::{Key, Modifiers},
+    widget::{button, column, container, horizontal_space, row, scrollable, text, Column},
+    Element, Length,
+};
+use serde::{Deserialize, Serialize};
+
+/// Something the player can do from the keyboard. Names are the keys of
+/// `[keys]` table in config, e.g. `toggle_play = "Space"`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    TogglePlay,
+    Next,
+    Prev,
+    SeekForward,
+    SeekBackward,
+    VolumeUp,
+    VolumeDown,
+    FocusSearch,
+    ToggleQueue,
+    LikeCurrent,
+    #[serde(rename = "rate_0")]
+    Rate0,
+    #[serde(rename = "rate_1")]
+    Rate1,
+    #[serde(rename = "rate_2")]
+    Rate2,
+    #[serde(rename = "rate_3")]
+    Rate3,
+    #[serde(rename = "rate_4")]
+    Rate4,
+    #[serde(rename = "rate_5")]
+    Rate5,
+    SelectPrev,
+    SelectNext,
+    PlaySelected,
+    SelectAll,
+    Help,
+    Cancel,
+}
+
+/// Key with modifiers, written as `Ctrl+Shift+ArrowRight`. Keys are iced named keys
+/// (`Space`, `Enter`, `ArrowUp`, `F1`...) or a single character.
+/// `Ctrl` is Command on macOS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyCombo {
+    key: String,  // Lowercase, for matching
+    name: String, // As written, for help
+    ctrl: bool,
+    alt: bool,
+    shift: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Keybindings {
+    bindings: Vec<(Action, KeyCombo)>,
+}
+
+impl Action {
+    pub const ALL: [Action; 22] = [
+        Action::TogglePlay,
+        Action::Next,
+        Action::Prev,
+        Action::SeekForward,
+        Action::SeekBackward,
+        Action::VolumeUp,
+        Action::VolumeDown,
+        Action::FocusSearch,
+        Action::ToggleQueue,
+        Action::LikeCurrent,
+        Action::Rate0,
+        Action::Rate1,
+        Action::Rate2,
+        Action::Rate3,
+        Action::Rate4,
+        Action::Rate5,
+        Action::SelectPrev,
+        Action::SelectNext,
+        Action::PlaySelected,
+        Action::SelectAll,
+        Action::Help,
+        Action::Cancel,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Action::TogglePlay => "Play / pause",
+            Action::Next => "Next track",
+            Action::Prev => "Previous track",
+            Action::SeekForward => "Seek forward",
+            Action::SeekBackward => "Seek backward",
+            Action::VolumeUp => "Volume up",
+            Action::VolumeDown => "Volume down",
+            Action::FocusSearch => "Search",
+            Action::ToggleQueue => "Show / hide queue",
+            Action::LikeCurrent => "Like playing track",
+            Action::Rate0 => "Clear rating of playing track",
+            Action::Rate1 => "Rate playing track 1 star",
+            Action::Rate2 => "Rate playing track 2 stars",
+            Action::Rate3 => "Rate playing track 3 stars",
+            Action::Rate4 => "Rate playing track 4 stars",
+            Action::Rate5 => "Rate playing track 5 stars",
+            Action::SelectPrev => "Select previous row",
+            Action::SelectNext => "Select next row",
+            Action::PlaySelected => "Play selected row",
+            Action::SelectAll => "Select all",
+            Action::Help => "Show this help",
+            Action::Cancel => "Close help / clear selection",
+        }
+    }
+
+    /// Stars set by rating actions
+    pub fn rating(&self) -> Option<u8> {
+        match self {
+            Action::Rate0 => Some(0),
+            Action::Rate1 => Some(1),
+            Action::Rate2 => Some(2),
+            Action::Rate3 => Some(3),
+            Action::Rate4 => Some(4),
+            Action::Rate5 => Some(5),
+            _ => None,
+        }
+    }
+
+    fn default_key(&self) -> &'static str {
+        match self {
+            Action::TogglePlay => "Space",
+            Action::Next => "Ctrl+ArrowRight",
+            Action::Prev => "Ctrl+ArrowLeft",
+            Action::SeekForward => "ArrowRight",
+            Action::SeekBackward => "ArrowLeft",
+            Action::VolumeUp => "Ctrl+ArrowUp",
+            Action::VolumeDown => "Ctrl+ArrowDown",
+            Action::FocusSearch => "Ctrl+f",
+            Action::ToggleQueue => "q",
+            Action::LikeCurrent => "l",
+            Action::Rate0 => "Alt+0",
+            Action::Rate1 => "Alt+1",
+            Action::Rate2 => "Alt+2",
+            Action::Rate3 => "Alt+3",
+            Action::Rate4 => "Alt+4",
+            Action::Rate5 => "Alt+5",
+            Action::SelectPrev => "ArrowUp",
+            Action::SelectNext => "ArrowDown",
+            Action::PlaySelected => "Enter",
+            Action::SelectAll => "Ctrl+a",
+            Action::Help => "?",
+            Action::Cancel => "Escape",
+        }
+    }
+}
+
+impl FromStr for KeyCombo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut combo = KeyCombo {
+            key: String::new(),
+            name: String::new(),
+            ctrl: false,
+            alt: false,
+            shift: false,
+        };
+
+        // Split from the right so `+` itself can be bound, e.g. `Ctrl++`
+        let (modifiers, key) = match s.strip_suffix("++") {
+            Some(rest) => (rest, "+"),
+            None => s.rsplit_once('+').unwrap_or(("", s)),
+        };
+
+        for modifier in modifiers.split('+').filter(|m| !m.is_empty()) {
+            match modifier.to_lowercase().as_str() {
+                "ctrl" | "cmd" => combo.ctrl = true,
+                "alt" => combo.alt = true,
+                "shift" => combo.shift = true,
+                other => return Err(format!("Unknown modifier {other:?} in {s:?}")),
+            }
+        }
+
+        if key.is_empty() {
+            return Err(format!("No key in {s:?}"));
+        }
+
+        combo.key = key.to_lowercase();
+        combo.name = key.to_string();
+        Ok(combo)
+    }
+}
+
+impl fmt::Display for KeyCombo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let modifiers = [
+            (self.ctrl, "Ctrl+"),
+            (self.alt, "Alt+"),
+            (self.shift, "Shift+"),
+        ];
+        for (_, name) in modifiers.iter().filter(|(on, _)| *on) {
+            f.write_str(name)?;
+        }
+
+        f.write_str(&self.name)
+    }
+}
+
+impl KeyCombo {
+    /// Shift is not compared for characters, it is already part of them (`?` is Shift+/)
+    fn matches(&self, key: &Key, modifiers: Modifiers) -> bool {
+        let (name, character) = match key {
+            Key::Named(named) => (format!("{named:?}").to_lowercase(), false),
+            Key::Character(c) => (c.to_lowercase(), true),
+            Key::Unidentified => return false,
+        };
+
+        name == self.key
+            && modifiers.command() == self.ctrl
+            && modifiers.alt() == self.alt
+            && (character || modifiers.shift() == self.shift)
+    }
+}
+
+impl Keybindings {
+    /// Defaults with keys from config put over them. Keys that don't parse
+    /// are reported and the default is kept
+    pub fn new(keys: &HashMap<Action, String>) -> Self {
+        let bindings = Action::ALL
+            .iter()
+            .map(|action| {
+                let default = || action.default_key().parse().unwrap();
+                let combo = match keys.get(action) {
+                    Some(key) => key
+                        .parse()
+                        .map_err(|e| eprintln!("Unable to bind {action:?}: {e}"))
+                        .unwrap_or_else(|_| default()),
+                    None => default(),
+                };
+
+                (*action, combo)
+            })
+            .collect();
+
+        Self { bindings }
+    }
+
+    pub fn action(&self, key: &Key, modifiers: Modifiers) -> Option<Action> {
+        self.bindings
+            .iter()
+            .find(|(_, combo)| combo.matches(key, modifiers))
+            .map(|(action, _)| *action)
+    }
+
+    /// List of every binding for the help overlay
+    pub fn view<'a, Message: Clone + 'a>(&'a self, close: Message) -> Element<'a, Message> {
+        let rows = self.bindings.iter().map(|(action, combo)| {
+            row![
+                text(action.label()).width(Length::Fill),
+                text(combo.to_string()).width(150)
+            ]
+            .into()
+        });
+
+        let header = row![
+            text("Keyboard shortcuts").size(20),
+            horizontal_space(),
+            button("x").on_press(close)
+        ];
+
+        let content = column![
+            header,
+            scrollable(Column::with_children(rows).spacing(5)),
+            text("Keys can be changed in [keys] table of config.toml").size(12),
+        ]
+        .spacing(10);
+
+        container(content)
+            .padding(15)
+            .width(400)
+            .style(container::rounded_box)
+            .into()
+    }
+}
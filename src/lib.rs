@@ -1,5 +1,31 @@
+pub mod analysis;
+pub mod backup;
+pub mod columns;
+pub mod config;
+pub mod db;
+pub mod duplicates;
+pub mod engine;
+pub mod fingerprint;
+pub mod identity;
+pub mod keybindings;
+pub mod loudness;
 pub mod models;
-pub mod track;
+pub mod organizer;
+pub mod output;
+pub mod playback;
 pub mod playlist;
-pub mod db;
+pub mod queue;
+pub mod replaygain;
+pub mod scan;
+pub mod selection;
+pub mod settings;
+pub mod store;
+pub mod tag_editor;
+pub mod tags;
+#[cfg(test)]
+mod test_utils;
+pub mod theme;
+pub mod toast;
+pub mod track;
+pub mod track_list;
 pub mod utils;
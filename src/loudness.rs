@@ -0,0 +1,386 @@
+use std::{f64::consts::PI, fs::File, io::BufReader, path::Path};
+
+use rodio::{Decoder, Source};
+
+use crate::replaygain::ReplayGain;
+
+// ReplayGain 2 plays every track as loud as this, in LUFS
+const REFERENCE: f64 = -18.0;
+// Blocks quieter than this don't count at all
+const ABSOLUTE_GATE: f64 = -70.0;
+// Nor do blocks this many LU under the loudness of the louder ones
+const RELATIVE_GATE: f64 = -10.0;
+// Blocks are 400ms long and start every 100ms, so four steps make a block
+const STEP_MS: u32 = 100;
+const STEPS_PER_BLOCK: usize = 4;
+// Samples are looked at between themselves at this many points for the true peak
+const OVERSAMPLING: usize = 4;
+// Samples on each side an in-between value is made of
+const TAPS: usize = 6;
+// How many samples are decoded between checks whether to go on
+const CHECK_EVERY: usize = 1 << 16;
+
+/// Loudness of a track and of its album, as measured. Peaks are true peaks,
+/// 1.0 is full scale
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Measured {
+    pub loudness: f32, // LUFS
+    pub peak: f32,
+    pub album_loudness: Option<f32>, // None for tracks without album
+    pub album_peak: Option<f32>,
+}
+
+impl Measured {
+    pub fn replay_gain(&self) -> ReplayGain {
+        let gain = |loudness: f32| (REFERENCE - loudness as f64) as f32;
+        ReplayGain {
+            track_gain: Some(gain(self.loudness)),
+            track_peak: Some(self.peak),
+            album_gain: self.album_loudness.map(gain),
+            album_peak: self.album_peak,
+        }
+    }
+}
+
+/// Integrated loudness and true peak per EBU R128 (ITU BS.1770), fed with
+/// interleaved samples
+pub struct Meter {
+    channels: usize,
+    weights: Vec<f64>,
+    filters: Vec<KWeighting>,
+    history: Vec<[f64; 2 * TAPS]>, // Last samples of each channel, newest at the end
+    interpolation: [[f64; 2 * TAPS]; OVERSAMPLING],
+    step_len: usize, // Frames in a step
+    step_frames: usize,
+    step_energy: f64,
+    steps: Vec<f64>,  // Energy of every step so far
+    blocks: Vec<f64>, // Mean square of every block
+    peak: f64,
+}
+
+impl Meter {
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        let channels = channels.max(1) as usize;
+        let sample_rate = sample_rate.max(1) as f64;
+
+        Self {
+            channels,
+            weights: (0..channels)
+                .map(|channel| weight(channel, channels))
+                .collect(),
+            filters: (0..channels)
+                .map(|_| KWeighting::new(sample_rate))
+                .collect(),
+            history: vec![[0.0; 2 * TAPS]; channels],
+            interpolation: interpolation(),
+            step_len: (sample_rate * STEP_MS as f64 / 1000.0).round().max(1.0) as usize,
+            step_frames: 0,
+            step_energy: 0.0,
+            steps: vec![],
+            blocks: vec![],
+            peak: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample = *sample as f64;
+                let filtered = self.filters[channel].process(sample);
+                self.step_energy += self.weights[channel] * filtered * filtered;
+                self.track_peak(channel, sample);
+            }
+
+            self.step_frames += 1;
+            if self.step_frames == self.step_len {
+                self.end_step();
+            }
+        }
+    }
+
+    /// Integrated loudness in LUFS, None when everything was too quiet to count
+    pub fn loudness(&self) -> Option<f64> {
+        integrated(&self.blocks)
+    }
+
+    pub fn peak(&self) -> f64 {
+        self.peak
+    }
+
+    fn end_step(&mut self) {
+        self.steps.push(self.step_energy);
+        self.step_energy = 0.0;
+        self.step_frames = 0;
+
+        if self.steps.len() >= STEPS_PER_BLOCK {
+            let energy: f64 = self.steps[self.steps.len() - STEPS_PER_BLOCK..]
+                .iter()
+                .sum();
+            self.blocks
+                .push(energy / (self.step_len * STEPS_PER_BLOCK) as f64);
+        }
+    }
+
+    /// Keeps the highest value of the signal, also between samples
+    fn track_peak(&mut self, channel: usize, sample: f64) {
+        let history = &mut self.history[channel];
+        history.rotate_left(1);
+        history[2 * TAPS - 1] = sample;
+
+        self.peak = self.peak.max(sample.abs());
+        for phase in &self.interpolation[1..] {
+            let value: f64 = phase.iter().zip(history.iter()).map(|(c, s)| c * s).sum();
+            self.peak = self.peak.max(value.abs());
+        }
+    }
+}
+
+/// Loudness of all tracks of an album together. Gating goes over the blocks of
+/// every track, so quiet tracks count less than they would on their own
+pub fn album_loudness<'a>(meters: impl IntoIterator<Item = &'a Meter>) -> Option<f64> {
+    let blocks: Vec<f64> = meters
+        .into_iter()
+        .flat_map(|meter| meter.blocks.iter().copied())
+        .collect();
+    integrated(&blocks)
+}
+
+/// Decodes the whole file, which takes a while. Stops with an error when
+/// `keep_going` says so
+pub fn measure(path: &Path, keep_going: impl Fn() -> bool) -> Result<Meter, String> {
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let decoder = Decoder::new(BufReader::new(file)).map_err(|e| e.to_string())?;
+    let mut meter = Meter::new(decoder.channels(), decoder.sample_rate());
+
+    let chunk_len = CHECK_EVERY - CHECK_EVERY % meter.channels;
+    let mut chunk = Vec::with_capacity(chunk_len);
+    let mut samples = decoder.map(|sample| sample as f32 / i16::MAX as f32);
+    loop {
+        if !keep_going() {
+            return Err("Stopped".to_string());
+        }
+        chunk.clear();
+        chunk.extend(samples.by_ref().take(chunk_len));
+        if chunk.is_empty() {
+            break;
+        }
+        meter.add(&chunk);
+    }
+
+    Ok(meter)
+}
+
+fn loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn integrated(blocks: &[f64]) -> Option<f64> {
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+    let audible: Vec<f64> = blocks
+        .iter()
+        .copied()
+        .filter(|energy| *energy > 0.0 && loudness(*energy) > ABSOLUTE_GATE)
+        .collect();
+    if audible.is_empty() {
+        return None;
+    }
+
+    let gate = loudness(mean(&audible)) + RELATIVE_GATE;
+    let counted: Vec<f64> = audible
+        .into_iter()
+        .filter(|energy| loudness(*energy) > gate)
+        .collect();
+    Some(loudness(mean(&counted)))
+}
+
+/// Surround channels count more, LFE not at all. Channels are in the
+/// usual L, R, C, LFE, Ls, Rs order
+fn weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (5, 3..) | (6, 4..) => 1.41,
+        _ => 1.0,
+    }
+}
+
+/// Coefficients of windowed sinc for values a quarter, half and three quarters
+/// of the way after the middle sample
+fn interpolation() -> [[f64; 2 * TAPS]; OVERSAMPLING] {
+    let mut phases = [[0.0; 2 * TAPS]; OVERSAMPLING];
+    for (phase, coefficients) in phases.iter_mut().enumerate() {
+        let at = (TAPS - 1) as f64 + phase as f64 / OVERSAMPLING as f64;
+        for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+            let x = at - tap as f64;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * x).sin() / (PI * x)
+            };
+            let window = 0.5 * (1.0 + (PI * x / TAPS as f64).cos());
+            *coefficient = sinc * window;
+        }
+    }
+    phases
+}
+
+/// Two filters that make the signal sound to the meter as it does to ears:
+/// a shelf that lifts highs and a high pass
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    /// Coefficients for any sample rate, as worked out in libebur128
+    fn new(sample_rate: f64) -> Self {
+        let f0 = 1681.974450955533;
+        let gain = 3.999843853973347;
+        let q = 0.7071752369554196;
+        let k = (PI * f0 / sample_rate).tan();
+        let vh = 10f64.powf(gain / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let f0 = 38.13547087602444;
+        let q = 0.5003270373238773;
+        let k = (PI * f0 / sample_rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        Self { shelf, high_pass }
+    }
+
+    fn process(&mut self, sample: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(sample))
+    }
+}
+
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2], // a0 is 1
+    state: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            state: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::FRAC_1_SQRT_2;
+
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    /// Stereo sine with given peak, in dBFS
+    fn sine(freq: f64, level: f64, secs: f64, phase: f64) -> Vec<f32> {
+        let amplitude = 10f64.powf(level / 20.0);
+        let frames = (secs * RATE as f64) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let t = i as f64 / RATE as f64;
+                let sample = (amplitude * (2.0 * PI * freq * t + phase).sin()) as f32;
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    fn meter(samples: &[f32]) -> Meter {
+        let mut meter = Meter::new(2, RATE);
+        meter.add(samples);
+        meter
+    }
+
+    #[test]
+    fn sine_at_minus_23_dbfs_is_minus_23_lufs() {
+        // Reference signal of EBU Tech 3341
+        let meter = meter(&sine(997.0, -23.0, 5.0, 0.0));
+        let loudness = meter.loudness().unwrap();
+        assert!((loudness + 23.0).abs() < 0.1, "{loudness}");
+
+        let gain = Measured {
+            loudness: loudness as f32,
+            peak: meter.peak() as f32,
+            album_loudness: None,
+            album_peak: None,
+        }
+        .replay_gain();
+        assert!((gain.track_gain.unwrap() - 5.0).abs() < 0.1);
+        assert_eq!(gain.album_gain, None);
+    }
+
+    #[test]
+    fn silence_is_left_out() {
+        let mut samples = sine(997.0, -20.0, 3.0, 0.0);
+        samples.extend(vec![0.0; 2 * 10 * RATE as usize]);
+
+        let loudness = meter(&samples).loudness().unwrap();
+        let tone_only = meter(&sine(997.0, -20.0, 3.0, 0.0)).loudness().unwrap();
+        // Blocks where the tone fades into silence still count a little
+        assert!((loudness - tone_only).abs() < 0.5, "{loudness} {tone_only}");
+
+        assert_eq!(meter(&vec![0.0; 2 * RATE as usize]).loudness(), None);
+    }
+
+    #[test]
+    fn true_peak_is_found_between_samples() {
+        // At a quarter of sample rate and shifted by 45 degrees every sample
+        // misses the top by 3 dB
+        let samples = sine(RATE as f64 / 4.0, -6.0, 1.0, PI / 4.0);
+        let sample_peak = samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        let true_peak = meter(&samples).peak();
+
+        let expected = 10f64.powf(-6.0 / 20.0);
+        assert!((sample_peak as f64 - expected * FRAC_1_SQRT_2).abs() < 0.01);
+        assert!(
+            (true_peak - expected).abs() < expected * 0.02,
+            "{true_peak}"
+        );
+    }
+
+    #[test]
+    fn album_is_between_its_tracks() {
+        let loud = meter(&sine(997.0, -20.0, 3.0, 0.0));
+        let quiet = meter(&sine(997.0, -25.0, 3.0, 0.0));
+
+        let album = album_loudness([&loud, &quiet]).unwrap();
+        assert!(album < loud.loudness().unwrap() - 1.0, "{album}");
+        assert!(album > quiet.loudness().unwrap() + 1.0, "{album}");
+
+        // Track over 10 LU under the rest is gated out
+        let silent = meter(&sine(997.0, -40.0, 3.0, 0.0));
+        let album = album_loudness([&loud, &silent]).unwrap();
+        assert!((album - loud.loudness().unwrap()).abs() < 0.1, "{album}");
+    }
+
+    #[test]
+    fn missing_files_are_not_measured() {
+        assert!(measure(Path::new("/nowhere/track.mp3"), || true).is_err());
+    }
+}
@@ -1,32 +1,56 @@
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::env;
-use std::ffi::OsString;
 use std::fmt::Debug;
-use std::fs::File;
-use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::time::{Duration, Instant};
 
-use player::models::playlist_model::PlaylistModel;
-use sqlx::pool::PoolOptions;
-use sqlx::SqlitePool;
-
 use iced::widget::{
-    button, center, column, container, horizontal_space, keyed_column, progress_bar, row, text,
+    button, center, column, container, horizontal_space, keyed_column, opaque, pick_list,
+    progress_bar, row, scrollable, stack, text, text_input,
 };
 use iced::Length::{self, Fill};
-use iced::{time, window, Element, Subscription, Task};
-use lofty::file::AudioFile;
-use lofty::probe::Probe;
-use rodio::{OutputStream, Sink, Source};
+use iced::{event, keyboard, time, window, Element, Event, Size, Subscription, Task, Theme};
 use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::{self, Sender};
 use uuid::Uuid;
 
-use player::{db, playlist::*, track::*};
+use player::{
+    analysis::{self, AnalysisEvent, AnalysisState},
+    backup::{self, BackupMessage},
+    columns::{ColumnHeader, ColumnLayout, ColumnMessage},
+    config::{Config, LIBRARY_VIEW},
+    db::DbError,
+    duplicates::{self, DuplicateFinder, DuplicatesMessage, Merge, Reason},
+    engine::{self, Command},
+    fingerprint::Fingerprint,
+    identity,
+    keybindings::{Action, Keybindings},
+    loudness::Measured,
+    models::{playlist_model::PlaylistModel, session_model::SessionModel, track_model::TrackModel},
+    organizer::{self, Move, Organizer, OrganizerMessage},
+    output,
+    playback::{PlaybackCommand, PlaybackEvent, PlayerCore},
+    playlist::*,
+    queue::{self, QueueDrag, QueueMessage, QueuePosition},
+    replaygain,
+    scan::{self, Problem, ProblemsMessage},
+    selection::{Selection, SelectionMessage},
+    settings::{self, SettingsMessage},
+    store::{LibraryStore, SqliteStore},
+    tag_editor::{TagEditor, TagEditorMessage},
+    tags,
+    theme::{self, ThemeMode},
+    toast::{ToastMessage, Toasts},
+    track::*,
+    track_list::{TrackList, TrackListMessage},
+    utils,
+};
 
 pub const HOME_PATH: &str = "/home/lf/Music";
+const DOUBLE_CLICK: Duration = Duration::from_millis(400);
+const SEEK_STEP: Duration = Duration::from_secs(5);
+const VOLUME_STEP: f32 = 0.1;
 
 fn main() -> iced::Result {
     dotenvy::dotenv().ok();
@@ -37,56 +61,134 @@ fn main() -> iced::Result {
         std::fs::create_dir(path).unwrap();
     }
 
+    let config = Config::load();
+    let settings = iced::Settings {
+        default_text_size: config.appearance.font_size().into(),
+        ..Default::default()
+    };
+
     iced::application(Player::title, Player::update, Player::view)
+        .settings(settings)
         .window(window::Settings {
             ..Default::default()
         })
+        .theme(Player::theme)
+        .scale_factor(Player::scale_factor)
         .subscription(Player::subscription)
-        .run_with(Player::new)
+        .exit_on_close_request(false)
+        .run_with(move || Player::new(config))
 }
 
 struct Player {
     tracks: Vec<Track>, // All tracks found in system they are not meant to play anything
-    init_queue: Vec<Track>, // This will be initial queue. It will be master-copy for queue
-    queue: VecDeque<Track>, // All tracks OR tracks from playlist. This will pop tracks
-    prio_queue: VecDeque<Track>, // Tracks added by user
-
-    // To jump to prev tracks
-    // Its FILA so vec is perfect
-    backward_queue: Vec<Track>,
-
-    // Currently playing track. As we pop tracks from queue or
-    // prio_queue it will be here
-    current_track: Option<Track>,
+    core: PlayerCore,   // Shown list, queues and what plays
 
     playlists: Vec<Playlist>,
+    liked: HashSet<Uuid>, // Tracks of the liked playlist, for the like buttons
     current_playlist: Option<Playlist>,
     current_pos: Duration, // Current time pos of track
+    volume: f32,           // 0.0 to 1.0
+
+    search: String,
+    show_queue: bool,
+    queue_drag: Option<QueueDrag>,
+    queue_playlist_name: String,
+
+    config: Config,
+    system_dark: bool, // Followed in system and custom theme without a file
+    custom_theme: Option<Theme>, // From theme file, read on start
+    columns: ColumnLayout, // Layout of current view, copy of the one in config
+    column_header: ColumnHeader,
+    track_list: TrackList, // Shown rows of `init_queue`, refreshed on every change to it
+
+    selection: Selection,
+    modifiers: keyboard::Modifiers,
+    keybindings: Keybindings,
+    show_help: bool,
+    tag_editor: Option<TagEditor>,
+    organizer: Option<Organizer>,
+    last_organized: Vec<Move>, // Moves of last organize, for undo
+    duplicates: Option<DuplicateFinder>,
+    problems: Vec<Problem>, // Files and folders last scan couldn't take
+    show_problems: bool,
+    rescanning: bool,
+    show_backups: bool,
+    show_settings: bool,
+    devices: Option<Result<Vec<String>, String>>, // Output devices, None while looked up
+    backups: Vec<PathBuf>,                        // Files in backup folder, newest first
+    backup_status: Option<String>,
+    backup_busy: bool,
+    loaded: bool, // Library is in db, analysis waits for it
+    analysis: AnalysisState,
+    scan_round: u32, // Bumped by rescans, which start background jobs over for new tracks
+    toasts: Toasts,
+    last_click: Option<(Uuid, Instant)>,
 
     sender: Sender<Command>,
     timer: DurationBar,
-    db_pool: SqlitePool,
-}
-
-#[derive(Debug, Clone)]
-enum Command {
-    Play(PathBuf),
-    ToggleTrack,
+    store: SqliteStore,
 }
 
 #[derive(Debug, Clone)]
+#[allow(clippy::enum_variant_names)]
 enum Message {
     Loaded(Result<SavedState, LoadError>),
-    LoadPlaylist(Vec<Playlist>),
+    Rescanned(Result<SavedState, LoadError>),
+    LoadPlaylist(Result<Vec<Playlist>, String>),
     TrackMessage(usize, Uuid, TrackMessage),
     PlaylistMessage(usize, Uuid, PlaylistMessage),
-    PlayTrack,
+    QueueMessage(QueueMessage),
+    SelectionMessage(SelectionMessage),
+    ColumnMessage(ColumnMessage),
+    ModifiersChanged(keyboard::Modifiers),
+    KeyPressed(keyboard::Key, keyboard::Modifiers),
+    ToggleHelp,
+    OpenTagEditor(Vec<Track>),
+    TagEditorLoaded(Result<TagEditor, String>),
+    TagEditorMessage(TagEditorMessage),
+    TagsSaved((Vec<Track>, Vec<String>)),
+    OpenOrganizer,
+    OrganizerMessage(OrganizerMessage),
+    FilesMoved(Vec<Move>, Option<String>, bool), // Done moves, error, whether it was undo
+    ToggleProblems,
+    ProblemsMessage(ProblemsMessage),
+    TrackRetried(Uuid, Result<Track, Problem>),
+    OpenDuplicates,
+    DuplicatesMessage(DuplicatesMessage),
+    DuplicatesMerged(Result<(Vec<TrackModel>, Vec<Uuid>), String>), // Merged tracks as stored now, and deleted ones
+    ToggleBackups,
+    BackupMessage(BackupMessage),
+    ToggleSettings,
+    SettingsMessage(SettingsMessage),
+    DevicesListed(Result<Vec<String>, String>),
+    Analysis(AnalysisEvent),
+    IdentifyFailed(String), // Background job that fills in identities stopped
+    GainsLoaded(Result<HashMap<Uuid, Option<Measured>>, String>),
+    ExportPicked(Option<PathBuf>),
+    ImportPicked(Option<PathBuf>),
+    BackupDone(Result<String, String>), // What was done, for the backups view
+    Imported(Result<String, String>),
+    ThemeChanged(ThemeMode),
+    CheckSystemTheme,
+    SystemThemeChanged(bool),
+    ListScrolled(scrollable::Viewport),
+    WindowResized(Size),
+    Enqueue((Result<Vec<Track>, String>, QueuePosition)),
+    SearchChanged(String),
+    EnqueueSearch(QueuePosition),
+    ToggleQueuePanel,
     ToggleTrack,
     JumpToNext,
     JumpToPrev,
-    SetQueue((Result<Vec<Track>, String>, usize)),
+    ToggleShuffle,
+    CycleRepeat,
+    PlayList((Result<Vec<Track>, String>, Uuid)), // List to play and track to start with
+    ShowList(Result<Vec<Track>, String>),
     Tick(Instant),
-    Err(Result<(), String>),
+    SaveSession,
+    CloseRequested(window::Id),
+    Err(Result<(), String>), // Errors are shown as toasts
+    Toast(ToastMessage),
 }
 
 #[derive(Debug, Clone, Default)]
@@ -100,73 +202,86 @@ enum DurationBar {
 }
 
 impl Player {
-    fn new() -> (Self, Task<Message>) {
-        let (tx, mut rx) = mpsc::channel::<Command>(100);
+    fn new(config: Config) -> (Self, Task<Message>) {
+        let (tx, rx) = mpsc::channel::<Command>(100);
 
+        let device = config.output_device.clone();
+        let gain = config.replay_gain;
         tokio::task::spawn_blocking(move || {
-            let (_stream, stream_handle) = OutputStream::try_default().unwrap();
-            let sink = Sink::try_new(&stream_handle).unwrap();
-
-            while let Some(command) = rx.blocking_recv() {
-                match command.clone() {
-                    Command::Play(path) => {
-                        let file = File::open(path).unwrap();
-                        let source = rodio::Decoder::new(BufReader::new(file)).unwrap();
-                        let dur = source.total_duration();
-
-                        println!("Track Thread: Playing track");
-                        println!("Total duration = {dur:#?}");
-                        sink.stop();
-                        sink.play();
-                        sink.append(source);
-                    }
-                    Command::ToggleTrack => {
-                        if sink.is_paused() {
-                            sink.play();
-                            println!("Track resumed");
-                        } else {
-                            sink.pause();
-                            println!("Track paused");
-                        }
-                    }
-                };
-            }
-            dbg!("Engine died")
+            // Set to `null` or `wav:<path>` to play without sound card
+            let spec = env::var("AUDIO_OUTPUT").ok();
+            let output = output::open(spec.as_deref(), device.as_deref());
+            engine::run(output, device, gain, rx);
         });
 
-        if let None = env::var("DATABASE_URL").ok() {
+        if env::var("DATABASE_URL").is_err() {
             env::set_var("DATABASE_URL", "sqlite://db.sql");
         }
 
         let connection_string = env::var("DATABASE_URL").unwrap();
 
-        let db_pool = PoolOptions::new()
-            .max_connections(5)
-            .connect_lazy(&connection_string)
-            .expect("SQLite doesn't work");
+        let store = SqliteStore::connect_lazy(&connection_string).expect("SQLite doesn't work");
+
+        let keybindings = Keybindings::new(&config.keys);
 
         let player = Player {
             tracks: vec![],
-            init_queue: vec![],
-            queue: VecDeque::new(),
-            prio_queue: VecDeque::default(),
-            backward_queue: vec![],
-            current_track: None,
+            core: PlayerCore::default(),
 
             playlists: vec![],
+            liked: HashSet::new(),
             current_playlist: None,
             current_pos: Duration::default(),
+            volume: 1.0,
+
+            search: String::new(),
+            show_queue: false,
+            queue_drag: None,
+            queue_playlist_name: String::new(),
+
+            columns: config.columns(LIBRARY_VIEW),
+            config,
+            system_dark: false, // Looked up off the ui thread once started
+            custom_theme: theme::load_custom(),
+            column_header: ColumnHeader::default(),
+            track_list: TrackList::default(),
+
+            selection: Selection::default(),
+            modifiers: keyboard::Modifiers::default(),
+            keybindings,
+            show_help: false,
+            tag_editor: None,
+            organizer: None,
+            last_organized: vec![],
+            duplicates: None,
+            problems: vec![],
+            show_problems: false,
+            rescanning: false,
+            show_backups: false,
+            show_settings: false,
+            devices: None,
+            backups: vec![],
+            backup_status: None,
+            backup_busy: false,
+            loaded: false,
+            analysis: AnalysisState::default(),
+            scan_round: 0,
+            toasts: Toasts::default(),
+            last_click: None,
 
             timer: DurationBar::default(),
             sender: tx,
-            db_pool,
+            store,
         };
 
-        let pool = player.db_pool.clone();
+        let store = player.store.clone();
 
         (
             player,
-            Task::perform(SavedState::load(pool), Message::Loaded),
+            Task::batch(vec![
+                Task::perform(SavedState::load(store), Message::Loaded),
+                Task::done(Message::CheckSystemTheme),
+            ]),
         )
     }
 
@@ -179,52 +294,160 @@ impl Player {
             Message::Loaded(Ok(state)) => {
                 self.tracks = state.tracks;
                 self.playlists = state.playlists;
-                self.init_queue = self.tracks.clone();
-                self.backward_queue = vec![];
-                self.queue = VecDeque::new();
+                self.problems = state.problems;
+                state
+                    .broken_playlists
+                    .into_iter()
+                    .chain(state.backup_failed)
+                    .for_each(|err| self.toasts.push(err));
+                self.liked = liked_tracks(&self.playlists);
+                self.core
+                    .update(PlaybackCommand::SetList(self.tracks.clone()));
+                self.loaded = true;
+                if self.config.analyze_loudness {
+                    self.analysis = AnalysisState::Looking;
+                }
 
+                let task = match state.session {
+                    Some(session) => self.restore_session(session),
+                    None => Task::none(),
+                };
+                self.refresh_list();
+                Task::batch(vec![task, self.load_gains()])
+            }
+            Message::Loaded(Err(err)) => {
+                self.toasts.push(format!("Unable to load library: {err}"));
                 Task::none()
             }
-            Message::Loaded(Err(_err)) => Task::none(),
-            Message::LoadPlaylist(playlists) => {
+            Message::Rescanned(state) => {
+                self.rescanning = false;
+                let state = match state {
+                    Ok(state) => state,
+                    Err(err) => {
+                        self.toasts.push(format!("Unable to rescan library: {err}"));
+                        return Task::none();
+                    }
+                };
+
+                // Unlike first load, queues and playing track are left alone
+                self.tracks = state.tracks;
+                self.playlists = state.playlists;
+                self.problems = state.problems;
+                state
+                    .broken_playlists
+                    .into_iter()
+                    .chain(state.backup_failed)
+                    .for_each(|err| self.toasts.push(err));
+                self.liked = liked_tracks(&self.playlists);
+                if self.current_playlist.is_none() {
+                    self.core
+                        .update(PlaybackCommand::SetList(self.tracks.clone()));
+                }
+                self.refresh_list();
+
+                self.scan_round += 1;
+                if self.config.analyze_loudness {
+                    self.analysis = AnalysisState::Looking;
+                }
+                self.load_gains()
+            }
+            Message::LoadPlaylist(Err(err)) => {
+                self.toasts.push(err);
+                Task::none()
+            }
+            Message::LoadPlaylist(Ok(playlists)) => {
                 self.playlists = playlists;
+                self.liked = liked_tracks(&self.playlists);
+                if let Some(current) = &mut self.current_playlist {
+                    if let Some(playlist) = self.playlists.iter().find(|p| p.uuid == current.uuid) {
+                        *current = playlist.clone();
+                    }
+                }
                 Task::none()
             }
+            Message::TrackMessage(i, uuid, TrackMessage::Select) => {
+                let repeated = self
+                    .last_click
+                    .is_some_and(|(last, at)| last == uuid && at.elapsed() < DOUBLE_CLICK);
+                let double_click = repeated && !self.modifiers.shift() && !self.modifiers.command();
+                self.last_click = Some((uuid, Instant::now()));
+
+                let visible = uuids(self.visible_tracks().map(|(_, track)| track));
+                self.selection.click(uuid, self.modifiers, &visible);
+
+                if double_click {
+                    Task::done(Message::TrackMessage(i, uuid, TrackMessage::ChooseTrack))
+                } else {
+                    Task::none()
+                }
+            }
+            Message::TrackMessage(_, uuid, TrackMessage::ToggleLiked) => self.toggle_liked(uuid),
+            Message::TrackMessage(_, uuid, TrackMessage::Rate(stars)) => self.rate(uuid, stars),
+            Message::TrackMessage(i, _, TrackMessage::EditTags) => {
+                let tracks = self.core.init_queue().get(i).cloned().into_iter().collect();
+                Task::done(Message::OpenTagEditor(tracks))
+            }
             Message::TrackMessage(i, _uuid, track_message) => {
-                if let Some(track) = self.init_queue.get_mut(i) {
+                if let TrackMessage::ToggleActions | TrackMessage::OpenPlaylistMenu(_) =
+                    track_message
+                {
+                    // Only one row menu is shown at a time
+                    for (j, track) in self.core.list_mut().iter_mut().enumerate() {
+                        if j != i {
+                            track.show_actions = false;
+                            track.playlists = None;
+                        }
+                    }
+                }
+
+                if let Some(track) = self.core.list_mut().get_mut(i) {
                     match track_message {
                         TrackMessage::ChooseTrack => {
                             let _ = track.update(track_message);
-                            if !self.current_playlist.is_some() {
-                                let tracks = self.tracks.clone();
+                            let uuid = track.uuid;
+                            if let Some(playlist) = &self.current_playlist {
+                                let store = self.store.clone();
+                                let playlist_uuid = playlist.uuid;
 
-                                let set_queue_task = Task::done(Message::SetQueue((Ok(tracks), i)));
-                                let play_task = Task::done(Message::PlayTrack);
-
-                                Task::batch(vec![set_queue_task, play_task])
-                            } else {
-                                let pool = self.db_pool.clone();
-                                let playlist_uuid = self.current_playlist.as_ref().unwrap().uuid.clone();
-
-                                let set_queue_task = Task::perform(
+                                Task::perform(
                                     async move {
-                                        let tracks =
-                                            get_tracks_from_playlist(playlist_uuid, pool).await;
-                                        return (tracks, i);
+                                        (get_tracks_from_playlist(playlist_uuid, store).await, uuid)
                                     },
-                                    Message::SetQueue,
-                                );
-                                let play_task = Task::done(Message::PlayTrack);
-
-                                Task::batch(vec![play_task, set_queue_task])
+                                    Message::PlayList,
+                                )
+                            } else {
+                                Task::done(Message::PlayList((Ok(self.tracks.clone()), uuid)))
                             }
                         }
-                        TrackMessage::AddToQueue => {
+                        TrackMessage::ToggleActions => {
                             let _ = track.update(track_message);
-                            self.prio_queue.push_back(track.clone());
-
                             Task::none()
                         }
+                        TrackMessage::AddToQueue(position) => {
+                            let _ = track.update(track_message);
+                            let tracks = vec![track.clone()];
+                            self.playback(PlaybackCommand::Enqueue(tracks, position))
+                        }
+                        TrackMessage::QueueAlbum(position) => {
+                            let _ = track.update(track_message);
+                            match track.album.clone() {
+                                Some(album) => {
+                                    let tracks = queue::album_tracks(&self.tracks, &album);
+                                    self.playback(PlaybackCommand::Enqueue(tracks, position))
+                                }
+                                None => Task::none(),
+                            }
+                        }
+                        TrackMessage::QueueArtist(position) => {
+                            let _ = track.update(track_message);
+                            match track.artist.clone() {
+                                Some(artist) => {
+                                    let tracks = queue::artist_tracks(&self.tracks, &artist);
+                                    self.playback(PlaybackCommand::Enqueue(tracks, position))
+                                }
+                                None => Task::none(),
+                            }
+                        }
                         TrackMessage::OpenPlaylistMenu(_playlist) => {
                             let _ = track
                                 .update(TrackMessage::OpenPlaylistMenu(self.playlists.clone()));
@@ -237,43 +460,31 @@ impl Player {
                         TrackMessage::ToggleInPlaylist(playlist) => {
                             let tracks = playlist.tracks.clone();
                             let _ = track.update(TrackMessage::ToggleInPlaylist(playlist.clone()));
-                            let pool = self.db_pool.clone();
-                            let track_uuid = track.uuid.clone();
+                            let store = self.store.clone();
+                            let track_uuid = track.uuid;
 
-                            let db_task;
-
-                            let exists = tracks.iter().find(|uuid| **uuid == track_uuid);
-                            if exists.is_some() {
+                            let exists = tracks.contains(&track_uuid);
+                            let db_task = if exists {
                                 println!("DELETE FROM PLAYLIST");
-                                db_task = Task::perform(
+                                Task::perform(
                                     async move {
-                                        let playlist_models =
-                                            db::delete_from_playlist(&pool, playlist, track_uuid)
-                                                .await;
-                                        let playlists: Vec<Playlist> = playlist_models
-                                            .into_iter()
-                                            .map(Playlist::from)
-                                            .collect();
-                                        return playlists;
+                                        playlists(
+                                            store.delete_from_playlist(playlist, track_uuid).await,
+                                        )
                                     },
                                     Message::LoadPlaylist,
                                 )
                             } else {
                                 println!("INSERT INTO PLAYLIST");
-                                db_task = Task::perform(
+                                Task::perform(
                                     async move {
-                                        let playlist_models =
-                                            db::insert_into_playlist(&pool, playlist, track_uuid)
-                                                .await;
-                                        let playlists: Vec<Playlist> = playlist_models
-                                            .into_iter()
-                                            .map(Playlist::from)
-                                            .collect();
-                                        return playlists;
+                                        playlists(
+                                            store.insert_into_playlist(playlist, track_uuid).await,
+                                        )
                                     },
                                     Message::LoadPlaylist,
                                 )
-                            }
+                            };
 
                             db_task.chain(Task::done(Message::TrackMessage(
                                 i,
@@ -281,7 +492,11 @@ impl Player {
                                 TrackMessage::OpenPlaylistMenu(self.playlists.clone()),
                             )))
                         }
-                        TrackMessage::TrackEnd(_) => Task::none(),
+                        TrackMessage::Select
+                        | TrackMessage::ToggleLiked
+                        | TrackMessage::Rate(_)
+                        | TrackMessage::EditTags
+                        | TrackMessage::TrackEnd(_) => Task::none(),
                     }
                 } else {
                     Task::none()
@@ -290,7 +505,8 @@ impl Player {
             Message::PlaylistMessage(i, uuid, playlist_message) => match playlist_message {
                 PlaylistMessage::SelectPlaylist => {
                     println!("selected");
-                    let pool = self.db_pool.clone();
+                    let store = self.store.clone();
+                    self.selection.clear();
 
                     match &self.current_playlist {
                         Some(playlist) => {
@@ -300,82 +516,766 @@ impl Player {
                             } else {
                                 self.current_playlist = Some(self.playlists[i].clone());
                             }
-                        },
+                        }
                         None => {
                             println!("No playlist");
                             self.current_playlist = Some(self.playlists[i].clone());
                         }
                     }
+                    self.columns = self.config.columns(&self.view_key());
 
                     if self.current_playlist.is_some() {
-                        Task::perform(
-                            async move {
-                                let track_models = db::get_tracks_from_playlist(&pool, uuid).await;
-                                let tracks: Vec<Track> = track_models
-                                    .into_iter()
-                                    .map(|track| {
-                                        let track_metadata = Probe::open(&track.path)
-                                            .map_err(|_| LoadError::File)
-                                            .unwrap()
-                                            .read()
-                                            .map_err(|_| LoadError::File)
-                                            .unwrap();
-
-                                        let duration = track_metadata.properties().duration();
-                                        let duration_str = format!(
-                                            "{}:{}",
-                                            duration.as_secs() / 60,
-                                            duration.as_secs() % 60
-                                        );
-
-                                        let uuid = Uuid::from_str(&track.uuid).unwrap();
-                                        let path = PathBuf::from_str(&track.path).unwrap();
-                                        let name =
-                                        path.file_name().unwrap().to_str().unwrap().to_string();
-
-                                        Track {
-                                            uuid,
-                                            name,
-                                            duration_str,
-                                            duration,
-                                            path,
-                                            playlists: None,
-                                        }
-                                    })
-                                    .collect();
-
-                                return (Ok(tracks), 0);
-                            },
-                            Message::SetQueue,
-                        )
+                        Task::perform(get_tracks_from_playlist(uuid, store), Message::ShowList)
                     } else {
-                        Task::done(Message::SetQueue((Ok(self.tracks.clone()), 0)))
+                        Task::done(Message::ShowList(Ok(self.tracks.clone())))
                     }
-
+                }
+                PlaylistMessage::Enqueue(position) => {
+                    // Unlike selecting, queueing a playlist keeps the current list as is
+                    let store = self.store.clone();
+                    Task::perform(
+                        async move { (get_tracks_from_playlist(uuid, store).await, position) },
+                        Message::Enqueue,
+                    )
                 }
                 _ => Task::none(),
             },
-            Message::PlayTrack => {
-                let sender = self.sender.clone();
+            Message::Enqueue((tracks, position)) => match tracks {
+                Ok(tracks) => self.playback(PlaybackCommand::Enqueue(tracks, position)),
+                Err(err) => {
+                    self.toasts.push(format!("Unable to enqueue: {err}"));
+                    Task::none()
+                }
+            },
+            Message::SearchChanged(search) => {
+                self.search = search;
+                self.refresh_list();
+                Task::none()
+            }
+            Message::EnqueueSearch(position) => {
+                let tracks = self
+                    .visible_tracks()
+                    .map(|(_, track)| track.clone())
+                    .collect();
+                self.playback(PlaybackCommand::Enqueue(tracks, position))
+            }
+            Message::ColumnMessage(column_message) => {
+                if !self.column_header.update(&mut self.columns, column_message) {
+                    return Task::none();
+                }
 
-                self.current_pos = Duration::default();
-                self.timer = DurationBar::Ticking {
-                    last_tick: Instant::now(),
+                self.refresh_list();
+                self.config
+                    .columns
+                    .insert(self.view_key(), self.columns.clone());
+                Task::perform(self.config.clone().save(), Message::Err)
+            }
+            Message::ModifiersChanged(modifiers) => {
+                self.modifiers = modifiers;
+                Task::none()
+            }
+            Message::KeyPressed(key, modifiers) => match self.keybindings.action(&key, modifiers) {
+                Some(action) => self.run_action(action),
+                None => Task::none(),
+            },
+            Message::OpenTagEditor(tracks) => {
+                if tracks.is_empty() {
+                    return Task::none();
+                }
+
+                let tracks: Vec<(Uuid, PathBuf)> = tracks
+                    .into_iter()
+                    .map(|track| (track.uuid, track.path))
+                    .collect();
+                Task::perform(
+                    async move {
+                        // Files that can't be read are left out and listed in the editor
+                        tokio::task::spawn_blocking(move || {
+                            let mut readable = vec![];
+                            let mut values = vec![];
+                            let mut failed = vec![];
+                            for (uuid, path) in tracks {
+                                match tags::read_tags(&path) {
+                                    Ok(value) => {
+                                        readable.push((uuid, path));
+                                        values.push(value);
+                                    }
+                                    Err(err) => failed.push(format!("{path:?}: {err}")),
+                                }
+                            }
+                            if readable.is_empty() {
+                                return Err(failed.join("\n"));
+                            }
+
+                            let mut editor = TagEditor::new(readable, values);
+                            if !failed.is_empty() {
+                                editor.error = Some(format!(
+                                    "Left out, tags can't be read:\n{}",
+                                    failed.join("\n")
+                                ));
+                            }
+                            Ok(editor)
+                        })
+                        .await
+                        .map_err(|e| e.to_string())?
+                    },
+                    Message::TagEditorLoaded,
+                )
+            }
+            Message::TagEditorLoaded(editor) => {
+                match editor {
+                    Ok(editor) => self.tag_editor = Some(editor),
+                    Err(err) => self.toasts.push(format!("Unable to read tags: {err}")),
+                }
+                Task::none()
+            }
+            Message::TagEditorMessage(TagEditorMessage::Cancel) => {
+                self.tag_editor = None;
+                Task::none()
+            }
+            Message::TagEditorMessage(TagEditorMessage::Save) => {
+                let Some(editor) = &mut self.tag_editor else {
+                    return Task::none();
                 };
+                editor.saving = true;
+                editor.error = None;
+
+                let changes = editor.changes();
+                let library: HashMap<Uuid, &Track> = self
+                    .tracks
+                    .iter()
+                    .map(|track| (track.uuid, track))
+                    .collect();
+                let tracks: Vec<Track> = editor
+                    .tracks
+                    .iter()
+                    .filter_map(|(uuid, _)| library.get(uuid).map(|track| (*track).clone()))
+                    .collect();
 
-                let path = self.current_track.as_ref().unwrap().path.clone();
+                Task::perform(
+                    async move {
+                        tokio::task::spawn_blocking(move || {
+                            let mut saved = vec![];
+                            let mut errors = vec![];
+                            for track in tracks {
+                                let res = tags::write_tags(&track.path, &changes)
+                                    .and_then(|_| track.reload().map_err(|e| e.to_string()));
+                                match res {
+                                    Ok(track) => saved.push(track),
+                                    Err(err) => errors.push(format!("{}: {err}", track.name)),
+                                }
+                            }
+                            (saved, errors)
+                        })
+                        .await
+                        .unwrap_or_else(|e| (vec![], vec![e.to_string()]))
+                    },
+                    Message::TagsSaved,
+                )
+            }
+            Message::TagEditorMessage(message) => match &mut self.tag_editor {
+                Some(editor) => editor.update(message).map(Message::TagEditorMessage),
+                None => Task::none(),
+            },
+            Message::TagsSaved((saved, errors)) => {
+                // Library metadata comes from the files, put the reread tracks in place
+                for fresh in saved {
+                    self.for_each_copy(fresh.uuid, |track| {
+                        *track = Track {
+                            playlists: track.playlists.take(),
+                            show_actions: track.show_actions,
+                            ..fresh.clone()
+                        };
+                    });
+                }
+                self.refresh_list();
 
-                println!("Track played");
+                if errors.is_empty() {
+                    self.tag_editor = None;
+                } else if let Some(editor) = &mut self.tag_editor {
+                    editor.saving = false;
+                    editor.error = Some(errors.join("\n"));
+                }
+                Task::none()
+            }
+            Message::OpenOrganizer => {
+                // Selected tracks when there are any, whole library otherwise
+                let tracks = match self.selection.is_empty() {
+                    true => self.tracks.clone(),
+                    false => self.selected_tracks(),
+                };
+                let tracks = tracks
+                    .into_iter()
+                    .map(|track| (track.uuid, track.path))
+                    .collect();
+
+                self.organizer = Some(Organizer::new(
+                    HOME_PATH.into(),
+                    self.config.organize_pattern.clone(),
+                    tracks,
+                ));
+                Task::none()
+            }
+            Message::OrganizerMessage(OrganizerMessage::Close) => {
+                self.organizer = None;
+                Task::none()
+            }
+            Message::OrganizerMessage(OrganizerMessage::Apply) => {
+                let Some(organizer) = &mut self.organizer else {
+                    return Task::none();
+                };
+                organizer.busy = true;
+                organizer.error = None;
+                self.config.organize_pattern = organizer.pattern.clone();
+
+                let store = self.store.clone();
+                let moves = organizer.moves();
+                Task::batch(vec![
+                    Task::perform(move_files(store, moves, false), |(done, err)| {
+                        Message::FilesMoved(done, err, false)
+                    }),
+                    Task::perform(self.config.clone().save(), Message::Err),
+                ])
+            }
+            Message::OrganizerMessage(OrganizerMessage::Undo) => {
+                let Some(organizer) = &mut self.organizer else {
+                    return Task::none();
+                };
+                organizer.busy = true;
+                organizer.error = None;
+
+                let store = self.store.clone();
+                let moves = self.last_organized.clone();
+                Task::perform(move_files(store, moves, true), |(done, err)| {
+                    Message::FilesMoved(done, err, true)
+                })
+            }
+            Message::OrganizerMessage(message) => match &mut self.organizer {
+                Some(organizer) => organizer.update(message).map(Message::OrganizerMessage),
+                None => Task::none(),
+            },
+            Message::FilesMoved(done, error, undone) => {
+                for moved in &done {
+                    self.for_each_copy(moved.uuid, |track| track.path = moved.to.clone());
+                }
+
+                if undone {
+                    // Whatever failed to go back can still be undone later
+                    self.last_organized
+                        .retain(|moved| !done.iter().any(|back| back.uuid == moved.uuid));
+                } else {
+                    self.last_organized = done;
+                }
+
+                if let Some(organizer) = &mut self.organizer {
+                    organizer.busy = false;
+                    organizer.plan = None;
+                    organizer.error = error;
+                } else if let Some(err) = error {
+                    // Closed meanwhile, files may be left half moved
+                    self.toasts.push(format!("Unable to organize files: {err}"));
+                }
+                Task::batch(vec![Task::done(Message::SaveSession), self.load_gains()])
+            }
+            Message::ToggleProblems => {
+                self.show_problems = !self.show_problems;
+                Task::none()
+            }
+            Message::ProblemsMessage(ProblemsMessage::Close) => {
+                self.show_problems = false;
+                Task::none()
+            }
+            Message::ProblemsMessage(ProblemsMessage::RetryAll) => {
+                self.rescanning = true;
+                let store = self.store.clone();
+                Task::perform(SavedState::load(store), Message::Rescanned)
+            }
+            Message::ProblemsMessage(ProblemsMessage::Retry(i)) => {
+                let Some(problem) = self.problems.get(i).cloned() else {
+                    return Task::none();
+                };
+                let Some(uuid) = problem.uuid else {
+                    return Task::none();
+                };
+
+                let store = self.store.clone();
                 Task::perform(
                     async move {
-                        let _ = sender.send(Command::Play(path)).await;
+                        let model = match store.track(uuid).await {
+                            Ok(Some(model)) => model,
+                            Ok(None) => {
+                                return Err(Problem {
+                                    reason: "Track is not in library anymore".to_string(),
+                                    ..problem
+                                })
+                            }
+                            Err(err) => {
+                                return Err(Problem {
+                                    reason: err.to_string(),
+                                    ..problem
+                                })
+                            }
+                        };
+
+                        let path = problem.path.clone();
+                        tokio::task::spawn_blocking(move || {
+                            Track::try_from(model).map_err(|e| Problem::track(path, uuid, &e))
+                        })
+                        .await
+                        .unwrap_or_else(|e| {
+                            Err(Problem {
+                                reason: e.to_string(),
+                                ..problem
+                            })
+                        })
                     },
-                    |_| (),
+                    move |res| Message::TrackRetried(uuid, res),
                 )
-                .discard()
+            }
+            Message::TrackRetried(uuid, res) => {
+                let i = self.problems.iter().position(|p| p.uuid == Some(uuid));
+                match (res, i) {
+                    (Ok(track), Some(i)) => {
+                        self.problems.remove(i);
+                        if self.current_playlist.is_none() {
+                            self.core.list_mut().push(track.clone());
+                        }
+                        self.tracks.push(track);
+                        self.refresh_list();
+                    }
+                    (Err(problem), Some(i)) => self.problems[i] = problem,
+                    (_, None) => {}
+                }
+
+                if self.problems.is_empty() {
+                    self.show_problems = false;
+                }
+                Task::none()
+            }
+            Message::OpenDuplicates => {
+                self.duplicates = Some(DuplicateFinder::default());
+                Task::none()
+            }
+            Message::DuplicatesMessage(DuplicatesMessage::Close) => {
+                self.duplicates = None;
+                Task::none()
+            }
+            Message::DuplicatesMessage(DuplicatesMessage::Find) => {
+                let Some(finder) = &mut self.duplicates else {
+                    return Task::none();
+                };
+                finder.busy = true;
+                finder.error = None;
+
+                let store = self.store.clone();
+                let tracks = self.tracks.clone();
+                let reasons = finder.reasons.clone();
+                Task::perform(find_duplicates(store, tracks, reasons), |groups| {
+                    Message::DuplicatesMessage(DuplicatesMessage::Found(groups))
+                })
+            }
+            Message::DuplicatesMessage(DuplicatesMessage::Merge) => {
+                let Some(finder) = &mut self.duplicates else {
+                    return Task::none();
+                };
+                finder.busy = true;
+                finder.error = None;
+
+                let store = self.store.clone();
+                let merges = finder.merges();
+                Task::perform(
+                    merge_duplicates(store, merges, finder.delete_files),
+                    Message::DuplicatesMerged,
+                )
+            }
+            Message::DuplicatesMessage(message) => match &mut self.duplicates {
+                Some(finder) => finder.update(message).map(Message::DuplicatesMessage),
+                None => Task::none(),
+            },
+            Message::DuplicatesMerged(res) => {
+                let (merged, deleted) = match res {
+                    Ok(res) => res,
+                    Err(err) => {
+                        if let Some(finder) = &mut self.duplicates {
+                            finder.busy = false;
+                            finder.error = Some(err);
+                        }
+                        return Task::none();
+                    }
+                };
+
+                // Plays, ratings and added dates as db has them after the merge
+                for model in merged {
+                    let Ok(uuid) = model.uuid.parse() else {
+                        continue;
+                    };
+                    self.for_each_copy(uuid, |track| {
+                        track.play_count = model.play_count;
+                        track.rating = model.rating.map(|r| r.clamp(1, 5) as u8);
+                        track.added_at = model.added_at;
+                    });
+                }
+
+                let gone = |track: &Track| deleted.contains(&track.uuid);
+                self.tracks.retain(|track| !gone(track));
+                self.core.retain(|track| !gone(track));
+                self.refresh_list();
+
+                self.duplicates = None;
+                let store = self.store.clone();
+                Task::perform(
+                    async move { playlists(store.playlists().await) },
+                    Message::LoadPlaylist,
+                )
+                .chain(Task::done(Message::SaveSession))
+            }
+            Message::ToggleSettings => {
+                self.show_settings = !self.show_settings;
+                if self.show_settings {
+                    self.list_devices()
+                } else {
+                    Task::none()
+                }
+            }
+            Message::SettingsMessage(SettingsMessage::PickDevice(device)) => {
+                self.config.output_device = device.clone();
+                Task::batch(vec![
+                    self.send(Command::SetDevice(device)),
+                    Task::perform(self.config.clone().save(), Message::Err),
+                ])
+            }
+            Message::SettingsMessage(SettingsMessage::SetGainMode(mode)) => {
+                self.config.replay_gain.mode = mode;
+                self.save_gain()
+            }
+            Message::SettingsMessage(SettingsMessage::SetPreamp(preamp)) => {
+                self.config.replay_gain.preamp =
+                    preamp.clamp(-replaygain::MAX_PREAMP, replaygain::MAX_PREAMP);
+                self.save_gain()
+            }
+            Message::SettingsMessage(SettingsMessage::AnalyzeLoudness(on)) => {
+                self.config.analyze_loudness = on;
+                self.analysis = if on {
+                    AnalysisState::Looking
+                } else {
+                    AnalysisState::Off
+                };
+                Task::perform(self.config.clone().save(), Message::Err)
+            }
+            Message::SettingsMessage(SettingsMessage::WriteGainTags(on)) => {
+                // Analysis starts over, files measured before are left as they are
+                self.config.write_gain_tags = on;
+                if self.config.analyze_loudness {
+                    self.analysis = AnalysisState::Looking;
+                }
+                Task::perform(self.config.clone().save(), Message::Err)
+            }
+            Message::SettingsMessage(SettingsMessage::Refresh) => self.list_devices(),
+            Message::SettingsMessage(SettingsMessage::Close) => {
+                self.show_settings = false;
+                Task::none()
+            }
+            Message::DevicesListed(devices) => {
+                self.devices = Some(devices);
+                Task::none()
+            }
+            Message::IdentifyFailed(err) => {
+                self.toasts
+                    .push(format!("Unable to identify tracks: {err}"));
+                Task::none()
+            }
+            Message::Analysis(event) => {
+                self.analysis.update(&event);
+                match event {
+                    AnalysisEvent::Analyzed(_, gains) => self.send(Command::AddGains(gains)),
+                    AnalysisEvent::Failed(err) => {
+                        self.toasts
+                            .push(format!("Loudness analysis stopped: {err}"));
+                        Task::none()
+                    }
+                    AnalysisEvent::Started(_) | AnalysisEvent::Finished => Task::none(),
+                }
+            }
+            Message::GainsLoaded(Ok(measured)) => {
+                let gains = self
+                    .tracks
+                    .iter()
+                    .filter_map(|track| {
+                        let measured = measured.get(&track.uuid)?.as_ref()?;
+                        Some((track.path.clone(), measured.replay_gain()))
+                    })
+                    .collect();
+                self.send(Command::AddGains(gains))
+            }
+            Message::GainsLoaded(Err(err)) => {
+                self.toasts.push(format!("Unable to load loudness: {err}"));
+                Task::none()
+            }
+            Message::ToggleBackups => {
+                self.show_backups = !self.show_backups;
+                self.backups = backup::list(&backup::dir());
+                Task::none()
+            }
+            Message::BackupMessage(BackupMessage::Close) => {
+                self.show_backups = false;
+                Task::none()
+            }
+            Message::BackupMessage(BackupMessage::BackUp) => {
+                self.backup_busy = true;
+                let store = self.store.clone();
+                Task::perform(
+                    async move {
+                        store
+                            .back_up(&backup::dir(), "manual")
+                            .await
+                            .map(|path| format!("Backed up to {}", path.display()))
+                            .map_err(|e| e.to_string())
+                    },
+                    Message::BackupDone,
+                )
+            }
+            Message::BackupMessage(BackupMessage::Export) => Task::perform(
+                async {
+                    rfd::AsyncFileDialog::new()
+                        .add_filter("JSON", &["json"])
+                        .set_file_name("music_player_export.json")
+                        .save_file()
+                        .await
+                        .map(|file| file.path().to_path_buf())
+                },
+                Message::ExportPicked,
+            ),
+            Message::BackupMessage(BackupMessage::Import) => Task::perform(
+                async {
+                    rfd::AsyncFileDialog::new()
+                        .add_filter("JSON", &["json"])
+                        .pick_file()
+                        .await
+                        .map(|file| file.path().to_path_buf())
+                },
+                Message::ImportPicked,
+            ),
+            Message::ExportPicked(Some(path)) => {
+                self.backup_busy = true;
+                let store = self.store.clone();
+                let tracks = self.tracks.clone();
+                let playlists = self.playlists.clone();
+                Task::perform(
+                    export_library(store, tracks, playlists, path),
+                    Message::BackupDone,
+                )
+            }
+            Message::ImportPicked(Some(path)) => {
+                self.backup_busy = true;
+                let store = self.store.clone();
+                let tracks = self.tracks.clone();
+                Task::perform(import_library(store, tracks, path), Message::Imported)
+            }
+            Message::ExportPicked(None) | Message::ImportPicked(None) => Task::none(),
+            Message::BackupDone(res) => {
+                self.backup_busy = false;
+                self.backups = backup::list(&backup::dir());
+                match res {
+                    Ok(status) => self.backup_status = Some(status),
+                    Err(err) => self.toasts.push(err),
+                }
+                Task::none()
+            }
+            Message::Imported(res) => {
+                self.backup_busy = false;
+                match res {
+                    Ok(status) => self.backup_status = Some(status),
+                    Err(err) => {
+                        self.toasts.push(err);
+                        return Task::none();
+                    }
+                }
+
+                // Stats and playlists changed under loaded tracks
+                self.rescanning = true;
+                let store = self.store.clone();
+                Task::perform(SavedState::load(store), Message::Rescanned)
+            }
+            Message::ToggleHelp => {
+                self.show_help = !self.show_help;
+                Task::none()
+            }
+            Message::ThemeChanged(mode) => {
+                self.config.appearance.theme = mode;
+                Task::perform(self.config.clone().save(), Message::Err)
+            }
+            Message::CheckSystemTheme => Task::perform(
+                async {
+                    tokio::task::spawn_blocking(theme::system_is_dark)
+                        .await
+                        .unwrap_or_default()
+                },
+                Message::SystemThemeChanged,
+            ),
+            Message::SystemThemeChanged(dark) => {
+                self.system_dark = dark;
+                Task::none()
+            }
+            Message::ListScrolled(viewport) => {
+                self.track_list.scrolled(viewport);
+                Task::none()
+            }
+            Message::WindowResized(size) => {
+                self.track_list.resized(size.height);
+                Task::none()
+            }
+            Message::SelectionMessage(selection_message) => match selection_message {
+                SelectionMessage::SelectAll => {
+                    let visible = uuids(self.visible_tracks().map(|(_, track)| track));
+                    self.selection.select_all(&visible);
+                    Task::none()
+                }
+                SelectionMessage::Clear => {
+                    self.selection.clear();
+                    Task::none()
+                }
+                SelectionMessage::Play => {
+                    let tracks = self.selected_tracks();
+                    self.playback(PlaybackCommand::PlayTracks(tracks))
+                }
+                SelectionMessage::Enqueue(position) => {
+                    let tracks = self.selected_tracks();
+                    self.playback(PlaybackCommand::Enqueue(tracks, position))
+                }
+                SelectionMessage::TogglePlaylistMenu => {
+                    self.selection.show_playlists = !self.selection.show_playlists;
+                    Task::none()
+                }
+                SelectionMessage::AddToPlaylist(playlist) => {
+                    self.selection.show_playlists = false;
+                    let store = self.store.clone();
+                    let tracks: Vec<Uuid> = uuids(&self.selected_tracks())
+                        .into_iter()
+                        .filter(|uuid| !playlist.tracks.contains(uuid))
+                        .collect();
+
+                    Task::perform(
+                        async move { playlists(store.append_to_playlist(playlist, tracks).await) },
+                        Message::LoadPlaylist,
+                    )
+                }
+                SelectionMessage::RemoveFromPlaylist => {
+                    let Some(playlist) = self.current_playlist.clone() else {
+                        return Task::none();
+                    };
+
+                    let store = self.store.clone();
+                    let tracks = uuids(&self.selected_tracks());
+
+                    // Current list is this playlist, so drop removed tracks from it right away
+                    let selection = &self.selection;
+                    self.core
+                        .retain_list(|track| !selection.is_selected(&track.uuid));
+                    self.selection.clear();
+                    self.refresh_list();
+
+                    Task::perform(
+                        async move {
+                            playlists(store.delete_many_from_playlist(playlist, tracks).await)
+                        },
+                        Message::LoadPlaylist,
+                    )
+                    .chain(Task::done(Message::SaveSession))
+                }
+                SelectionMessage::EditTags => {
+                    Task::done(Message::OpenTagEditor(self.selected_tracks()))
+                }
+                SelectionMessage::Reveal => {
+                    let mut folders = HashSet::new();
+                    for track in self.selected_tracks() {
+                        if folders.insert(track.path.parent().map(|p| p.to_path_buf())) {
+                            if let Err(err) = utils::reveal_in_file_manager(&track.path) {
+                                println!("Unable to open file manager: {err}");
+                            }
+                        }
+                    }
+                    Task::none()
+                }
+            },
+            Message::QueueMessage(queue_message) => match queue_message {
+                QueueMessage::Remove(section, i) => {
+                    self.playback(PlaybackCommand::Remove(section, i))
+                }
+                QueueMessage::MoveUp(section, i) => match i.checked_sub(1) {
+                    Some(to) => self.playback(PlaybackCommand::Move((section, i), (section, to))),
+                    None => Task::none(),
+                },
+                QueueMessage::MoveDown(section, i) => {
+                    self.playback(PlaybackCommand::Move((section, i), (section, i + 1)))
+                }
+                QueueMessage::Clear(section) => self.playback(PlaybackCommand::Clear(section)),
+                QueueMessage::PlayFrom(section, i) => {
+                    self.playback(PlaybackCommand::PlayFrom(section, i))
+                }
+                QueueMessage::DragStart(section, i) => {
+                    self.queue_drag = Some(QueueDrag {
+                        from: (section, i),
+                        over: None,
+                    });
+                    Task::none()
+                }
+                QueueMessage::DragOver(section, i) => {
+                    if let Some(drag) = &mut self.queue_drag {
+                        drag.over = Some((section, i));
+                    }
+                    Task::none()
+                }
+                QueueMessage::Drop => match self.queue_drag.take() {
+                    Some(QueueDrag {
+                        from,
+                        over: Some(to),
+                    }) => self.playback(PlaybackCommand::Move(from, to)),
+                    _ => Task::none(),
+                },
+                QueueMessage::CancelDrag => {
+                    self.queue_drag = None;
+                    Task::none()
+                }
+                QueueMessage::PlaylistNameChanged(name) => {
+                    self.queue_playlist_name = name;
+                    Task::none()
+                }
+                QueueMessage::SaveAsPlaylist => {
+                    let store = self.store.clone();
+                    let title = std::mem::take(&mut self.queue_playlist_name)
+                        .trim()
+                        .to_string();
+                    let tracks = queue::queued_tracks(
+                        self.core.current_track(),
+                        self.core.prio_queue(),
+                        self.core.queue(),
+                    );
+
+                    Task::perform(
+                        async move { playlists(store.create_playlist(title, tracks).await) },
+                        Message::LoadPlaylist,
+                    )
+                }
+                QueueMessage::AppendToPlaylist(playlist) => {
+                    let store = self.store.clone();
+                    let tracks = queue::queued_tracks(
+                        self.core.current_track(),
+                        self.core.prio_queue(),
+                        self.core.queue(),
+                    );
+
+                    Task::perform(
+                        async move { playlists(store.append_to_playlist(playlist, tracks).await) },
+                        Message::LoadPlaylist,
+                    )
+                }
+            },
+            Message::ToggleQueuePanel => {
+                self.show_queue = !self.show_queue;
+                Task::none()
             }
             Message::ToggleTrack => {
-                if self.current_track.is_none() {
+                if self.core.current_track().is_none() {
                     return Task::none();
                 }
 
@@ -395,92 +1295,158 @@ impl Player {
                     |_| (),
                 )
                 .discard()
+                .chain(Task::done(Message::SaveSession))
             }
-            Message::JumpToNext => {
-                if self.current_track.is_none() {
-                    return Task::none();
-                }
-
-                self.backward_queue.push(self.current_track.take().unwrap());
-
-                if self.prio_queue.len() > 0 {
-                    self.current_track = self.prio_queue.pop_front();
-                } else {
-                    if self.queue.len() == 0 {
-                        self.queue = self.init_queue.clone().into();
-                        self.backward_queue = vec![];
-                    };
-                    self.current_track = self.queue.pop_front();
-                }
-
-                Task::done(Message::PlayTrack)
+            Message::JumpToNext => self.playback(PlaybackCommand::Next),
+            Message::JumpToPrev => self.playback(PlaybackCommand::Prev),
+            Message::ToggleShuffle => {
+                let shuffle = !self.core.shuffle();
+                self.playback(PlaybackCommand::SetShuffle(shuffle))
             }
-            Message::JumpToPrev => {
-                if self.current_track.is_none() {
-                    return Task::none();
-                }
-
-                self.queue.push_front(self.current_track.take().unwrap());
-
-                if self.backward_queue.len() == 0 {
-                    self.backward_queue = self.init_queue.clone();
-                    self.queue = VecDeque::new();
+            Message::CycleRepeat => {
+                let repeat = self.core.repeat().next();
+                self.playback(PlaybackCommand::SetRepeat(repeat))
+            }
+            Message::PlayList((tracks, uuid)) => {
+                let mut tracks = match tracks {
+                    Ok(tracks) => tracks,
+                    Err(err) => {
+                        self.toasts.push(err);
+                        return Task::none();
+                    }
                 };
-                self.current_track = self.backward_queue.pop();
 
-                Task::done(Message::PlayTrack)
+                // Play in the order the list is shown
+                tracks.sort_by(|a, b| self.columns.compare(a, b));
+                let start = tracks.iter().position(|t| t.uuid == uuid).unwrap_or(0);
+                let task = self.playback(PlaybackCommand::PlayList(tracks, start));
+                self.refresh_list();
+                task
             }
-            Message::SetQueue((tracks, idx)) => {
-                println!("Tracks for init queue: {tracks:#?}");
-                self.init_queue = tracks.unwrap();
-                self.backward_queue = self.init_queue.clone().into();
-                self.queue = self.backward_queue.split_off(idx).into();
+            Message::ShowList(tracks) => {
+                let tracks = match tracks {
+                    Ok(tracks) => tracks,
+                    Err(err) => {
+                        self.toasts.push(err);
+                        return Task::none();
+                    }
+                };
 
-                self.current_track = Some(self.queue.pop_front().unwrap());
-                Task::none()
+                let task = self.playback(PlaybackCommand::SetList(tracks));
+                self.refresh_list();
+                task
             }
             Message::Tick(now) => {
-                if self.current_track.is_none() {
+                let Some(track) = self.core.current_track() else {
                     return Task::none();
-                }
+                };
 
                 if let DurationBar::Ticking { last_tick } = &mut self.timer {
-                    let dur = self.current_track.as_ref().unwrap().duration;
-                    if self.current_pos >= dur {
-                        return Task::done(Message::JumpToNext);
+                    if self.current_pos >= track.duration {
+                        return self.playback(PlaybackCommand::TrackEnded);
                     } else {
                         self.current_pos += now - *last_tick;
                         *last_tick = now;
                         return Task::none();
                     }
-                    // println!("now {:#?}", self.current_pos);
                 }
                 Task::none()
             }
+            Message::SaveSession => {
+                let store = self.store.clone();
+                let session = self.session();
+                Task::perform(
+                    async move { store.save_session(session).await.map_err(|e| e.to_string()) },
+                    Message::Err,
+                )
+            }
+            Message::CloseRequested(id) => {
+                let store = self.store.clone();
+                let session = self.session();
+                Task::perform(async move { store.save_session(session).await }, |res| {
+                    if let Err(err) = res {
+                        println!("Unable to save session: {err}");
+                    }
+                })
+                .then(move |_| window::close(id))
+            }
             Message::Err(res) => {
-                println!("{res:#?}");
+                if let Err(err) = res {
+                    println!("{err:#?}");
+                    self.toasts.push(err);
+                }
+                Task::none()
+            }
+            Message::Toast(message) => {
+                self.toasts.update(message);
                 Task::none()
             }
         }
     }
 
-    fn view(&self) -> Element<Message> {
-        let tracks: Element<_> = if self.init_queue.len() > 0 {
-            keyed_column(self.init_queue.iter().enumerate().map(|(i, track)| {
-                let uuid = track.uuid;
-                (
-                    track.uuid,
-                    track
-                        .view()
-                        .map(move |message| Message::TrackMessage(i, uuid, message)),
+    fn view(&self) -> Element<'_, Message> {
+        let tracks: Element<_> = if !self.core.init_queue().is_empty() {
+            let searching = !self.search.is_empty();
+
+            let search_bar = row![
+                text_input("Search", &self.search)
+                    .id(search_id())
+                    .on_input(Message::SearchChanged)
+                    .width(Fill),
+                button("Play next").on_press_maybe(
+                    searching.then_some(Message::EnqueueSearch(QueuePosition::Next))
+                ),
+                button("Add to queue").on_press_maybe(
+                    searching.then_some(Message::EnqueueSearch(QueuePosition::End))
+                ),
+            ]
+            .spacing(5);
+
+            let rows = self
+                .track_list
+                .view(
+                    self.core.init_queue(),
+                    &self.selection,
+                    &self.liked,
+                    &self.columns,
                 )
-            }))
-            .spacing(10)
-            .width(Length::FillPortion(5))
-            .height(Fill)
-            .into()
+                .map(|message| match message {
+                    TrackListMessage::Scrolled(viewport) => Message::ListScrolled(viewport),
+                    TrackListMessage::Track(i, uuid, message) => {
+                        Message::TrackMessage(i, uuid, message)
+                    }
+                });
+
+            let header = self
+                .column_header
+                .view(&self.columns)
+                .map(Message::ColumnMessage);
+
+            let mut list = column![search_bar, header].spacing(10);
+            if !self.selection.is_empty() {
+                list = list.push(
+                    self.selection
+                        .view(&self.playlists, self.current_playlist.as_ref())
+                        .map(Message::SelectionMessage),
+                );
+            }
+
+            let menu = self
+                .core
+                .init_queue()
+                .iter()
+                .enumerate()
+                .find_map(|(i, track)| Some((i, track.uuid, track.menu()?)));
+            if let Some((i, uuid, menu)) = menu {
+                list = list.push(menu.map(move |message| Message::TrackMessage(i, uuid, message)));
+            }
+
+            list.push(rows)
+                .width(Length::FillPortion(5))
+                .height(Fill)
+                .into()
         } else {
-            center(text("Hello").width(Fill).size(25).color([0.7, 0.7, 0.7]))
+            center(text("Hello").width(Fill).size(25).style(theme::muted))
                 .height(200)
                 .into()
         };
@@ -492,7 +1458,7 @@ impl Player {
                 (
                     playlist.uuid,
                     playlist
-                        .view()
+                        .view(self.current_playlist.as_ref().map(|p| p.uuid) == Some(uuid))
                         .map(move |message| Message::PlaylistMessage(i, uuid, message)),
                 )
             }))
@@ -500,10 +1466,37 @@ impl Player {
         .width(Length::FillPortion(1))
         .height(Length::Fill);
 
-        let content = row![playlists, tracks].width(Fill).height(Fill);
+        let mut content = row![playlists, tracks].width(Fill).height(Fill);
+
+        if self.show_queue {
+            let remaining = queue::remaining_time(
+                self.core.current_track(),
+                self.current_pos,
+                self.core.prio_queue(),
+                self.core.queue(),
+            );
+
+            let queue_panel = container(
+                queue::view(
+                    self.core.current_track(),
+                    self.core.prio_queue(),
+                    self.core.queue(),
+                    remaining,
+                    self.queue_drag,
+                    &self.playlists,
+                    &self.queue_playlist_name,
+                )
+                .map(Message::QueueMessage),
+            )
+            .padding([0, 10])
+            .width(Length::FillPortion(2))
+            .height(Fill);
+
+            content = content.push(queue_panel);
+        }
 
         let mut dur = 0.0;
-        if let Some(track) = &self.current_track {
+        if let Some(track) = self.core.current_track() {
             dur = track.duration.as_secs_f32();
         };
 
@@ -517,18 +1510,82 @@ impl Player {
             ],
             row![
                 horizontal_space(),
+                button("Shuffle")
+                    .style(if self.core.shuffle() {
+                        button::primary
+                    } else {
+                        button::secondary
+                    })
+                    .on_press(Message::ToggleShuffle),
                 button("<").on_press(Message::JumpToPrev),
                 button("||").on_press(Message::ToggleTrack),
                 button(">").on_press(Message::JumpToNext),
+                button(text(self.core.repeat().to_string())).on_press(Message::CycleRepeat),
                 horizontal_space(),
+                text(format!("Volume {:.0}%", self.volume * 100.0)),
+                button("Queue").on_press(Message::ToggleQueuePanel),
+                button("Organize").on_press(Message::OpenOrganizer),
+                button("Duplicates").on_press(Message::OpenDuplicates),
+                button("Backups").on_press(Message::ToggleBackups),
+                button("Settings").on_press(Message::ToggleSettings),
+                pick_list(
+                    ThemeMode::ALL,
+                    Some(self.config.appearance.theme),
+                    Message::ThemeChanged
+                ),
+                button("?").on_press(Message::ToggleHelp),
             ]
+            .push_maybe(match self.analysis {
+                AnalysisState::Running { done, total } if done < total =>
+                    Some(text(format!("Measuring loudness {done}/{total}")).style(theme::muted),),
+                _ => None,
+            })
+            .push_maybe((!self.problems.is_empty()).then(|| {
+                button(text(format!("Problems ({})", self.problems.len())))
+                    .style(button::danger)
+                    .on_press(Message::ToggleProblems)
+            }))
             .padding([10, 0])
             .spacing(50),
         ])
         .center_x(Fill);
 
         let content = column![content, control].padding([10, 20]);
-        container(content).width(Fill).height(Fill).into()
+        let content = container(content).width(Fill).height(Fill);
+
+        let overlay = if let Some(editor) = &self.tag_editor {
+            Some(editor.view().map(Message::TagEditorMessage))
+        } else if let Some(finder) = &self.duplicates {
+            Some(finder.view().map(Message::DuplicatesMessage))
+        } else if let Some(organizer) = &self.organizer {
+            let can_undo = !self.last_organized.is_empty();
+            Some(organizer.view(can_undo).map(Message::OrganizerMessage))
+        } else if self.show_settings {
+            Some(
+                settings::view(self.devices.as_ref(), &self.config, &self.analysis)
+                    .map(Message::SettingsMessage),
+            )
+        } else if self.show_backups {
+            let status = self.backup_status.as_deref();
+            Some(backup::view(&self.backups, status, self.backup_busy).map(Message::BackupMessage))
+        } else if self.show_problems {
+            Some(scan::view(&self.problems, self.rescanning).map(Message::ProblemsMessage))
+        } else if self.show_help {
+            Some(self.keybindings.view(Message::ToggleHelp))
+        } else {
+            None
+        };
+
+        let content: Element<_> = match overlay {
+            Some(overlay) => stack![content, opaque(center(overlay))].into(),
+            None => content.into(),
+        };
+
+        if self.toasts.is_empty() {
+            content
+        } else {
+            stack![content, self.toasts.view().map(Message::Toast)].into()
+        }
     }
 
     fn subscription(&self) -> Subscription<Message> {
@@ -539,112 +1596,674 @@ impl Player {
             }
         };
 
-        Subscription::batch(vec![tick])
+        // Desktops don't notify about theme changes in a portable way, so ask now and then
+        let system_theme = if self.follows_system_theme() {
+            time::every(Duration::from_secs(5)).map(|_| Message::CheckSystemTheme)
+        } else {
+            Subscription::none()
+        };
+
+        let close = window::close_requests().map(Message::CloseRequested);
+        let resize = window::resize_events().map(|(_id, size)| Message::WindowResized(size));
+
+        let keyboard = event::listen_with(|event, status, _window| match event {
+            Event::Keyboard(keyboard::Event::ModifiersChanged(modifiers)) => {
+                Some(Message::ModifiersChanged(modifiers))
+            }
+            // Keys taken by focused widgets, like typing in search, are not shortcuts
+            Event::Keyboard(keyboard::Event::KeyPressed { key, modifiers, .. })
+                if status == event::Status::Ignored =>
+            {
+                Some(Message::KeyPressed(key, modifiers))
+            }
+            _ => None,
+        });
+
+        let toasts = self.toasts.subscription().map(Message::Toast);
+
+        // Measured albums are saved, so a new run goes on where the last one stopped
+        let analysis = if self.loaded && self.config.analyze_loudness {
+            let write_tags = self.config.write_gain_tags;
+            let id = ("loudness", write_tags, self.scan_round);
+            Subscription::run_with_id(id, analysis::run(self.store.clone(), write_tags))
+                .map(Message::Analysis)
+        } else {
+            Subscription::none()
+        };
+
+        // Identities let scans find moved files, they're filled in after loading
+        let identify = if self.loaded {
+            Subscription::run_with_id(
+                ("identity", self.scan_round),
+                identity::run(self.store.clone()),
+            )
+            .map(Message::IdentifyFailed)
+        } else {
+            Subscription::none()
+        };
+
+        Subscription::batch(vec![
+            tick,
+            system_theme,
+            close,
+            resize,
+            keyboard,
+            toasts,
+            analysis,
+            identify,
+        ])
+    }
+
+    /// Rows of current list that pass the search, with their index in `init_queue`
+    /// Sorted as set in column layout
+    fn visible_tracks(&self) -> impl Iterator<Item = (usize, &Track)> {
+        self.track_list
+            .rows()
+            .iter()
+            .map(|i| (*i, &self.core.init_queue()[*i]))
+    }
+
+    fn refresh_list(&mut self) {
+        self.track_list
+            .refresh(self.core.init_queue(), &self.search, &self.columns);
+    }
+
+    fn theme(&self) -> Theme {
+        self.config
+            .appearance
+            .theme(self.system_dark, self.custom_theme.as_ref())
+    }
+
+    fn scale_factor(&self) -> f64 {
+        self.config.appearance.scale_factor()
+    }
+
+    fn follows_system_theme(&self) -> bool {
+        match self.config.appearance.theme {
+            ThemeMode::System => true,
+            ThemeMode::Custom => self.custom_theme.is_none(),
+            ThemeMode::Light | ThemeMode::Dark => false,
+        }
+    }
+
+    fn run_action(&mut self, action: Action) -> Task<Message> {
+        match action {
+            Action::TogglePlay => self.update(Message::ToggleTrack),
+            Action::Next => self.update(Message::JumpToNext),
+            Action::Prev => self.update(Message::JumpToPrev),
+            Action::SeekForward => self.seek(self.current_pos + SEEK_STEP),
+            Action::SeekBackward => self.seek(self.current_pos.saturating_sub(SEEK_STEP)),
+            Action::VolumeUp => self.set_volume(self.volume + VOLUME_STEP),
+            Action::VolumeDown => self.set_volume(self.volume - VOLUME_STEP),
+            Action::FocusSearch => text_input::focus(search_id()),
+            Action::ToggleQueue => self.update(Message::ToggleQueuePanel),
+            Action::LikeCurrent => match self.core.current_track() {
+                Some(track) => self.toggle_liked(track.uuid),
+                None => Task::none(),
+            },
+            Action::Rate0
+            | Action::Rate1
+            | Action::Rate2
+            | Action::Rate3
+            | Action::Rate4
+            | Action::Rate5 => match (self.core.current_track(), action.rating()) {
+                (Some(track), Some(stars)) => self.rate(track.uuid, stars),
+                _ => Task::none(),
+            },
+            Action::SelectPrev | Action::SelectNext => {
+                let delta = if action == Action::SelectPrev { -1 } else { 1 };
+                let visible = uuids(self.visible_tracks().map(|(_, track)| track));
+                match self.selection.step(&visible, delta) {
+                    Some(pos) => self.track_list.reveal(pos),
+                    None => Task::none(),
+                }
+            }
+            Action::PlaySelected => {
+                let Some(cursor) = self.selection.cursor() else {
+                    return Task::none();
+                };
+
+                let i = self
+                    .visible_tracks()
+                    .find(|(_, track)| track.uuid == cursor)
+                    .map(|(i, _)| i);
+                match i {
+                    Some(i) => {
+                        self.update(Message::TrackMessage(i, cursor, TrackMessage::ChooseTrack))
+                    }
+                    None => Task::none(),
+                }
+            }
+            Action::SelectAll => {
+                self.update(Message::SelectionMessage(SelectionMessage::SelectAll))
+            }
+            Action::Help => self.update(Message::ToggleHelp),
+            Action::Cancel => {
+                if self
+                    .tag_editor
+                    .as_ref()
+                    .is_some_and(|editor| !editor.saving)
+                {
+                    self.tag_editor = None;
+                } else if self.organizer.as_ref().is_some_and(|o| !o.busy) {
+                    self.organizer = None;
+                } else if self.duplicates.as_ref().is_some_and(|d| !d.busy) {
+                    self.duplicates = None;
+                } else if self.show_settings {
+                    self.show_settings = false;
+                } else if self.show_backups && !self.backup_busy {
+                    self.show_backups = false;
+                } else if self.show_problems {
+                    self.show_problems = false;
+                } else if self.show_help {
+                    self.show_help = false;
+                } else {
+                    self.selection.clear();
+                }
+                Task::none()
+            }
+        }
+    }
+
+    fn seek(&mut self, pos: Duration) -> Task<Message> {
+        let Some(track) = self.core.current_track() else {
+            return Task::none();
+        };
+
+        self.current_pos = pos.min(track.duration);
+        self.send(Command::Seek(self.current_pos))
+            .chain(Task::done(Message::SaveSession))
+    }
+
+    fn set_volume(&mut self, volume: f32) -> Task<Message> {
+        self.volume = volume.clamp(0.0, 1.0);
+        self.send(Command::SetVolume(self.volume))
+    }
+
+    /// Adds track to the liked playlist or takes it out of it
+    fn toggle_liked(&self, uuid: Uuid) -> Task<Message> {
+        let Some(liked) = self
+            .playlists
+            .iter()
+            .find(|playlist| playlist.title == LIKED)
+        else {
+            return Task::none();
+        };
+
+        let store = self.store.clone();
+        let liked = liked.clone();
+        Task::perform(
+            async move {
+                let playlist_models = if liked.tracks.contains(&uuid) {
+                    store.delete_from_playlist(liked, uuid).await
+                } else {
+                    store.insert_into_playlist(liked, uuid).await
+                };
+                playlists(playlist_models)
+            },
+            Message::LoadPlaylist,
+        )
     }
+
+    /// Saves rating and shows it right away. 0 clears it
+    fn rate(&mut self, uuid: Uuid, stars: u8) -> Task<Message> {
+        let mut path = None;
+        self.for_each_copy(uuid, |track| {
+            track.rating = (stars > 0).then_some(stars.min(5));
+            path = Some(track.path.clone());
+        });
+        self.refresh_list();
+
+        let store = self.store.clone();
+        let write_tags = self.config.write_rating_tags;
+        Task::perform(
+            async move {
+                store
+                    .set_rating(uuid, stars)
+                    .await
+                    .map_err(|e| e.to_string())?;
+
+                match path {
+                    Some(path) if write_tags => tokio::task::spawn_blocking(move || {
+                        player::tags::write_rating(&path, stars)
+                    })
+                    .await
+                    .map_err(|e| e.to_string())?,
+                    _ => Ok(()),
+                }
+            },
+            Message::Err,
+        )
+    }
+
+    /// Same track is copied into library, list and queues. Keeps all of them in sync
+    fn for_each_copy(&mut self, uuid: Uuid, mut f: impl FnMut(&mut Track)) {
+        self.tracks
+            .iter_mut()
+            .chain(self.core.tracks_mut())
+            .filter(|track| track.uuid == uuid)
+            .for_each(&mut f);
+    }
+
+    /// Gives new gain to the engine and keeps it in config
+    fn save_gain(&self) -> Task<Message> {
+        Task::batch(vec![
+            self.send(Command::SetGain(self.config.replay_gain)),
+            Task::perform(self.config.clone().save(), Message::Err),
+        ])
+    }
+
+    /// Gives the engine what analysis measured so far
+    fn load_gains(&self) -> Task<Message> {
+        let store = self.store.clone();
+        Task::perform(
+            async move { store.loudness().await.map_err(|e| e.to_string()) },
+            Message::GainsLoaded,
+        )
+    }
+
+    /// Looks up output devices for settings, which may take a while
+    fn list_devices(&mut self) -> Task<Message> {
+        self.devices = None;
+        Task::perform(
+            async {
+                tokio::task::spawn_blocking(output::devices)
+                    .await
+                    .map_err(|e| e.to_string())?
+            },
+            Message::DevicesListed,
+        )
+    }
+
+    /// Runs command on queues and does what comes out of it with the engine
+    fn playback(&mut self, command: PlaybackCommand) -> Task<Message> {
+        let task = match self.core.update(command) {
+            Some(PlaybackEvent::Play(track)) => {
+                println!("Track played");
+                self.current_pos = Duration::default();
+                self.timer = DurationBar::Ticking {
+                    last_tick: Instant::now(),
+                };
+                self.send(Command::Play(track.path))
+            }
+            Some(PlaybackEvent::Stop) => {
+                self.current_pos = Duration::default();
+                self.timer = DurationBar::Idle;
+                self.send(Command::Stop)
+            }
+            Some(PlaybackEvent::Changed) => Task::none(),
+            None => return Task::none(),
+        };
+
+        task.chain(Task::done(Message::SaveSession))
+    }
+
+    fn send(&self, command: Command) -> Task<Message> {
+        let sender = self.sender.clone();
+        Task::perform(
+            async move {
+                let _ = sender.send(command).await;
+            },
+            |_| (),
+        )
+        .discard()
+    }
+
+    /// Key of column layout for the list that is shown
+    fn view_key(&self) -> String {
+        match &self.current_playlist {
+            Some(playlist) => format!("playlist:{}", playlist.uuid),
+            None => LIBRARY_VIEW.to_string(),
+        }
+    }
+
+    /// Selected tracks of current list in list order
+    fn selected_tracks(&self) -> Vec<Track> {
+        self.core
+            .init_queue()
+            .iter()
+            .filter(|track| self.selection.is_selected(&track.uuid))
+            .cloned()
+            .collect()
+    }
+
+    fn session(&self) -> SessionModel {
+        SessionModel {
+            position: self.current_pos,
+            current_playlist: self.current_playlist.as_ref().map(|p| p.uuid),
+            ..self.core.session()
+        }
+    }
+
+    /// Puts back saved queues and loads current track paused at saved position.
+    /// Tracks that are gone from library since last run are skipped
+    fn restore_session(&mut self, session: SessionModel) -> Task<Message> {
+        self.core.restore(&session, &self.tracks);
+        self.current_playlist = session.current_playlist.and_then(|uuid| {
+            self.playlists
+                .iter()
+                .find(|playlist| playlist.uuid == uuid)
+                .cloned()
+        });
+        self.columns = self.config.columns(&self.view_key());
+
+        let Some(track) = self.core.current_track() else {
+            return Task::none();
+        };
+
+        self.current_pos = session.position.min(track.duration);
+        self.timer = DurationBar::Paused;
+
+        let sender = self.sender.clone();
+        let command = Command::Load(track.path.clone(), self.current_pos);
+        Task::perform(
+            async move {
+                let _ = sender.send(command).await;
+            },
+            |_| (),
+        )
+        .discard()
+    }
+}
+
+fn liked_tracks(playlists: &[Playlist]) -> HashSet<Uuid> {
+    playlists
+        .iter()
+        .filter(|playlist| playlist.title == LIKED)
+        .flat_map(|playlist| playlist.tracks.iter().copied())
+        .collect()
+}
+
+/// Playlists returned by db, or why they couldn't be read
+/// Broken playlists were reported when library was loaded, here they're left out
+fn playlists(models: Result<Vec<PlaylistModel>, DbError>) -> Result<Vec<Playlist>, String> {
+    let (playlists, errors) = read_playlists(models.map_err(|e| e.to_string())?);
+    errors.iter().for_each(|err| println!("{err}"));
+    Ok(playlists)
+}
+
+/// Playlists that can be read, and why the others can't
+fn read_playlists(models: Vec<PlaylistModel>) -> (Vec<Playlist>, Vec<String>) {
+    let mut playlists = vec![];
+    let mut errors = vec![];
+    for model in models {
+        let title = model.title.clone();
+        match Playlist::try_from(model) {
+            Ok(playlist) => playlists.push(playlist),
+            Err(err) => errors.push(format!("Playlist {title} can't be read: {err}")),
+        }
+    }
+
+    (playlists, errors)
+}
+
+fn search_id() -> text_input::Id {
+    text_input::Id::new("search")
+}
+
+fn uuids<'a>(tracks: impl IntoIterator<Item = &'a Track>) -> Vec<Uuid> {
+    tracks.into_iter().map(|track| track.uuid).collect()
 }
 
 #[derive(Debug, Clone)]
 pub enum LoadError {
     File,
     Format,
+    Db(String), // Library couldn't be read from or saved into db
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::File => f.write_str("Unable to scan library folder"),
+            LoadError::Format => f.write_str("Library has unexpected format"),
+            LoadError::Db(err) => f.write_str(err),
+        }
+    }
+}
+
+impl From<DbError> for LoadError {
+    fn from(value: DbError) -> Self {
+        LoadError::Db(value.to_string())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SavedState {
     tracks: Vec<Track>,
     playlists: Vec<Playlist>,
+    session: Option<SessionModel>,
+    problems: Vec<Problem>,
+    broken_playlists: Vec<String>, // Why playlists that didn't load couldn't
+    backup_failed: Option<String>, // Why automatic backup couldn't be made
 }
 
 impl SavedState {
-    pub async fn load(pool: SqlitePool) -> Result<SavedState, LoadError> {
+    pub async fn load(store: impl LibraryStore) -> Result<SavedState, LoadError> {
         let mut tracks: Vec<Track> = vec![];
-        let mut paths = vec![];
-        Self::visit_dir(&mut paths, HOME_PATH.into());
-
-        db::init(&pool).await;
-        db::update_track_state(&pool, &paths).await;
-        let track_md_vec = db::get_tracks(&pool).await;
-        let playlists = db::get_playlists(&pool)
+        let scan = tokio::task::spawn_blocking(|| scan::scan(HOME_PATH.into()))
             .await
-            .into_iter()
-            .map(Playlist::from)
-            .collect();
+            .map_err(|_| LoadError::File)?;
+        let mut problems = scan.problems;
+
+        store.migrate().await?;
+        // Before scan, which deletes tracks whose files are gone
+        let backup_failed = match backup::auto_back_up(&store, &backup::dir()).await {
+            Ok(Some(path)) => {
+                println!("Backed up library to {path:?}");
+                None
+            }
+            Ok(None) => None,
+            Err(err) => Some(err.to_string()),
+        };
+        // Root that can't be read says nothing about which files are gone
+        if !scan.unread.iter().any(|dir| dir == Path::new(HOME_PATH)) {
+            store.sync_tracks(&scan.paths, &scan.unread).await?;
+        }
+        let track_md_vec = store.tracks().await?;
+        // One bad playlist or file shouldn't keep the rest of library from loading
+        let (playlists, broken_playlists) = read_playlists(store.playlists().await?);
 
         for track in track_md_vec {
-            let track_metadata = Probe::open(&track.path)
-                .map_err(|_| LoadError::File)?
-                .read()
-                .map_err(|_| LoadError::File)?;
-
-            let duration = track_metadata.properties().duration();
-            let duration_str = format!("{}:{}", duration.as_secs() / 60, duration.as_secs() % 60);
-
-            let uuid = Uuid::from_str(&track.uuid).unwrap();
-            let path = PathBuf::from_str(&track.path).unwrap();
-            let name = path.file_name().unwrap().to_str().unwrap().to_string();
-
-            tracks.push(Track {
-                uuid,
-                name,
-                duration_str,
-                duration,
-                path,
-                playlists: None,
-            });
+            let path = PathBuf::from(&track.path);
+            let uuid = Uuid::from_str(&track.uuid).unwrap_or_default();
+            match Track::try_from(track) {
+                Ok(track) => tracks.push(track),
+                Err(err) => problems.push(Problem::track(path, uuid, &err)),
+            }
         }
 
-        Ok(SavedState { tracks, playlists })
+        let session = store.session().await?;
+
+        Ok(SavedState {
+            tracks,
+            playlists,
+            session,
+            problems,
+            broken_playlists,
+            backup_failed,
+        })
     }
+}
 
-    fn visit_dir(paths: &mut Vec<PathBuf>, dir: PathBuf) {
-        println!("{:?}", dir);
-        if dir.is_dir() {
-            for entry in dir.read_dir().unwrap() {
-                let path = entry.unwrap().path();
-                if path.file_name().unwrap().to_str().unwrap().starts_with(".") {
-                    continue;
-                };
+/// Moves files off the ui thread, then saves new paths of the moved ones.
+/// With `undo` the moves are put back instead
+async fn move_files(
+    store: impl LibraryStore,
+    moves: Vec<Move>,
+    undo: bool,
+) -> (Vec<Move>, Option<String>) {
+    let root = PathBuf::from(HOME_PATH);
+    let (done, mut error) = tokio::task::spawn_blocking(move || match undo {
+        true => organizer::undo(&root, &moves),
+        false => organizer::apply(&root, &moves),
+    })
+    .await
+    .unwrap_or_else(|e| (vec![], Some(e.to_string())));
+
+    let paths: Vec<(Uuid, PathBuf)> = done
+        .iter()
+        .map(|moved| (moved.uuid, moved.to.clone()))
+        .collect();
+    if let Err(err) = store.set_track_paths(&paths).await {
+        error = Some(err.to_string());
+    }
+
+    (done, error)
+}
 
-                println!("{:?}", path);
-                if path.is_dir() {
-                    Self::visit_dir(paths, path);
-                } else if path.is_file() && path.extension() == Some(&OsString::from("mp3")) {
-                    paths.push(path);
+/// Groups copies in library. Fingerprints are only computed when asked for
+async fn find_duplicates(
+    store: impl LibraryStore,
+    tracks: Vec<Track>,
+    reasons: BTreeSet<Reason>,
+) -> Result<Vec<duplicates::Group>, String> {
+    let identities = store.identities().await.map_err(|e| e.to_string())?;
+
+    tokio::task::spawn_blocking(move || {
+        let mut fingerprints = HashMap::new();
+        if reasons.contains(&Reason::SoundsAlike) {
+            for track in &tracks {
+                match Fingerprint::compute(&track.path) {
+                    Ok(fingerprint) => {
+                        fingerprints.insert(track.uuid, fingerprint);
+                    }
+                    Err(err) => println!("Unable to fingerprint {:?}: {err}", track.path),
                 }
             }
         }
+
+        duplicates::find(&tracks, &reasons, &identities, &fingerprints)
+    })
+    .await
+    .map_err(|e| e.to_string())
+}
+
+/// Merges groups in db, then deletes files of merged copies if asked to.
+/// Returns merged tracks as stored after it, and tracks whose files were deleted
+async fn merge_duplicates(
+    store: impl LibraryStore,
+    merges: Vec<Merge>,
+    delete_files: bool,
+) -> Result<(Vec<TrackModel>, Vec<Uuid>), String> {
+    let mut merged = vec![];
+    for merge in &merges {
+        let others: Vec<Uuid> = merge.others.iter().map(|(uuid, _)| *uuid).collect();
+        store
+            .merge_tracks(merge.keep, &others)
+            .await
+            .map_err(|e| e.to_string())?;
+
+        // Read back, so the list shows what db made of the copies
+        for uuid in others.into_iter().chain([merge.keep]) {
+            if let Some(model) = store.track(uuid).await.map_err(|e| e.to_string())? {
+                merged.push(model);
+            }
+        }
+    }
+
+    if !delete_files {
+        return Ok((merged, vec![]));
     }
+
+    let files: Vec<(Uuid, PathBuf)> = merges
+        .iter()
+        .flat_map(|merge| merge.others.iter().cloned())
+        .collect();
+    let deleted: Vec<Uuid> = tokio::task::spawn_blocking(move || {
+        files
+            .into_iter()
+            .filter_map(|(uuid, path)| match std::fs::remove_file(&path) {
+                Ok(()) => Some(uuid),
+                Err(err) => {
+                    println!("Unable to delete {path:?}: {err}");
+                    None
+                }
+            })
+            .collect()
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    store
+        .delete_tracks(&deleted)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok((merged, deleted))
+}
+
+/// Writes user data of library as JSON at `path`
+async fn export_library(
+    store: impl LibraryStore,
+    tracks: Vec<Track>,
+    playlists: Vec<Playlist>,
+    path: PathBuf,
+) -> Result<String, String> {
+    let models = store.tracks().await.map_err(|e| e.to_string())?;
+    let identities = store.identities().await.map_err(|e| e.to_string())?;
+
+    let export = backup::export(
+        &PathBuf::from(HOME_PATH),
+        &tracks,
+        models,
+        &identities,
+        &playlists,
+    );
+    let json = serde_json::to_string_pretty(&export).map_err(|e| e.to_string())?;
+    tokio::fs::write(&path, json)
+        .await
+        .map_err(|e| format!("Unable to write {}: {e}", path.display()))?;
+
+    Ok(format!(
+        "Exported {} tracks and {} playlists",
+        export.tracks.len(),
+        export.playlists.len()
+    ))
+}
+
+/// Reads export at `path` and puts what matches this library into db
+async fn import_library(
+    store: impl LibraryStore,
+    tracks: Vec<Track>,
+    path: PathBuf,
+) -> Result<String, String> {
+    let json = tokio::fs::read_to_string(&path)
+        .await
+        .map_err(|e| format!("Unable to read {}: {e}", path.display()))?;
+    let export: backup::Export =
+        serde_json::from_str(&json).map_err(|e| format!("Not a library export: {e}"))?;
+    let identities = store.identities().await.map_err(|e| e.to_string())?;
+
+    let import = backup::plan_import(export, &PathBuf::from(HOME_PATH), &tracks, &identities)?;
+    store
+        .import_user_data(&import)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(format!(
+        "Imported {} tracks and {} playlists, {} tracks not found in library",
+        import.stats.len(),
+        import.playlists.len(),
+        import.unmatched
+    ))
 }
 
 async fn get_tracks_from_playlist(
     playlist_uuid: Uuid,
-    pool: SqlitePool,
+    store: impl LibraryStore,
 ) -> Result<Vec<Track>, String> {
     let mut res = vec![];
-    let tracks = db::get_tracks_from_playlist(&pool, playlist_uuid).await;
+    let tracks = store
+        .playlist_tracks(playlist_uuid)
+        .await
+        .map_err(|e| e.to_string())?;
     for track in tracks {
-        let track_metadata = Probe::open(&track.path)
-            .map_err(|_| LoadError::File)
-            .unwrap()
-            .read()
-            .map_err(|_| LoadError::File)
-            .unwrap();
-
-        let duration = track_metadata.properties().duration();
-        let duration_str = format!("{}:{}", duration.as_secs() / 60, duration.as_secs() % 60);
-
-        let uuid = Uuid::from_str(&track.uuid).unwrap();
-        let path = PathBuf::from_str(&track.path).unwrap();
-        let name = path.file_name().unwrap().to_str().unwrap().to_string();
-
-        res.push(Track {
-            uuid,
-            name,
-            duration_str,
-            duration,
-            path,
-            playlists: None,
-        });
+        // Unreadable files are listed in problems, the rest of playlist still plays
+        let path = track.path.clone();
+        match Track::try_from(track) {
+            Ok(track) => res.push(track),
+            Err(err) => println!("Unable to read {path}: {err}"),
+        }
     }
 
     Ok(res)
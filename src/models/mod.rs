@@ -1,2 +1,3 @@
-pub mod track_model;
 pub mod playlist_model;
+pub mod session_model;
+pub mod track_model;
@@ -0,0 +1,22 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::playback::RepeatMode;
+
+/// Playback state that is restored on startup. Stored as JSON in `session` table
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct SessionModel {
+    pub current_track: Option<Uuid>,
+    pub position: Duration,
+    pub init_queue: Vec<Uuid>,
+    pub queue: Vec<Uuid>,
+    pub prio_queue: Vec<Uuid>,
+    pub backward_queue: Vec<Uuid>,
+    pub current_playlist: Option<Uuid>,
+    #[serde(default)]
+    pub shuffle: bool,
+    #[serde(default)]
+    pub repeat: RepeatMode,
+}
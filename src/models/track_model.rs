@@ -1,9 +1,9 @@
-use uuid::Uuid;
-
-#[derive(sqlx::FromRow, Debug)]
+#[derive(sqlx::FromRow, Debug, Clone)]
 pub struct TrackModel {
     pub uuid: String,
     pub path: String, // into PathBuf
     pub play_count: i64,
     pub play_minutes: f64,
+    pub added_at: Option<i64>, // Unix time when track got into library
+    pub rating: Option<i64>,   // 1 to 5 stars, None when not rated
 }
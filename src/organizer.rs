@@ -0,0 +1,541 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+
+use iced::{
+    widget::{button, column, container, horizontal_space, row, scrollable, text, text_input},
+    Alignment, Element, Length, Task,
+};
+use uuid::Uuid;
+
+use crate::{
+    tags::{self, Field, TagValues},
+    theme,
+};
+
+pub const DEFAULT_PATTERN: &str = "{albumartist}/{year} - {album}/{disc}-{track} {title}.{ext}";
+// Rows listed in preview, the rest is only counted
+const PREVIEW_LIMIT: usize = 500;
+// Name of a folder or file when nothing is left of it
+const PLACEHOLDER: &str = "Unknown";
+
+/// File of a track going from one place to another
+#[derive(Debug, Clone, PartialEq)]
+pub struct Move {
+    pub uuid: Uuid,
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// Move found by dry run. Moves with conflict are not done
+#[derive(Debug, Clone)]
+pub struct Planned {
+    pub to_do: Move,
+    pub conflict: Option<String>,
+}
+
+/// Renames and moves files of tracks into folders built from their tags
+#[derive(Debug, Clone)]
+pub struct Organizer {
+    pub tracks: Vec<(Uuid, PathBuf)>,
+    pub pattern: String,
+    pub plan: Option<Vec<Planned>>, // Moves that change something, from last preview
+    pub busy: bool,
+    pub error: Option<String>,
+    root: PathBuf,
+}
+
+#[derive(Debug, Clone)]
+pub enum OrganizerMessage {
+    PatternChanged(String),
+    Preview,
+    Planned(Result<Vec<Planned>, String>),
+    Apply,
+    Undo,
+    Close,
+}
+
+impl Move {
+    fn reversed(&self) -> Move {
+        Move {
+            uuid: self.uuid,
+            from: self.to.clone(),
+            to: self.from.clone(),
+        }
+    }
+}
+
+impl Organizer {
+    /// Files end up under `root`, which should be the scanned library folder
+    /// so the next scan still finds them
+    pub fn new(root: PathBuf, pattern: String, tracks: Vec<(Uuid, PathBuf)>) -> Self {
+        let pattern = if pattern.trim().is_empty() {
+            DEFAULT_PATTERN.to_string()
+        } else {
+            pattern
+        };
+
+        Self {
+            tracks,
+            pattern,
+            plan: None,
+            busy: false,
+            error: None,
+            root,
+        }
+    }
+
+    /// Moves of last preview without conflicts
+    pub fn moves(&self) -> Vec<Move> {
+        self.plan
+            .iter()
+            .flatten()
+            .filter(|planned| planned.conflict.is_none())
+            .map(|planned| planned.to_do.clone())
+            .collect()
+    }
+
+    /// Apply, undo and close are up to the player
+    pub fn update(&mut self, message: OrganizerMessage) -> Task<OrganizerMessage> {
+        match message {
+            OrganizerMessage::PatternChanged(pattern) => {
+                self.pattern = pattern;
+                self.plan = None;
+                Task::none()
+            }
+            OrganizerMessage::Preview => {
+                self.busy = true;
+                self.error = None;
+
+                let (root, pattern, tracks) =
+                    (self.root.clone(), self.pattern.clone(), self.tracks.clone());
+                Task::perform(
+                    async move {
+                        tokio::task::spawn_blocking(move || plan(&root, &pattern, &tracks))
+                            .await
+                            .map_err(|e| e.to_string())?
+                    },
+                    OrganizerMessage::Planned,
+                )
+            }
+            OrganizerMessage::Planned(plan) => {
+                self.busy = false;
+                match plan {
+                    Ok(plan) => self.plan = Some(plan),
+                    Err(err) => self.error = Some(err),
+                }
+                Task::none()
+            }
+            OrganizerMessage::Apply | OrganizerMessage::Undo | OrganizerMessage::Close => {
+                Task::none()
+            }
+        }
+    }
+
+    /// `can_undo` is whether there is a done operation to put back
+    pub fn view(&self, can_undo: bool) -> Element<'_, OrganizerMessage> {
+        let idle = !self.busy;
+
+        let pattern = row![
+            text_input(DEFAULT_PATTERN, &self.pattern)
+                .on_input(OrganizerMessage::PatternChanged)
+                .on_submit(OrganizerMessage::Preview),
+            button("Preview").on_press_maybe(idle.then_some(OrganizerMessage::Preview)),
+        ]
+        .spacing(10)
+        .align_y(Alignment::Center);
+
+        let help = text(
+            "Fields: {title} {artist} {album} {albumartist} {year} {disc} {track} {genre} {ext}",
+        )
+        .size(12)
+        .style(theme::muted);
+
+        let mut content = column![
+            text(format!("Organize {} files", self.tracks.len())).size(20),
+            pattern,
+            help
+        ]
+        .spacing(10);
+
+        if let Some(plan) = &self.plan {
+            let conflicts = plan.iter().filter(|p| p.conflict.is_some()).count();
+            let summary = format!(
+                "{} of {} files will be moved, {} conflicts, {} already in place",
+                plan.len() - conflicts,
+                self.tracks.len(),
+                conflicts,
+                self.tracks.len() - plan.len(),
+            );
+
+            let rows = plan.iter().take(PREVIEW_LIMIT).map(|planned| {
+                let Move { from, to, .. } = &planned.to_do;
+                let line = column![
+                    text(self.relative(from).display().to_string()).style(theme::muted),
+                    text(format!("-> {}", self.relative(to).display())),
+                ];
+
+                match &planned.conflict {
+                    Some(conflict) => line.push(text(conflict).style(text::danger)),
+                    None => line,
+                }
+                .into()
+            });
+
+            let mut list = column(rows).spacing(8);
+            if plan.len() > PREVIEW_LIMIT {
+                list = list.push(text(format!("and {} more", plan.len() - PREVIEW_LIMIT)));
+            }
+
+            content = content
+                .push(text(summary))
+                .push(scrollable(list).height(300).width(Length::Fill));
+        }
+
+        if let Some(error) = &self.error {
+            content = content.push(text(error).style(text::danger));
+        }
+
+        let can_apply = idle && !self.moves().is_empty();
+        let footer = row![
+            button("Undo last")
+                .style(button::secondary)
+                .on_press_maybe((idle && can_undo).then_some(OrganizerMessage::Undo)),
+            horizontal_space(),
+            button("Close")
+                .style(button::secondary)
+                .on_press_maybe(idle.then_some(OrganizerMessage::Close)),
+            button(if self.busy {
+                "Working..."
+            } else {
+                "Move files"
+            })
+            .on_press_maybe(can_apply.then_some(OrganizerMessage::Apply)),
+        ]
+        .spacing(10);
+
+        container(content.push(footer))
+            .padding(15)
+            .width(700)
+            .style(container::rounded_box)
+            .into()
+    }
+
+    fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.root).unwrap_or(path)
+    }
+}
+
+/// Dry run. Reads tags of every track and works out where it would go.
+/// Tracks that are already in place are left out
+pub fn plan(
+    root: &Path,
+    pattern: &str,
+    tracks: &[(Uuid, PathBuf)],
+) -> Result<Vec<Planned>, String> {
+    check_pattern(pattern)?;
+
+    let mut plan = vec![];
+    for (uuid, from) in tracks {
+        let values = tags::read_tags(from).map_err(|e| format!("{from:?}: {e}"))?;
+        let to = root.join(render(pattern, &values, from)?);
+
+        if to != *from {
+            plan.push(Planned {
+                to_do: Move {
+                    uuid: *uuid,
+                    from: from.clone(),
+                    to,
+                },
+                conflict: None,
+            });
+        }
+    }
+
+    // Case is ignored, some file systems don't tell `a.mp3` from `A.mp3`
+    let mut targets: HashMap<String, usize> = HashMap::new();
+    for planned in &plan {
+        *targets.entry(key(&planned.to_do.to)).or_default() += 1;
+    }
+
+    for planned in &mut plan {
+        let Move { from, to, .. } = &planned.to_do;
+        planned.conflict = if targets[&key(to)] > 1 {
+            Some("Another file goes to the same place".to_string())
+        } else if to.exists() && key(to) != key(from) {
+            Some("A file already exists there".to_string())
+        } else {
+            None
+        };
+    }
+
+    Ok(plan)
+}
+
+/// Does the moves in order. Stops at the first failure and returns moves done
+/// before it, so they can still be saved and undone
+pub fn apply(root: &Path, moves: &[Move]) -> (Vec<Move>, Option<String>) {
+    let mut done = vec![];
+    for to_do in moves {
+        if let Err(err) = move_file(&to_do.from, &to_do.to) {
+            let error = format!("Unable to move {:?}: {err}", to_do.from);
+            return (done, Some(error));
+        }
+
+        remove_empty_dirs(root, &to_do.from);
+        done.push(to_do.clone());
+    }
+
+    (done, None)
+}
+
+/// Puts files of a done operation back where they were
+pub fn undo(root: &Path, done: &[Move]) -> (Vec<Move>, Option<String>) {
+    let moves: Vec<Move> = done.iter().rev().map(Move::reversed).collect();
+    apply(root, &moves)
+}
+
+/// Pattern is relative to the library folder and can't leave it
+fn check_pattern(pattern: &str) -> Result<(), String> {
+    let path = Path::new(pattern);
+    if pattern.trim().is_empty() {
+        return Err("Pattern is empty".to_string());
+    }
+    if path
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return Err("Pattern must be a relative path without `..`".to_string());
+    }
+
+    Ok(())
+}
+
+/// Fills the pattern with tags of the file. Missing text falls back to
+/// `Unknown ...`, missing disc to 1 and other numbers to 0. Values can't add folders
+fn render(pattern: &str, values: &TagValues, path: &Path) -> Result<PathBuf, String> {
+    let get = |field: Field| values.fields.get(&field).filter(|v| !v.trim().is_empty());
+    let number = |field: Field, width: usize, missing: u32| {
+        let n: u32 = get(field)
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(missing);
+        format!("{n:0width$}")
+    };
+
+    let mut res = String::new();
+    let mut rest = pattern;
+    while let Some(start) = rest.find('{') {
+        res.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or(format!("Unclosed {{ in {pattern:?}"))?;
+        let name = &rest[start + 1..start + end];
+
+        let value = match name {
+            "title" => get(Field::Title).cloned().unwrap_or_else(|| {
+                path.file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string())
+                    .unwrap_or_default()
+            }),
+            "artist" => get(Field::Artist)
+                .cloned()
+                .unwrap_or("Unknown Artist".to_string()),
+            "albumartist" => get(Field::AlbumArtist)
+                .or_else(|| get(Field::Artist))
+                .cloned()
+                .unwrap_or("Unknown Artist".to_string()),
+            "album" => get(Field::Album)
+                .cloned()
+                .unwrap_or("Unknown Album".to_string()),
+            "genre" => get(Field::Genre)
+                .cloned()
+                .unwrap_or("Unknown Genre".to_string()),
+            "year" => number(Field::Year, 4, 0),
+            "disc" => number(Field::Disc, 1, 1),
+            "track" => number(Field::Track, 2, 0),
+            "ext" => path
+                .extension()
+                .map(|ext| ext.to_string_lossy().to_lowercase())
+                .unwrap_or_default(),
+            other => return Err(format!("Unknown field {{{other}}}")),
+        };
+
+        let value = sanitize(&value);
+        res.push_str(match value.as_str() {
+            "" if name != "ext" => PLACEHOLDER,
+            value => value,
+        });
+        rest = &rest[start + end + 1..];
+    }
+    res.push_str(rest);
+
+    // Scan skips hidden files, a track moved to one would be dropped on rescan
+    Ok(res
+        .split('/')
+        .map(|part| match trim_name(part) {
+            "" => PLACEHOLDER,
+            part => part,
+        })
+        .collect())
+}
+
+/// Makes tag text safe as a part of file name
+fn sanitize(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect();
+
+    trim_name(&value).to_string()
+}
+
+/// Leading dots hide a file, trailing dots and spaces are dropped on Windows
+fn trim_name(name: &str) -> &str {
+    name.trim_matches(|c: char| c == '.' || c.is_whitespace())
+}
+
+fn key(path: &Path) -> String {
+    path.to_string_lossy().to_lowercase()
+}
+
+fn move_file(from: &Path, to: &Path) -> io::Result<()> {
+    // Rename replaces files silently, something may have appeared since preview
+    if to.exists() && key(to) != key(from) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{to:?} already exists"),
+        ));
+    }
+
+    if let Some(dir) = to.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // Rename doesn't work across file systems, copy then
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+
+    Ok(())
+}
+
+/// Removes folders left empty by a move, going up until one isn't empty.
+/// Library folder itself is kept
+fn remove_empty_dirs(root: &Path, moved: &Path) {
+    let mut dir = moved.parent();
+    while let Some(path) = dir.filter(|path| path.starts_with(root) && *path != root) {
+        // Fails on the first folder with something in it
+        if fs::remove_dir(path).is_err() {
+            break;
+        }
+        dir = path.parent();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::test_utils::{self, TempDir};
+
+    use super::*;
+
+    const PATTERN: &str = "{album}/{title}.{ext}";
+
+    /// Untagged track at `name` under `root`
+    fn track(root: &Path, name: &str) -> (Uuid, PathBuf) {
+        let path = root.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        test_utils::tone(&path, 440.0, Duration::from_millis(50), 0.5);
+        (Uuid::new_v4(), path)
+    }
+
+    fn values(fields: &[(Field, &str)]) -> TagValues {
+        TagValues {
+            fields: fields
+                .iter()
+                .map(|(field, value)| (*field, value.to_string()))
+                .collect(),
+            has_cover: false,
+        }
+    }
+
+    #[test]
+    fn plan_flags_conflicts() {
+        let dir = TempDir::new();
+        let root = &dir.0;
+        let tracks = [
+            track(root, "x/Song.wav"),
+            track(root, "y/song.wav"),
+            track(root, "z/taken.wav"),
+            track(root, "Unknown Album/kept.wav"),
+        ];
+        fs::write(root.join("Unknown Album/taken.wav"), "").unwrap();
+
+        let plan = plan(root, PATTERN, &tracks).unwrap();
+        let conflicts: Vec<_> = plan
+            .iter()
+            .map(|planned| planned.conflict.as_deref())
+            .collect();
+        // Track already in place is left out
+        assert_eq!(
+            conflicts,
+            [
+                Some("Another file goes to the same place"),
+                Some("Another file goes to the same place"),
+                Some("A file already exists there"),
+            ]
+        );
+    }
+
+    #[test]
+    fn undo_puts_files_back() {
+        let dir = TempDir::new();
+        let root = &dir.0;
+        let tracks = [track(root, "old/a.wav"), track(root, "old/deep/b.wav")];
+
+        let moves: Vec<Move> = plan(root, PATTERN, &tracks)
+            .unwrap()
+            .into_iter()
+            .map(|planned| planned.to_do)
+            .collect();
+        let (done, error) = apply(root, &moves);
+        assert_eq!((done.len(), error), (2, None));
+        assert!(root.join("Unknown Album/a.wav").exists());
+        assert!(root.join("Unknown Album/b.wav").exists());
+        assert!(!root.join("old").exists());
+
+        let (undone, error) = undo(root, &done);
+        assert_eq!((undone.len(), error), (2, None));
+        assert!(tracks.iter().all(|(_, path)| path.exists()));
+        assert!(!root.join("Unknown Album").exists());
+    }
+
+    #[test]
+    fn names_are_not_hidden_or_empty() {
+        let path = Path::new("/music/track.mp3");
+        let render = |fields: &[(Field, &str)]| {
+            render("{artist}/{album}/{title}.{ext}", &values(fields), path).unwrap()
+        };
+
+        assert_eq!(
+            render(&[(Field::Artist, ".hidden"), (Field::Title, "end. ")]),
+            PathBuf::from("hidden/Unknown Album/end.mp3")
+        );
+        assert_eq!(
+            render(&[(Field::Artist, "..."), (Field::Album, " . ")]),
+            PathBuf::from("Unknown/Unknown/track.mp3")
+        );
+        assert_eq!(
+            render(&[(Field::Album, "a/b"), (Field::Title, "..")]),
+            PathBuf::from("Unknown Artist/a_b/Unknown.mp3")
+        );
+    }
+}
@@ -0,0 +1,372 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rodio::{
+    cpal::{self, traits::HostTrait},
+    queue::SourcesQueueOutput,
+    source::UniformSourceIterator,
+    DeviceTrait, OutputStream, Sink, Source,
+};
+
+// Samples are taken in pieces this long
+const CHUNK: Duration = Duration::from_millis(10);
+// How often a thread looks whether there is something to play
+const IDLE: Duration = Duration::from_millis(5);
+// How often the header of a WAV file being written is brought up to date
+const WAV_HEADER_EVERY: Duration = Duration::from_secs(1);
+
+/// Where the engine's tracks are played to. The engine only works with the sink,
+/// outputs differ in what takes samples out of it
+pub trait Output {
+    fn sink(&self) -> &Sink;
+
+    /// What it plays to, for logs
+    fn name(&self) -> String;
+}
+
+/// Sound card through rodio and cpal
+pub struct DeviceOutput {
+    _stream: OutputStream, // Sound stops when it's dropped
+    sink: Sink,
+    name: String,
+}
+
+impl DeviceOutput {
+    /// Device with given name, default one with None
+    pub fn open(name: Option<&str>) -> Result<Self, String> {
+        let host = cpal::default_host();
+        let device = match name {
+            Some(name) => host
+                .output_devices()
+                .map_err(|e| e.to_string())?
+                .find(|device| device.name().is_ok_and(|n| n == name))
+                .ok_or_else(|| format!("{name} is not connected"))?,
+            None => host
+                .default_output_device()
+                .ok_or("No audio device found")?,
+        };
+
+        let (stream, handle) = OutputStream::try_from_device(&device).map_err(|e| e.to_string())?;
+        let sink = Sink::try_new(&handle).map_err(|e| e.to_string())?;
+        Ok(Self {
+            _stream: stream,
+            sink,
+            name: device.name().map_err(|e| e.to_string())?,
+        })
+    }
+}
+
+impl Output for DeviceOutput {
+    fn sink(&self) -> &Sink {
+        &self.sink
+    }
+
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+}
+
+/// Names of output devices, to pick one by
+pub fn devices() -> Result<Vec<String>, String> {
+    let devices = cpal::default_host()
+        .output_devices()
+        .map_err(|e| e.to_string())?;
+    Ok(devices.filter_map(|device| device.name().ok()).collect())
+}
+
+/// Plays to nowhere at `speed` times real time. For machines without sound
+/// card, and for tests that need to get to the end of a track fast
+pub struct NullOutput {
+    drain: Drain,
+    speed: f32,
+}
+
+impl NullOutput {
+    /// Speeds that `pause` doesn't take play at real time
+    pub fn new(speed: f32) -> Self {
+        let (speed, pause) = match pause(speed) {
+            Some(pause) => (speed, pause),
+            None => (1.0, CHUNK),
+        };
+        let drain = Drain::spawn(|samples| samples, move |_| Ok(()), Some(pause));
+        Self { drain, speed }
+    }
+}
+
+/// Time a chunk takes at `speed`, None unless speed is a positive number
+/// whose chunks take a time that can be waited for
+fn pause(speed: f32) -> Option<Duration> {
+    if !speed.is_finite() || speed <= 0.0 {
+        return None;
+    }
+    Duration::try_from_secs_f32(CHUNK.as_secs_f32() / speed).ok()
+}
+
+impl Output for NullOutput {
+    fn sink(&self) -> &Sink {
+        &self.drain.sink
+    }
+
+    fn name(&self) -> String {
+        format!("nothing ({}x)", self.speed)
+    }
+}
+
+/// Writes what is played to a 16-bit WAV file, as fast as it's decoded.
+/// Tracks are converted to the format of the file. Pauses are not written
+pub struct WavOutput {
+    drain: Drain,
+    path: PathBuf,
+}
+
+impl WavOutput {
+    pub fn create(path: &Path, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut wav = WavFile::create(path, channels, sample_rate)?;
+        let drain = Drain::spawn(
+            move |samples| UniformSourceIterator::new(samples, channels, sample_rate),
+            move |chunk| wav.write(chunk),
+            None,
+        );
+
+        Ok(Self {
+            drain,
+            path: path.to_path_buf(),
+        })
+    }
+}
+
+impl Output for WavOutput {
+    fn sink(&self) -> &Sink {
+        &self.drain.sink
+    }
+
+    fn name(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+}
+
+/// Output given by `spec`: `null`, `null:<speed>`, `wav:<path>` or nothing
+/// for the sound card, where `device` is tried first. Plays to nowhere when
+/// that can't be opened, so the player still works without sound
+pub fn open(spec: Option<&str>, device: Option<&str>) -> Box<dyn Output> {
+    let output: Result<Box<dyn Output>, String> = match spec {
+        Some("null") => Ok(Box::new(NullOutput::new(1.0))),
+        Some(spec) if spec.starts_with("null:") => spec["null:".len()..]
+            .parse()
+            .ok()
+            .filter(|speed| pause(*speed).is_some())
+            .map(|speed| Box::new(NullOutput::new(speed)) as Box<dyn Output>)
+            .ok_or_else(|| format!("Bad speed in {spec:?}")),
+        Some(spec) if spec.starts_with("wav:") => {
+            WavOutput::create(Path::new(&spec["wav:".len()..]), 2, 44_100)
+                .map(|output| Box::new(output) as Box<dyn Output>)
+                .map_err(|e| e.to_string())
+        }
+        _ => DeviceOutput::open(device)
+            .or_else(|err| match device {
+                Some(device) => {
+                    println!("Unable to open {device}: {err}. Playing to default device");
+                    DeviceOutput::open(None)
+                }
+                None => Err(err),
+            })
+            .map(|output| Box::new(output) as Box<dyn Output>),
+    };
+
+    output.unwrap_or_else(|err| {
+        println!("Unable to open audio output: {err}. Playing to nowhere");
+        Box::new(NullOutput::new(1.0))
+    })
+}
+
+/// Sink that a thread of ours takes samples out of, instead of a sound card
+struct Drain {
+    sink: Arc<Sink>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drain {
+    /// `consume` gets every chunk that is played. With `pause` the thread waits
+    /// that long after each chunk, without it chunks are taken as fast as they come
+    fn spawn<S>(
+        wrap: impl FnOnce(SourcesQueueOutput<f32>) -> S,
+        mut consume: impl FnMut(&[f32]) -> io::Result<()> + Send + 'static,
+        pause: Option<Duration>,
+    ) -> Self
+    where
+        S: Source<Item = f32> + Send + 'static,
+    {
+        let (sink, samples) = Sink::new_idle();
+        let sink = Arc::new(sink);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let mut samples = wrap(samples);
+        let thread = {
+            let sink = sink.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let mut chunk = vec![];
+                while !stop.load(Ordering::Relaxed) {
+                    // Queue plays silence when empty, that is not taken
+                    if sink.empty() {
+                        thread::sleep(IDLE);
+                        continue;
+                    }
+
+                    let len = samples.sample_rate() as usize * samples.channels() as usize
+                        / (1000 / CHUNK.as_millis() as usize);
+                    chunk.clear();
+                    // Silence after the end of last track is left out
+                    let samples = samples.by_ref().take(len.max(1));
+                    chunk.extend(samples.take_while(|_| !sink.empty()));
+
+                    // Paused sink still has to be run for stop and seek to get through
+                    if sink.is_paused() {
+                        thread::sleep(CHUNK);
+                        continue;
+                    }
+
+                    if let Err(err) = consume(&chunk) {
+                        println!("Audio output failed: {err}");
+                        break;
+                    }
+                    if let Some(pause) = pause {
+                        thread::sleep(pause);
+                    }
+                }
+            })
+        };
+
+        Self {
+            sink,
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Drain {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// WAV file whose header is brought up to date every `WAV_HEADER_EVERY` and
+/// when it's dropped, so it can be read while written
+struct WavFile {
+    file: BufWriter<File>,
+    channels: u16,
+    sample_rate: u32,
+    data_len: u32, // Bytes of samples
+    header_at: Instant,
+}
+
+impl WavFile {
+    fn create(path: &Path, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut wav = Self {
+            file: BufWriter::new(File::create(path)?),
+            channels,
+            sample_rate,
+            data_len: 0,
+            header_at: Instant::now(),
+        };
+        wav.write_header()?;
+        Ok(wav)
+    }
+
+    /// Fails once the file would be too long for the sizes in its header
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        // RIFF size is the data and 36 bytes of header after it
+        let data_len = u32::try_from(samples.len() * 2)
+            .ok()
+            .and_then(|len| self.data_len.checked_add(len))
+            .filter(|len| len.checked_add(36).is_some())
+            .ok_or_else(|| io::Error::other("WAV file is full"))?;
+
+        for sample in samples {
+            let sample = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_len = data_len;
+
+        if self.header_at.elapsed() >= WAV_HEADER_EVERY {
+            self.update_header()?;
+        }
+        Ok(())
+    }
+
+    fn update_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(0))?;
+        self.write_header()?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.header_at = Instant::now();
+        self.file.flush()
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = self.channels * 2;
+        let byte_rate = self.sample_rate * block_align as u32;
+
+        let file = &mut self.file;
+        file.write_all(b"RIFF")?;
+        file.write_all(&(36 + self.data_len).to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&self.channels.to_le_bytes())?;
+        file.write_all(&self.sample_rate.to_le_bytes())?;
+        file.write_all(&byte_rate.to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?; // Bits per sample
+        file.write_all(b"data")?;
+        file.write_all(&self.data_len.to_le_bytes())
+    }
+}
+
+impl Drop for WavFile {
+    fn drop(&mut self) {
+        if let Err(err) = self.update_header() {
+            println!("Unable to finish WAV file: {err}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn speed_must_be_a_positive_number() {
+        assert_eq!(pause(2.0), Some(CHUNK / 2));
+        for speed in [0.0, -1.0, f32::NAN, f32::INFINITY, f32::MIN_POSITIVE] {
+            assert_eq!(pause(speed), None, "{speed}");
+        }
+        assert_eq!(NullOutput::new(f32::NAN).speed, 1.0);
+        assert_eq!(open(Some("null:0"), None).name(), "nothing (1x)");
+    }
+
+    #[test]
+    fn full_wav_file_fails_instead_of_wrapping() {
+        let dir = TempDir::new();
+        let mut wav = WavFile::create(&dir.0.join("full.wav"), 2, 44_100).unwrap();
+        wav.data_len = u32::MAX - 36 - 4;
+        wav.write(&[0.0, 0.0]).unwrap();
+        assert!(wav.write(&[0.0]).is_err());
+        assert_eq!(wav.data_len, u32::MAX - 36);
+    }
+}
@@ -0,0 +1,749 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    models::session_model::SessionModel,
+    queue::{self, QueuePosition, QueueSection},
+    track::Track,
+};
+
+/// What happens when a track ends or the list runs out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum RepeatMode {
+    Off, // Stop after the last track
+    #[default]
+    All, // Start the list over
+    One, // Play the same track again when it ends, skipping still moves on
+}
+
+impl RepeatMode {
+    /// Mode the repeat button switches to
+    pub fn next(self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::All,
+            RepeatMode::All => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Off,
+        }
+    }
+}
+
+impl fmt::Display for RepeatMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RepeatMode::Off => "Repeat off",
+            RepeatMode::All => "Repeat all",
+            RepeatMode::One => "Repeat one",
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum PlaybackCommand {
+    PlayList(Vec<Track>, usize), // Makes it the list and plays it from given index
+    SetList(Vec<Track>),         // Other list is shown, what plays and is queued stays
+    PlayTracks(Vec<Track>),      // Plays first now and the rest after it
+    PlayFrom(QueueSection, usize),
+    Next,
+    Prev,
+    TrackEnded,
+    Enqueue(Vec<Track>, QueuePosition),
+    Remove(QueueSection, usize),
+    Move((QueueSection, usize), (QueueSection, usize)),
+    Clear(QueueSection),
+    SetShuffle(bool),
+    SetRepeat(RepeatMode),
+}
+
+/// What the frontend has to do after a command
+#[derive(Debug, Clone)]
+pub enum PlaybackEvent {
+    Play(Box<Track>), // Start the track from the beginning
+    Stop,             // Nothing left to play
+    Changed,          // Queues or modes changed, worth saving
+}
+
+/// Queues and playback order, without any GUI or audio.
+/// Frontends send commands and do what the returned event says
+#[derive(Debug, Clone)]
+pub struct PlayerCore {
+    init_queue: Vec<Track>, // All tracks OR tracks from playlist, the list that is shown
+    queue: VecDeque<Track>, // Rest of the list. This will pop tracks
+    prio_queue: VecDeque<Track>, // Tracks added by user, they go first
+
+    // To jump to prev tracks
+    // Its FILA so vec is perfect
+    backward_queue: Vec<Track>,
+
+    // Currently playing track. As we pop tracks from queue or
+    // prio_queue it will be here
+    current_track: Option<Track>,
+
+    shuffle: bool,
+    repeat: RepeatMode,
+    seed: u64, // State of the shuffle generator
+}
+
+impl Default for PlayerCore {
+    fn default() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_nanos() as u64)
+            .unwrap_or_default();
+
+        Self {
+            init_queue: vec![],
+            queue: VecDeque::new(),
+            prio_queue: VecDeque::new(),
+            backward_queue: vec![],
+            current_track: None,
+            shuffle: false,
+            repeat: RepeatMode::default(),
+            // Xorshift never leaves zero
+            seed: nanos | 1,
+        }
+    }
+}
+
+impl PlayerCore {
+    pub fn update(&mut self, command: PlaybackCommand) -> Option<PlaybackEvent> {
+        match command {
+            PlaybackCommand::PlayList(tracks, start) => self.play_list(tracks, start),
+            PlaybackCommand::SetList(tracks) => {
+                self.init_queue = tracks;
+                Some(PlaybackEvent::Changed)
+            }
+            PlaybackCommand::PlayTracks(tracks) => {
+                let mut tracks = VecDeque::from(tracks);
+                let first = tracks.pop_front()?;
+
+                self.backward_queue.extend(self.current_track.take());
+                self.queue = tracks;
+                if self.shuffle {
+                    self.shuffle_queue();
+                }
+                Some(self.play(first))
+            }
+            PlaybackCommand::PlayFrom(section, i) => {
+                let source = match section {
+                    QueueSection::Prio => &mut self.prio_queue,
+                    QueueSection::Upcoming => &mut self.queue,
+                };
+                if i >= source.len() {
+                    return None;
+                }
+
+                // Everything we skip over is treated as already played
+                let skipped: Vec<Track> = source.drain(..i).collect();
+                let track = source.pop_front()?;
+                self.backward_queue.extend(self.current_track.take());
+                self.backward_queue.extend(skipped);
+                Some(self.play(track))
+            }
+            PlaybackCommand::Next => self.advance(false),
+            PlaybackCommand::TrackEnded => self.advance(true),
+            PlaybackCommand::Prev => self.back(),
+            PlaybackCommand::Enqueue(tracks, position) => {
+                queue::enqueue(&mut self.prio_queue, tracks, position);
+                Some(PlaybackEvent::Changed)
+            }
+            PlaybackCommand::Remove(section, i) => {
+                match section {
+                    QueueSection::Prio => self.prio_queue.remove(i),
+                    QueueSection::Upcoming => self.queue.remove(i),
+                };
+                Some(PlaybackEvent::Changed)
+            }
+            PlaybackCommand::Move(from, to) => {
+                queue::move_entry(&mut self.prio_queue, &mut self.queue, from, to);
+                Some(PlaybackEvent::Changed)
+            }
+            PlaybackCommand::Clear(section) => {
+                match section {
+                    QueueSection::Prio => self.prio_queue.clear(),
+                    QueueSection::Upcoming => self.queue.clear(),
+                };
+                Some(PlaybackEvent::Changed)
+            }
+            PlaybackCommand::SetShuffle(shuffle) => {
+                if shuffle != self.shuffle {
+                    self.shuffle = shuffle;
+                    match shuffle {
+                        true => self.shuffle_queue(),
+                        false => self.unshuffle_queue(),
+                    }
+                }
+                Some(PlaybackEvent::Changed)
+            }
+            PlaybackCommand::SetRepeat(repeat) => {
+                self.repeat = repeat;
+                Some(PlaybackEvent::Changed)
+            }
+        }
+    }
+
+    pub fn init_queue(&self) -> &[Track] {
+        &self.init_queue
+    }
+
+    pub fn queue(&self) -> &VecDeque<Track> {
+        &self.queue
+    }
+
+    pub fn prio_queue(&self) -> &VecDeque<Track> {
+        &self.prio_queue
+    }
+
+    pub fn backward_queue(&self) -> &[Track] {
+        &self.backward_queue
+    }
+
+    pub fn current_track(&self) -> Option<&Track> {
+        self.current_track.as_ref()
+    }
+
+    pub fn shuffle(&self) -> bool {
+        self.shuffle
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    /// Rows of the list, for state that lives on tracks such as open menus
+    pub fn list_mut(&mut self) -> &mut Vec<Track> {
+        &mut self.init_queue
+    }
+
+    /// Every copy of tracks in the list and queues
+    pub fn tracks_mut(&mut self) -> impl Iterator<Item = &mut Track> {
+        self.init_queue
+            .iter_mut()
+            .chain(self.queue.iter_mut())
+            .chain(self.prio_queue.iter_mut())
+            .chain(self.backward_queue.iter_mut())
+            .chain(self.current_track.iter_mut())
+    }
+
+    /// Drops tracks from the list and all queues. Current track plays on
+    pub fn retain(&mut self, f: impl Fn(&Track) -> bool) {
+        self.retain_list(&f);
+        self.prio_queue.retain(&f);
+        self.backward_queue.retain(&f);
+    }
+
+    /// Drops tracks from the list and what is left of it to play,
+    /// tracks queued by user stay
+    pub fn retain_list(&mut self, f: impl Fn(&Track) -> bool) {
+        self.init_queue.retain(&f);
+        self.queue.retain(&f);
+    }
+
+    /// Queues and modes to be saved, the frontend fills in the rest
+    pub fn session(&self) -> SessionModel {
+        SessionModel {
+            current_track: self.current_track.as_ref().map(|track| track.uuid),
+            init_queue: uuids(&self.init_queue),
+            queue: uuids(&self.queue),
+            prio_queue: uuids(&self.prio_queue),
+            backward_queue: uuids(&self.backward_queue),
+            shuffle: self.shuffle,
+            repeat: self.repeat,
+            ..Default::default()
+        }
+    }
+
+    /// Puts back saved queues. Tracks that are gone from library since are skipped,
+    /// and the list is left as is when none of it is left
+    pub fn restore(&mut self, session: &SessionModel, library: &[Track]) {
+        let library: HashMap<Uuid, &Track> =
+            library.iter().map(|track| (track.uuid, track)).collect();
+        let resolve = |uuids: &[Uuid]| -> Vec<Track> {
+            uuids
+                .iter()
+                .filter_map(|uuid| library.get(uuid).map(|track| (*track).clone()))
+                .collect()
+        };
+
+        let init_queue = resolve(&session.init_queue);
+        if !init_queue.is_empty() {
+            self.init_queue = init_queue;
+        }
+        self.queue = resolve(&session.queue).into();
+        self.prio_queue = resolve(&session.prio_queue).into();
+        self.backward_queue = resolve(&session.backward_queue);
+        self.current_track = session
+            .current_track
+            .and_then(|uuid| library.get(&uuid).map(|track| (*track).clone()));
+        self.shuffle = session.shuffle;
+        self.repeat = session.repeat;
+    }
+
+    fn play(&mut self, track: Track) -> PlaybackEvent {
+        self.current_track = Some(track.clone());
+        PlaybackEvent::Play(Box::new(track))
+    }
+
+    fn play_list(&mut self, tracks: Vec<Track>, start: usize) -> Option<PlaybackEvent> {
+        self.init_queue = tracks;
+        let Some(track) = self.init_queue.get(start).cloned() else {
+            // Nothing to start with, what plays keeps playing
+            return Some(PlaybackEvent::Changed);
+        };
+
+        if self.shuffle {
+            // Played ones are only those played from now on
+            self.backward_queue = vec![];
+            self.queue = self.init_queue.clone().into();
+            self.queue.remove(start);
+            self.shuffle_queue();
+        } else {
+            self.backward_queue = self.init_queue[..start].to_vec();
+            self.queue = self.init_queue[start + 1..].iter().cloned().collect();
+        }
+        Some(self.play(track))
+    }
+
+    /// Moves on to the track after current one, `ended` when current one played to its end
+    fn advance(&mut self, ended: bool) -> Option<PlaybackEvent> {
+        if ended && self.repeat == RepeatMode::One {
+            if let Some(track) = self.current_track.clone() {
+                return Some(PlaybackEvent::Play(Box::new(track)));
+            }
+        }
+
+        let previous = self.current_track.take();
+        let was_playing = previous.is_some();
+        self.backward_queue.extend(previous);
+
+        let next = match self.prio_queue.pop_front() {
+            Some(track) => Some(track),
+            None => {
+                if self.queue.is_empty() && self.repeat != RepeatMode::Off {
+                    // New round, what was played before is forgotten
+                    self.backward_queue = vec![];
+                    self.queue = self.init_queue.clone().into();
+                    if self.shuffle {
+                        self.shuffle_queue();
+                    }
+                }
+                self.queue.pop_front()
+            }
+        };
+
+        match next {
+            Some(track) => Some(self.play(track)),
+            // Played tracks are kept, so prev still goes back to the last one
+            None if was_playing => Some(PlaybackEvent::Stop),
+            None => None,
+        }
+    }
+
+    fn back(&mut self) -> Option<PlaybackEvent> {
+        if let Some(track) = self.backward_queue.pop() {
+            if let Some(current) = self.current_track.take() {
+                self.queue.push_front(current);
+            }
+            return Some(self.play(track));
+        }
+
+        if self.repeat == RepeatMode::Off || self.init_queue.is_empty() {
+            // At the start, so current track starts over
+            let track = self.current_track.clone()?;
+            return Some(PlaybackEvent::Play(Box::new(track)));
+        }
+
+        // Wraps to the end of the list, the whole list counts as played
+        self.current_track = None;
+        self.queue = VecDeque::new();
+        self.backward_queue = self.init_queue.clone();
+        if self.shuffle {
+            let mut order = std::mem::take(&mut self.backward_queue);
+            self.shuffle_tracks(&mut order);
+            self.backward_queue = order;
+        }
+        let track = self.backward_queue.pop()?;
+        Some(self.play(track))
+    }
+
+    fn shuffle_queue(&mut self) {
+        let mut tracks = std::mem::take(&mut self.queue);
+        self.shuffle_tracks(tracks.make_contiguous());
+        self.queue = tracks;
+    }
+
+    /// Puts upcoming tracks back in list order, starting after the current track
+    fn unshuffle_queue(&mut self) {
+        let positions: HashMap<Uuid, usize> = self
+            .init_queue
+            .iter()
+            .enumerate()
+            .map(|(i, track)| (track.uuid, i))
+            .collect();
+        let current = self
+            .current_track
+            .as_ref()
+            .and_then(|track| positions.get(&track.uuid).copied());
+
+        // Tracks not in the list go last
+        let key = |track: &Track| match (positions.get(&track.uuid), current) {
+            (Some(&i), Some(current)) => (i <= current, i),
+            (Some(&i), None) => (false, i),
+            (None, _) => (true, usize::MAX),
+        };
+        self.queue.make_contiguous().sort_by_key(key);
+    }
+
+    /// Fisher-Yates with xorshift, good enough for play order
+    fn shuffle_tracks(&mut self, tracks: &mut [Track]) {
+        for i in (1..tracks.len()).rev() {
+            self.seed ^= self.seed << 13;
+            self.seed ^= self.seed >> 7;
+            self.seed ^= self.seed << 17;
+            let j = (self.seed % (i as u64 + 1)) as usize;
+            tracks.swap(i, j);
+        }
+    }
+}
+
+fn uuids<'a>(tracks: impl IntoIterator<Item = &'a Track>) -> Vec<Uuid> {
+    tracks.into_iter().map(|track| track.uuid).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils;
+
+    use super::*;
+
+    /// Track for every letter, named by it
+    fn tracks(names: &str) -> Vec<Track> {
+        names
+            .chars()
+            .map(|name| test_utils::track(&name.to_string()))
+            .collect()
+    }
+
+    fn names<'a>(tracks: impl IntoIterator<Item = &'a Track>) -> String {
+        tracks
+            .into_iter()
+            .map(|track| track.name.as_str())
+            .collect()
+    }
+
+    /// Played, current and upcoming tracks, as in `ab [c] de`
+    fn state(core: &PlayerCore) -> String {
+        let current = core.current_track().map_or("-", |track| &track.name);
+        format!(
+            "{} [{current}] {}",
+            names(core.backward_queue()),
+            names(core.queue())
+        )
+    }
+
+    fn played(event: Option<PlaybackEvent>) -> String {
+        match event {
+            Some(PlaybackEvent::Play(track)) => track.name,
+            other => panic!("Expected a track to play, got {other:?}"),
+        }
+    }
+
+    fn core(names: &str, start: usize, repeat: RepeatMode) -> PlayerCore {
+        let mut core = PlayerCore::default();
+        core.update(PlaybackCommand::SetRepeat(repeat));
+        played(core.update(PlaybackCommand::PlayList(tracks(names), start)));
+        core
+    }
+
+    #[test]
+    fn play_list_starts_at_given_track() {
+        let core = core("abcd", 2, RepeatMode::Off);
+        assert_eq!(state(&core), "ab [c] d");
+        assert_eq!(names(core.init_queue()), "abcd");
+    }
+
+    #[test]
+    fn play_list_without_start_track_keeps_playing() {
+        let mut core = core("abc", 1, RepeatMode::All);
+
+        let event = core.update(PlaybackCommand::PlayList(tracks("xy"), 5));
+        assert!(matches!(event, Some(PlaybackEvent::Changed)));
+        assert_eq!(state(&core), "a [b] c");
+        assert_eq!(names(core.init_queue()), "xy");
+
+        let event = core.update(PlaybackCommand::PlayList(vec![], 0));
+        assert!(matches!(event, Some(PlaybackEvent::Changed)));
+        assert_eq!(state(&core), "a [b] c");
+    }
+
+    #[test]
+    fn next_goes_through_list_in_order() {
+        let mut core = core("abc", 0, RepeatMode::Off);
+        assert_eq!(played(core.update(PlaybackCommand::Next)), "b");
+        assert_eq!(state(&core), "a [b] c");
+        assert_eq!(played(core.update(PlaybackCommand::TrackEnded)), "c");
+        assert_eq!(state(&core), "ab [c] ");
+    }
+
+    #[test]
+    fn end_of_list_stops_without_repeat() {
+        for command in [PlaybackCommand::Next, PlaybackCommand::TrackEnded] {
+            let mut core = core("abc", 2, RepeatMode::Off);
+
+            let event = core.update(command.clone());
+            assert!(matches!(event, Some(PlaybackEvent::Stop)));
+            assert_eq!(state(&core), "abc [-] ");
+
+            // Nothing more to stop
+            assert!(core.update(command).is_none());
+            assert_eq!(state(&core), "abc [-] ");
+        }
+    }
+
+    #[test]
+    fn prev_after_stop_plays_last_track() {
+        let mut core = core("abc", 2, RepeatMode::Off);
+        core.update(PlaybackCommand::Next);
+
+        assert_eq!(played(core.update(PlaybackCommand::Prev)), "c");
+        assert_eq!(state(&core), "ab [c] ");
+    }
+
+    #[test]
+    fn end_of_list_starts_over_with_repeat_all() {
+        for command in [PlaybackCommand::Next, PlaybackCommand::TrackEnded] {
+            let mut core = core("abc", 2, RepeatMode::All);
+
+            assert_eq!(played(core.update(command)), "a");
+            // New round forgets what was played
+            assert_eq!(state(&core), " [a] bc");
+        }
+    }
+
+    #[test]
+    fn repeat_one_replays_ended_track() {
+        let mut core = core("abc", 1, RepeatMode::One);
+
+        assert_eq!(played(core.update(PlaybackCommand::TrackEnded)), "b");
+        assert_eq!(state(&core), "a [b] c");
+        assert_eq!(played(core.update(PlaybackCommand::TrackEnded)), "b");
+        assert_eq!(state(&core), "a [b] c");
+    }
+
+    #[test]
+    fn repeat_one_still_skips() {
+        let mut core = core("abc", 1, RepeatMode::One);
+
+        assert_eq!(played(core.update(PlaybackCommand::Next)), "c");
+        // Skipping past the end starts over like repeat all
+        assert_eq!(played(core.update(PlaybackCommand::Next)), "a");
+        assert_eq!(state(&core), " [a] bc");
+    }
+
+    #[test]
+    fn prev_goes_back_and_next_comes_again() {
+        let mut core = core("abcd", 2, RepeatMode::Off);
+
+        assert_eq!(played(core.update(PlaybackCommand::Prev)), "b");
+        assert_eq!(state(&core), "a [b] cd");
+        assert_eq!(played(core.update(PlaybackCommand::Prev)), "a");
+        assert_eq!(state(&core), " [a] bcd");
+        assert_eq!(played(core.update(PlaybackCommand::Next)), "b");
+        assert_eq!(played(core.update(PlaybackCommand::Next)), "c");
+        assert_eq!(state(&core), "ab [c] d");
+    }
+
+    #[test]
+    fn prev_at_start_restarts_track_without_repeat() {
+        let mut core = core("abc", 0, RepeatMode::Off);
+
+        assert_eq!(played(core.update(PlaybackCommand::Prev)), "a");
+        assert_eq!(state(&core), " [a] bc");
+    }
+
+    #[test]
+    fn prev_at_start_wraps_to_end_with_repeat() {
+        for repeat in [RepeatMode::All, RepeatMode::One] {
+            let mut core = core("abc", 0, repeat);
+
+            assert_eq!(played(core.update(PlaybackCommand::Prev)), "c");
+            assert_eq!(state(&core), "ab [c] ");
+            assert_eq!(played(core.update(PlaybackCommand::Next)), "a");
+            assert_eq!(state(&core), " [a] bc");
+        }
+    }
+
+    #[test]
+    fn nothing_to_play() {
+        for repeat in [RepeatMode::Off, RepeatMode::All, RepeatMode::One] {
+            let mut core = PlayerCore::default();
+            core.update(PlaybackCommand::SetRepeat(repeat));
+
+            assert!(core.update(PlaybackCommand::Next).is_none());
+            assert!(core.update(PlaybackCommand::Prev).is_none());
+            assert!(core.update(PlaybackCommand::TrackEnded).is_none());
+            assert_eq!(state(&core), " [-] ");
+        }
+    }
+
+    #[test]
+    fn next_with_nothing_playing_starts_list() {
+        let mut core = PlayerCore::default();
+        core.update(PlaybackCommand::SetList(tracks("abc")));
+
+        assert_eq!(played(core.update(PlaybackCommand::Next)), "a");
+        assert_eq!(state(&core), " [a] bc");
+    }
+
+    #[test]
+    fn set_list_leaves_playback_alone() {
+        let mut core = core("abc", 1, RepeatMode::All);
+        core.update(PlaybackCommand::SetList(tracks("xyz")));
+        assert_eq!(state(&core), "a [b] c");
+
+        // Next round is of the new list
+        core.update(PlaybackCommand::Next);
+        assert_eq!(played(core.update(PlaybackCommand::Next)), "x");
+    }
+
+    #[test]
+    fn queued_tracks_go_first() {
+        let mut core = core("abc", 0, RepeatMode::Off);
+        core.update(PlaybackCommand::Enqueue(tracks("x"), QueuePosition::End));
+        core.update(PlaybackCommand::Enqueue(tracks("y"), QueuePosition::Next));
+        assert_eq!(names(core.prio_queue()), "yx");
+
+        assert_eq!(played(core.update(PlaybackCommand::Next)), "y");
+        assert_eq!(played(core.update(PlaybackCommand::Next)), "x");
+        assert_eq!(played(core.update(PlaybackCommand::Next)), "b");
+        assert_eq!(state(&core), "ayx [b] c");
+
+        assert_eq!(played(core.update(PlaybackCommand::Prev)), "x");
+        assert_eq!(state(&core), "ay [x] bc");
+    }
+
+    #[test]
+    fn queued_tracks_play_after_end_of_list() {
+        let mut core = core("ab", 1, RepeatMode::Off);
+        core.update(PlaybackCommand::Enqueue(tracks("x"), QueuePosition::End));
+
+        assert_eq!(played(core.update(PlaybackCommand::TrackEnded)), "x");
+        let event = core.update(PlaybackCommand::TrackEnded);
+        assert!(matches!(event, Some(PlaybackEvent::Stop)));
+    }
+
+    #[test]
+    fn play_from_counts_skipped_as_played() {
+        let mut core = core("abcde", 0, RepeatMode::Off);
+        core.update(PlaybackCommand::Enqueue(tracks("xy"), QueuePosition::End));
+
+        assert_eq!(
+            played(core.update(PlaybackCommand::PlayFrom(QueueSection::Upcoming, 2))),
+            "d"
+        );
+        assert_eq!(state(&core), "abc [d] e");
+        assert_eq!(names(core.prio_queue()), "xy");
+
+        assert_eq!(
+            played(core.update(PlaybackCommand::PlayFrom(QueueSection::Prio, 1))),
+            "y"
+        );
+        assert_eq!(state(&core), "abcdx [y] e");
+
+        let event = core.update(PlaybackCommand::PlayFrom(QueueSection::Prio, 0));
+        assert!(event.is_none());
+    }
+
+    #[test]
+    fn play_tracks_replaces_upcoming() {
+        let mut core = core("abc", 0, RepeatMode::Off);
+
+        assert_eq!(
+            played(core.update(PlaybackCommand::PlayTracks(tracks("xy")))),
+            "x"
+        );
+        assert_eq!(state(&core), "a [x] y");
+        assert!(core.update(PlaybackCommand::PlayTracks(vec![])).is_none());
+    }
+
+    #[test]
+    fn shuffle_plays_every_track_once() {
+        let mut core = PlayerCore::default();
+        core.update(PlaybackCommand::SetRepeat(RepeatMode::Off));
+        core.update(PlaybackCommand::SetShuffle(true));
+        let list = "abcdefghijklmnopqrst";
+
+        let mut order = played(core.update(PlaybackCommand::PlayList(tracks(list), 5)));
+        assert_eq!(order, "f");
+        assert!(core.backward_queue().is_empty());
+
+        while let Some(PlaybackEvent::Play(track)) = core.update(PlaybackCommand::TrackEnded) {
+            order.push_str(&track.name);
+        }
+        let mut sorted: Vec<char> = order.chars().collect();
+        sorted.sort();
+        assert_eq!(sorted.into_iter().collect::<String>(), list);
+    }
+
+    #[test]
+    fn shuffle_off_goes_on_in_list_order() {
+        let mut core = core("abcdefgh", 0, RepeatMode::Off);
+        core.update(PlaybackCommand::Enqueue(tracks("x"), QueuePosition::End));
+        core.update(PlaybackCommand::SetShuffle(true));
+        assert_eq!(names(core.prio_queue()), "x");
+
+        core.update(PlaybackCommand::PlayFrom(QueueSection::Upcoming, 3));
+        let current = core.current_track().unwrap().name.clone();
+        core.update(PlaybackCommand::SetShuffle(false));
+
+        // Tracks after the current one first, then what was skipped before it
+        let list = "abcdefgh";
+        let at = list.find(&current).unwrap();
+        let upcoming = names(core.queue());
+        let expected: String = list[at + 1..]
+            .chars()
+            .chain(list[..at].chars())
+            .filter(|name| upcoming.contains(*name))
+            .collect();
+        assert_eq!(upcoming, expected);
+    }
+
+    #[test]
+    fn retain_keeps_current_track() {
+        let mut core = core("abcd", 1, RepeatMode::Off);
+        core.update(PlaybackCommand::Enqueue(tracks("x"), QueuePosition::End));
+
+        core.retain(|track| track.name == "c");
+        assert_eq!(state(&core), " [b] c");
+        assert_eq!(names(core.init_queue()), "c");
+        assert!(core.prio_queue().is_empty());
+    }
+
+    #[test]
+    fn session_round_trip() {
+        let library = tracks("abcdx");
+        let mut core = PlayerCore::default();
+        core.update(PlaybackCommand::SetRepeat(RepeatMode::One));
+        core.update(PlaybackCommand::PlayList(library[..4].to_vec(), 1));
+        core.update(PlaybackCommand::Enqueue(
+            vec![library[4].clone()],
+            QueuePosition::End,
+        ));
+
+        let mut restored = PlayerCore::default();
+        restored.restore(&core.session(), &library);
+        assert_eq!(state(&restored), "a [b] cd");
+        assert_eq!(names(restored.init_queue()), "abcd");
+        assert_eq!(names(restored.prio_queue()), "x");
+        assert_eq!(restored.repeat(), RepeatMode::One);
+
+        // Tracks gone from library are skipped
+        restored.restore(&core.session(), &library[1..]);
+        assert_eq!(state(&restored), " [b] cd");
+    }
+}
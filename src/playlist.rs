@@ -1,13 +1,20 @@
 use std::str::FromStr;
 
 use iced::{
-    widget::{button, container},
-    Element, Task,
+    widget::{button, container, row},
+    Element, Length, Task,
 };
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::models::playlist_model::PlaylistModel;
+use crate::{
+    db::{self, DbError},
+    models::playlist_model::PlaylistModel,
+    queue::QueuePosition,
+};
+
+/// Playlist that always exists, filled by the like button
+pub const LIKED: &str = "Liked";
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Playlist {
@@ -16,15 +23,22 @@ pub struct Playlist {
     pub tracks: Vec<Uuid>,
 }
 
-impl From<PlaylistModel> for Playlist {
-    fn from(value: PlaylistModel) -> Self {
-        let uuid = Uuid::from_str(&value.uuid).unwrap();
-        let tracks: Vec<Uuid> = serde_json::from_str(value.tracks.as_str().unwrap()).map_err(|e| eprintln!("{e:?}")).unwrap();
-        Self {
+impl TryFrom<PlaylistModel> for Playlist {
+    type Error = DbError;
+
+    fn try_from(value: PlaylistModel) -> Result<Self, Self::Error> {
+        let uuid = Uuid::from_str(&value.uuid).map_err(|e| {
+            DbError::Corrupt(format!(
+                "playlist {:?} has uuid {:?}: {e}",
+                value.title, value.uuid
+            ))
+        })?;
+        let tracks = db::playlist_tracks(value.tracks)?;
+        Ok(Self {
             uuid,
             title: value.title,
             tracks,
-        }
+        })
     }
 }
 
@@ -34,6 +48,7 @@ pub enum PlaylistMessage {
     DiscardPlaylist,
     AddPlaylist,
     RemovePlaylist,
+    Enqueue(QueuePosition),
 }
 
 impl Playlist {
@@ -43,25 +58,28 @@ impl Playlist {
             PlaylistMessage::DiscardPlaylist => Task::none(),
             PlaylistMessage::AddPlaylist => Task::none(),
             PlaylistMessage::RemovePlaylist => Task::none(),
+            PlaylistMessage::Enqueue(_position) => Task::none(),
         }
     }
 
-    pub fn view(&self) -> Element<PlaylistMessage> {
-        let title = container(button(self.title.as_ref()).on_press(PlaylistMessage::SelectPlaylist));
-
-        return title.into();
+    /// Playlist that is shown in the track list is highlighted
+    pub fn view(&self, selected: bool) -> Element<'_, PlaylistMessage> {
+        let title = button(self.title.as_ref())
+            .on_press(PlaylistMessage::SelectPlaylist)
+            .style(if selected {
+                button::primary
+            } else {
+                button::text
+            })
+            .width(Length::Fill);
+
+        let play_next = button("Next")
+            .style(button::secondary)
+            .on_press(PlaylistMessage::Enqueue(QueuePosition::Next));
+        let add_to_queue = button("+")
+            .style(button::secondary)
+            .on_press(PlaylistMessage::Enqueue(QueuePosition::End));
+
+        container(row![title, play_next, add_to_queue].spacing(5)).into()
     }
 }
-
-
-
-
-
-
-
-
-
-
-
-
-
@@ -0,0 +1,272 @@
+use std::{collections::VecDeque, time::Duration};
+
+use iced::{
+    widget::{
+        button, column, container, horizontal_space, mouse_area, row, scrollable, text, text_input,
+    },
+    Element, Length,
+};
+
+use uuid::Uuid;
+
+use crate::{playlist::Playlist, track::Track, utils::format_duration};
+
+/// Part of the play queue an entry lives in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueSection {
+    Prio,     // Tracks added by user
+    Upcoming, // Rest of the list that is playing
+}
+
+/// Where tracks added by user go in `prio_queue`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePosition {
+    Next, // Play right after current track
+    End,  // Play after everything user already queued
+}
+
+#[derive(Debug, Clone)]
+pub enum QueueMessage {
+    Remove(QueueSection, usize),
+    MoveUp(QueueSection, usize),
+    MoveDown(QueueSection, usize),
+    Clear(QueueSection),
+    PlayFrom(QueueSection, usize),
+    DragStart(QueueSection, usize),
+    DragOver(QueueSection, usize),
+    Drop,
+    CancelDrag,
+    PlaylistNameChanged(String),
+    SaveAsPlaylist,
+    AppendToPlaylist(Playlist),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueDrag {
+    pub from: (QueueSection, usize),
+    pub over: Option<(QueueSection, usize)>,
+}
+
+/// Adds tracks to `prio_queue` keeping their order
+pub fn enqueue(prio_queue: &mut VecDeque<Track>, tracks: Vec<Track>, position: QueuePosition) {
+    match position {
+        QueuePosition::Next => {
+            for track in tracks.into_iter().rev() {
+                prio_queue.push_front(track);
+            }
+        }
+        QueuePosition::End => prio_queue.extend(tracks),
+    }
+}
+
+/// Tracks of the same album in disc and track number order
+pub fn album_tracks(tracks: &[Track], album: &str) -> Vec<Track> {
+    let mut res: Vec<Track> = tracks
+        .iter()
+        .filter(|track| track.album.as_deref() == Some(album))
+        .cloned()
+        .collect();
+
+    res.sort_by_key(|track| (track.disc_number, track.track_number));
+    res
+}
+
+/// Tracks of the same artist grouped by album
+pub fn artist_tracks(tracks: &[Track], artist: &str) -> Vec<Track> {
+    let mut res: Vec<Track> = tracks
+        .iter()
+        .filter(|track| track.artist.as_deref() == Some(artist))
+        .cloned()
+        .collect();
+
+    res.sort_by(|a, b| {
+        (&a.album, a.disc_number, a.track_number).cmp(&(&b.album, b.disc_number, b.track_number))
+    });
+    res
+}
+
+/// Moves entry `from` of one section to position `to` of another (or the same) section.
+/// Out of range indices are ignored, `to` is clamped to the end of target section.
+pub fn move_entry(
+    prio_queue: &mut VecDeque<Track>,
+    queue: &mut VecDeque<Track>,
+    from: (QueueSection, usize),
+    to: (QueueSection, usize),
+) {
+    let source = match from.0 {
+        QueueSection::Prio => &mut *prio_queue,
+        QueueSection::Upcoming => &mut *queue,
+    };
+
+    let Some(track) = source.remove(from.1) else {
+        return;
+    };
+
+    let target = match to.0 {
+        QueueSection::Prio => prio_queue,
+        QueueSection::Upcoming => queue,
+    };
+
+    let idx = to.1.min(target.len());
+    target.insert(idx, track);
+}
+
+/// Time left to play: rest of current track and everything queued after it
+pub fn remaining_time(
+    current: Option<&Track>,
+    current_pos: Duration,
+    prio_queue: &VecDeque<Track>,
+    queue: &VecDeque<Track>,
+) -> Duration {
+    let current = current
+        .map(|track| track.duration.saturating_sub(current_pos))
+        .unwrap_or_default();
+
+    prio_queue
+        .iter()
+        .chain(queue.iter())
+        .fold(current, |acc, track| acc + track.duration)
+}
+
+/// Everything that would play from now on, current track included
+pub fn queued_tracks(
+    current: Option<&Track>,
+    prio_queue: &VecDeque<Track>,
+    queue: &VecDeque<Track>,
+) -> Vec<Uuid> {
+    current
+        .into_iter()
+        .chain(prio_queue.iter())
+        .chain(queue.iter())
+        .map(|track| track.uuid)
+        .collect()
+}
+
+pub fn view<'a>(
+    current: Option<&'a Track>,
+    prio_queue: &'a VecDeque<Track>,
+    queue: &'a VecDeque<Track>,
+    remaining: Duration,
+    drag: Option<QueueDrag>,
+    playlists: &'a [Playlist],
+    playlist_name: &'a str,
+) -> Element<'a, QueueMessage> {
+    let now_playing = match current {
+        Some(track) => text(&track.name),
+        None => text("Nothing is playing"),
+    };
+
+    let header = row![
+        text("Queue").size(20),
+        horizontal_space(),
+        text(format!("{} left", format_duration(remaining))),
+    ];
+
+    let content = column![
+        header,
+        text("Now playing").size(14),
+        container(now_playing).padding([5, 10]),
+        section_header("Next up", QueueSection::Prio, prio_queue.is_empty()),
+        section(QueueSection::Prio, prio_queue, drag),
+        section_header("Next from list", QueueSection::Upcoming, queue.is_empty()),
+        section(QueueSection::Upcoming, queue, drag),
+        save_as_playlist(current.is_some(), playlists, playlist_name),
+    ]
+    .spacing(5);
+
+    mouse_area(scrollable(content).height(Length::Fill))
+        .on_release(QueueMessage::Drop)
+        .on_exit(QueueMessage::CancelDrag)
+        .into()
+}
+
+fn section_header(title: &str, section: QueueSection, empty: bool) -> Element<'_, QueueMessage> {
+    let clear = button(text("Clear").size(12))
+        .style(button::text)
+        .on_press_maybe((!empty).then_some(QueueMessage::Clear(section)));
+
+    row![text(title).size(14), horizontal_space(), clear]
+        .padding([5, 0])
+        .into()
+}
+
+fn section(
+    section: QueueSection,
+    tracks: &VecDeque<Track>,
+    drag: Option<QueueDrag>,
+) -> Element<'_, QueueMessage> {
+    if tracks.is_empty() {
+        let placeholder = container(text("Empty").size(14)).padding([5, 10]);
+        return mouse_area(placeholder)
+            .on_enter(QueueMessage::DragOver(section, 0))
+            .into();
+    }
+
+    let last = tracks.len() - 1;
+
+    let rows = tracks.iter().enumerate().map(|(i, track)| {
+        let handle = mouse_area(container(text("=")).padding([0, 5]))
+            .on_press(QueueMessage::DragStart(section, i))
+            .interaction(iced::mouse::Interaction::Grab);
+
+        let name = button(text(&track.name))
+            .style(button::text)
+            .on_press(QueueMessage::PlayFrom(section, i))
+            .width(Length::Fill);
+
+        let up = button("^").on_press_maybe((i > 0).then_some(QueueMessage::MoveUp(section, i)));
+        let down =
+            button("v").on_press_maybe((i < last).then_some(QueueMessage::MoveDown(section, i)));
+        let remove = button("x").on_press(QueueMessage::Remove(section, i));
+
+        let entry = row![handle, name, text(&track.duration_str), up, down, remove]
+            .spacing(5)
+            .align_y(iced::Alignment::Center);
+
+        let is_target = drag.and_then(|drag| drag.over) == Some((section, i));
+        let entry = container(entry).width(Length::Fill);
+        let entry = if is_target {
+            entry.style(container::bordered_box)
+        } else {
+            entry
+        };
+
+        mouse_area(entry)
+            .on_enter(QueueMessage::DragOver(section, i))
+            .into()
+    });
+
+    column(rows).spacing(2).into()
+}
+
+fn save_as_playlist<'a>(
+    playing: bool,
+    playlists: &'a [Playlist],
+    playlist_name: &'a str,
+) -> Element<'a, QueueMessage> {
+    let can_save = playing && !playlist_name.trim().is_empty();
+
+    let save = row![
+        text_input("Save queue as playlist...", playlist_name)
+            .on_input(QueueMessage::PlaylistNameChanged)
+            .on_submit_maybe(can_save.then_some(QueueMessage::SaveAsPlaylist)),
+        button("Save").on_press_maybe(can_save.then_some(QueueMessage::SaveAsPlaylist)),
+    ]
+    .spacing(5);
+
+    let append = playlists.iter().map(|playlist| {
+        button(text(&playlist.title))
+            .style(button::text)
+            .on_press_maybe(playing.then(|| QueueMessage::AppendToPlaylist(playlist.clone())))
+            .into()
+    });
+
+    column![
+        save,
+        text("Append queue to playlist").size(14),
+        column(append)
+    ]
+    .spacing(5)
+    .padding([10, 0])
+    .into()
+}
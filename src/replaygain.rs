@@ -0,0 +1,208 @@
+use std::{fmt, path::Path};
+
+use lofty::{
+    file::{TaggedFile, TaggedFileExt},
+    probe::Probe,
+    tag::{ItemKey, Tag},
+};
+use serde::{Deserialize, Serialize};
+
+// R128 tags are relative to -23 LUFS, ReplayGain 2 to -18 LUFS
+const R128_TO_REPLAYGAIN: f32 = 5.0;
+
+/// Preamp goes this many dB up or down
+pub const MAX_PREAMP: f32 = 15.0;
+
+/// Which of the stored gains is used
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GainMode {
+    Off,
+    #[default]
+    Track, // Every track at the same loudness
+    Album, // Albums at the same loudness, tracks keep their levels within it
+}
+
+impl GainMode {
+    pub const ALL: [GainMode; 3] = [GainMode::Off, GainMode::Track, GainMode::Album];
+}
+
+impl fmt::Display for GainMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            GainMode::Off => "Off",
+            GainMode::Track => "Track",
+            GainMode::Album => "Album",
+        })
+    }
+}
+
+/// How tracks are leveled, kept in config
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GainSettings {
+    pub mode: GainMode,
+    pub preamp: f32, // dB added to the gain of tagged tracks
+}
+
+/// Gains in dB and peaks as sample values, from the tags of a file
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    /// ReplayGain tags of any tag in the file, R128 ones when there are none.
+    /// Files that can't be read have no gain
+    pub fn read(path: &Path) -> ReplayGain {
+        let Ok(file) = Probe::open(path).and_then(|probe| probe.read()) else {
+            return ReplayGain::default();
+        };
+        ReplayGain::from_file(&file)
+    }
+
+    /// Same as `read`, for a file that was already read
+    pub fn from_file(file: &TaggedFile) -> ReplayGain {
+        file.tags()
+            .iter()
+            .map(ReplayGain::from_tag)
+            .find(ReplayGain::has_gain)
+            .unwrap_or_default()
+    }
+
+    pub fn has_gain(&self) -> bool {
+        self.track_gain.is_some() || self.album_gain.is_some()
+    }
+
+    fn from_tag(tag: &Tag) -> ReplayGain {
+        let value = |key: ItemKey| tag.get_string(&key).and_then(parse_number);
+        let r128 = |key: &str| {
+            tag.get_string(&ItemKey::Unknown(key.to_string()))
+                .and_then(parse_r128)
+        };
+
+        ReplayGain {
+            track_gain: value(ItemKey::ReplayGainTrackGain).or_else(|| r128("R128_TRACK_GAIN")),
+            track_peak: value(ItemKey::ReplayGainTrackPeak),
+            album_gain: value(ItemKey::ReplayGainAlbumGain).or_else(|| r128("R128_ALBUM_GAIN")),
+            album_peak: value(ItemKey::ReplayGainAlbumPeak),
+        }
+    }
+
+    /// What samples are multiplied by. Album mode falls back to track gain and
+    /// the other way round. Gain is lowered so the peak doesn't clip
+    pub fn factor(&self, settings: GainSettings) -> f32 {
+        let track = self.track_gain.map(|gain| (gain, self.track_peak));
+        let album = self.album_gain.map(|gain| (gain, self.album_peak));
+        let picked = match settings.mode {
+            GainMode::Off => None,
+            GainMode::Track => track.or(album),
+            GainMode::Album => album.or(track),
+        };
+        let Some((gain, peak)) = picked else {
+            return 1.0;
+        };
+
+        let factor = 10f32.powf((gain + settings.preamp) / 20.0);
+        match peak {
+            Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+}
+
+/// Number at the start of values like "-6.54 dB" or "0.988547"
+fn parse_number(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let value = value
+        .strip_suffix("dB")
+        .or_else(|| value.strip_suffix("db"))
+        .unwrap_or(value);
+    value.trim().parse().ok().filter(|n: &f32| n.is_finite())
+}
+
+/// R128 gains are whole numbers of 1/256 dB
+fn parse_r128(value: &str) -> Option<f32> {
+    let value: i16 = value.trim().parse().ok()?;
+    Some(value as f32 / 256.0 + R128_TO_REPLAYGAIN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(mode: GainMode, preamp: f32) -> GainSettings {
+        GainSettings { mode, preamp }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn values_are_parsed_with_and_without_unit() {
+        assert_eq!(parse_number("-6.54 dB"), Some(-6.54));
+        assert_eq!(parse_number("+2.00 dB"), Some(2.0));
+        assert_eq!(parse_number(" 0.988547 "), Some(0.988547));
+        assert_eq!(parse_number("loud"), None);
+        assert_eq!(parse_number("NaN"), None);
+
+        // -2304/256 = -9 dB to -23 LUFS
+        assert_eq!(parse_r128("-2304"), Some(-4.0));
+        assert_eq!(parse_r128("-4.5"), None);
+    }
+
+    #[test]
+    fn mode_picks_gain_and_falls_back() {
+        let gain = ReplayGain {
+            track_gain: Some(-6.0),
+            album_gain: Some(-12.0),
+            ..Default::default()
+        };
+        assert_eq!(gain.factor(settings(GainMode::Off, 0.0)), 1.0);
+        assert!(close(
+            gain.factor(settings(GainMode::Track, 0.0)),
+            10f32.powf(-0.3)
+        ));
+        assert!(close(
+            gain.factor(settings(GainMode::Album, 0.0)),
+            10f32.powf(-0.6)
+        ));
+
+        let track_only = ReplayGain {
+            track_gain: Some(-6.0),
+            ..Default::default()
+        };
+        assert_eq!(
+            track_only.factor(settings(GainMode::Album, 0.0)),
+            track_only.factor(settings(GainMode::Track, 0.0))
+        );
+    }
+
+    #[test]
+    fn untagged_tracks_play_unchanged() {
+        let gain = ReplayGain::default();
+        assert_eq!(gain.factor(settings(GainMode::Track, 6.0)), 1.0);
+    }
+
+    #[test]
+    fn preamp_is_added_and_peak_prevents_clipping() {
+        let gain = ReplayGain {
+            track_gain: Some(-6.0),
+            track_peak: Some(0.8),
+            ..Default::default()
+        };
+        assert!(close(gain.factor(settings(GainMode::Track, 6.0)), 1.0));
+        // +6 dB would take the peak of 0.8 over full scale
+        assert!(close(gain.factor(settings(GainMode::Track, 12.0)), 1.25));
+    }
+
+    #[test]
+    fn missing_files_have_no_gain() {
+        let gain = ReplayGain::read(Path::new("/nowhere/track.mp3"));
+        assert_eq!(gain, ReplayGain::default());
+    }
+}
@@ -0,0 +1,253 @@
+use std::{collections::HashSet, fmt, path::PathBuf};
+
+use iced::{
+    widget::{button, column, container, horizontal_space, row, scrollable, text, Column},
+    Alignment, Element, Length,
+};
+use lofty::error::ErrorKind;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{theme, track::TrackError};
+
+/// Formats the engine can decode
+pub const SUPPORTED: [&str; 4] = ["mp3", "flac", "ogg", "wav"];
+// Other audio files, reported as unsupported instead of being skipped silently
+const OTHER_AUDIO: [&str; 10] = [
+    "m4a", "aac", "opus", "wma", "aiff", "aif", "ape", "wv", "alac", "mka",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ProblemKind {
+    Unreadable,  // No access, or gone while scanning
+    Corrupt,     // Read but not understood
+    Unsupported, // Audio the player can't play
+}
+
+/// File or folder that didn't get into library, and why
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Problem {
+    pub path: PathBuf,
+    pub uuid: Option<Uuid>, // Track of the file if it's in db, to retry just that one
+    pub kind: ProblemKind,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum ProblemsMessage {
+    Retry(usize),
+    RetryAll,
+    Close,
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ProblemKind::Unreadable => "Unreadable",
+            ProblemKind::Corrupt => "Corrupt",
+            ProblemKind::Unsupported => "Unsupported",
+        })
+    }
+}
+
+impl Problem {
+    fn new(path: PathBuf, kind: ProblemKind, reason: impl ToString) -> Self {
+        Self {
+            path,
+            uuid: None,
+            kind,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Track that is in db but whose file couldn't be read
+    pub fn track(path: PathBuf, uuid: Uuid, err: &TrackError) -> Self {
+        let kind = match err {
+            TrackError::File(err) => match err.kind() {
+                ErrorKind::Io(_) => ProblemKind::Unreadable,
+                ErrorKind::UnknownFormat => ProblemKind::Unsupported,
+                _ => ProblemKind::Corrupt,
+            },
+            TrackError::Db(_) => ProblemKind::Corrupt,
+        };
+
+        Self {
+            uuid: Some(uuid),
+            ..Self::new(path, kind, err)
+        }
+    }
+}
+
+/// What a scan found
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub paths: Vec<PathBuf>,
+    pub problems: Vec<Problem>,
+    pub unread: Vec<PathBuf>, // Folders and files that couldn't be looked into
+}
+
+/// Finds playable files under `dir`. Hidden files are skipped, everything
+/// else that can't be taken is reported instead of stopping the scan.
+/// Symlinks are followed, folders reached more than once are read once
+pub fn scan(dir: PathBuf) -> Scan {
+    let mut scan = Scan::default();
+    let mut visited = HashSet::new();
+    visit_dir(&mut scan, &mut visited, dir);
+
+    scan
+}
+
+impl Scan {
+    /// Unlike files that are gone, ones under these may still be there
+    fn unreadable(&mut self, path: PathBuf, err: impl ToString) {
+        self.problems
+            .push(Problem::new(path.clone(), ProblemKind::Unreadable, err));
+        self.unread.push(path);
+    }
+}
+
+fn visit_dir(scan: &mut Scan, visited: &mut HashSet<PathBuf>, dir: PathBuf) {
+    // A link to a folder above would loop forever
+    match dir.canonicalize() {
+        Ok(real) => {
+            if !visited.insert(real) {
+                return;
+            }
+        }
+        Err(err) => return scan.unreadable(dir, err),
+    }
+
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(err) => return scan.unreadable(dir, err),
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                scan.unreadable(dir.clone(), err);
+                continue;
+            }
+        };
+
+        let path = entry.path();
+        if entry.file_name().as_encoded_bytes().starts_with(b".") {
+            continue;
+        }
+
+        // Follows symlinks, unlike the entry's own file type
+        let metadata = match path.metadata() {
+            Ok(metadata) => metadata,
+            Err(err) => {
+                scan.unreadable(path, err);
+                continue;
+            }
+        };
+
+        if metadata.is_dir() {
+            visit_dir(scan, visited, path);
+            continue;
+        }
+
+        let extension = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        if SUPPORTED.contains(&extension.as_str()) {
+            // Paths are kept as text in db
+            if path.to_str().is_some() {
+                scan.paths.push(path);
+            } else {
+                let reason = "File name is not valid UTF-8";
+                scan.problems
+                    .push(Problem::new(path, ProblemKind::Unreadable, reason));
+            }
+        } else if OTHER_AUDIO.contains(&extension.as_str()) {
+            let reason = format!("{} files can't be played", extension.to_uppercase());
+            scan.problems
+                .push(Problem::new(path, ProblemKind::Unsupported, reason));
+        }
+    }
+}
+
+/// List of problems from last scan
+pub fn view<'a>(problems: &'a [Problem], busy: bool) -> Element<'a, ProblemsMessage> {
+    let rows = problems.iter().enumerate().map(|(i, problem)| {
+        // Folders and names that can't be stored are only found again by a new scan
+        let retry = problem.uuid.map(|_| ProblemsMessage::Retry(i));
+
+        row![
+            column![
+                text(problem.path.display().to_string()),
+                text(format!("{}: {}", problem.kind, problem.reason))
+                    .size(12)
+                    .style(theme::muted),
+            ]
+            .width(Length::Fill),
+            button("Retry")
+                .style(button::secondary)
+                .on_press_maybe(retry.filter(|_| !busy)),
+        ]
+        .spacing(10)
+        .align_y(Alignment::Center)
+        .into()
+    });
+
+    let header = row![
+        text(format!("Problems ({})", problems.len())).size(20),
+        horizontal_space(),
+        button("x").on_press(ProblemsMessage::Close)
+    ];
+
+    let footer = row![
+        text("Rescan finds new and fixed files everywhere in library")
+            .size(12)
+            .style(theme::muted),
+        horizontal_space(),
+        button(if busy { "Scanning..." } else { "Rescan" })
+            .on_press_maybe((!busy).then_some(ProblemsMessage::RetryAll)),
+    ]
+    .align_y(Alignment::Center);
+
+    let content = column![
+        header,
+        scrollable(Column::with_children(rows).spacing(8)).height(400),
+        footer
+    ]
+    .spacing(10);
+
+    container(content)
+        .padding(15)
+        .width(700)
+        .style(container::rounded_box)
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    #[cfg(unix)]
+    use std::os::unix::fs::symlink;
+
+    use crate::test_utils::TempDir;
+
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn linked_folders_are_read_once() {
+        let dir = TempDir::new();
+        let album = dir.0.join("album");
+        fs::create_dir(&album).unwrap();
+        fs::write(album.join("a.mp3"), b"").unwrap();
+        // Link back up, and a second way into the same folder
+        symlink(&dir.0, album.join("loop")).unwrap();
+        symlink(&album, dir.0.join("same album")).unwrap();
+
+        let scan = scan(dir.0.clone());
+        assert_eq!(scan.paths.len(), 1);
+        assert!(scan.problems.is_empty(), "{:?}", scan.problems);
+    }
+}
@@ -0,0 +1,155 @@
+use std::collections::HashSet;
+
+use iced::{
+    keyboard::Modifiers,
+    widget::{button, column, container, horizontal_space, row, text, Column},
+    Element, Length,
+};
+use uuid::Uuid;
+
+use crate::{playlist::Playlist, queue::QueuePosition};
+
+/// Selected rows of the track list. Kept by uuid so it survives
+/// filtering and sorting of the list
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    pub tracks: HashSet<Uuid>,
+    pub show_playlists: bool,
+    anchor: Option<Uuid>, // Last clicked track, start of shift-click range
+}
+
+#[derive(Debug, Clone)]
+pub enum SelectionMessage {
+    SelectAll,
+    Clear,
+    Play,
+    Enqueue(QueuePosition),
+    TogglePlaylistMenu,
+    AddToPlaylist(Playlist),
+    RemoveFromPlaylist,
+    Reveal,
+    EditTags,
+}
+
+impl Selection {
+    pub fn is_selected(&self, uuid: &Uuid) -> bool {
+        self.tracks.contains(uuid)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    /// Click on a row. Plain click selects only that row, ctrl toggles it and
+    /// shift selects everything between it and the last clicked row.
+    /// `visible` are the rows in the order they are shown
+    pub fn click(&mut self, uuid: Uuid, modifiers: Modifiers, visible: &[Uuid]) {
+        if modifiers.shift() {
+            let anchor = self
+                .anchor
+                .and_then(|anchor| visible.iter().position(|u| *u == anchor));
+            let clicked = visible.iter().position(|u| *u == uuid);
+
+            if let (Some(anchor), Some(clicked)) = (anchor, clicked) {
+                if !modifiers.command() {
+                    self.tracks.clear();
+                }
+
+                let range = anchor.min(clicked)..=anchor.max(clicked);
+                self.tracks.extend(visible[range].iter().copied());
+                return;
+            }
+        }
+
+        if modifiers.command() {
+            if !self.tracks.remove(&uuid) {
+                self.tracks.insert(uuid);
+            }
+        } else {
+            self.tracks.clear();
+            self.tracks.insert(uuid);
+        }
+
+        self.anchor = Some(uuid);
+    }
+
+    /// Last clicked or stepped to row, the one keyboard acts on
+    pub fn cursor(&self) -> Option<Uuid> {
+        self.anchor
+    }
+
+    /// Moves cursor by `delta` rows and selects only that row. Starts at the first
+    /// row when there is no cursor. Returns position of the new cursor in `visible`
+    pub fn step(&mut self, visible: &[Uuid], delta: isize) -> Option<usize> {
+        let last = visible.len().checked_sub(1)?;
+        let pos = match self
+            .anchor
+            .and_then(|anchor| visible.iter().position(|u| *u == anchor))
+        {
+            Some(pos) => pos.saturating_add_signed(delta).min(last),
+            None => 0,
+        };
+
+        self.tracks.clear();
+        self.tracks.insert(visible[pos]);
+        self.anchor = Some(visible[pos]);
+        Some(pos)
+    }
+
+    pub fn select_all(&mut self, visible: &[Uuid]) {
+        self.tracks.extend(visible.iter().copied());
+    }
+
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+        self.anchor = None;
+        self.show_playlists = false;
+    }
+
+    /// Bulk actions for selected tracks. Removing is only offered inside a playlist
+    pub fn view<'a>(
+        &'a self,
+        playlists: &'a [Playlist],
+        current_playlist: Option<&'a Playlist>,
+    ) -> Element<'a, SelectionMessage> {
+        let actions = row![
+            text(format!("{} selected", self.len())),
+            horizontal_space(),
+            button("Play").on_press(SelectionMessage::Play),
+            button("Play next").on_press(SelectionMessage::Enqueue(QueuePosition::Next)),
+            button("Add to queue").on_press(SelectionMessage::Enqueue(QueuePosition::End)),
+            button("Add to playlist").on_press(SelectionMessage::TogglePlaylistMenu),
+            button("Remove from playlist")
+                .on_press_maybe(current_playlist.map(|_| SelectionMessage::RemoveFromPlaylist)),
+            button("Show in folder").on_press(SelectionMessage::Reveal),
+            button("Edit tags").on_press(SelectionMessage::EditTags),
+            button("Select all").on_press(SelectionMessage::SelectAll),
+            button("Clear").on_press(SelectionMessage::Clear),
+        ]
+        .spacing(5)
+        .align_y(iced::Alignment::Center);
+
+        let mut content = column![actions].spacing(5);
+
+        if self.show_playlists {
+            let playlists = playlists.iter().map(|playlist| {
+                button(text(&playlist.title))
+                    .style(button::text)
+                    .on_press(SelectionMessage::AddToPlaylist(playlist.clone()))
+                    .into()
+            });
+
+            content = content.push(Column::with_children(playlists));
+        }
+
+        container(content)
+            .padding(5)
+            .width(Length::Fill)
+            .style(container::rounded_box)
+            .into()
+    }
+}
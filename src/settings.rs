@@ -0,0 +1,170 @@
+use iced::{
+    widget::{
+        button, checkbox, column, container, horizontal_space, progress_bar, row, scrollable, text,
+        Column,
+    },
+    Alignment, Element, Length,
+};
+
+use crate::{analysis::AnalysisState, config::Config, replaygain::GainMode, theme};
+
+// Preamp buttons change it by this many dB
+const PREAMP_STEP: f32 = 0.5;
+
+#[derive(Debug, Clone)]
+pub enum SettingsMessage {
+    PickDevice(Option<String>), // Device by name, default one with None
+    SetGainMode(GainMode),
+    SetPreamp(f32), // dB
+    AnalyzeLoudness(bool),
+    WriteGainTags(bool),
+    Refresh,
+    Close,
+}
+
+/// Settings page. `devices` are output devices found, None while they are looked up
+pub fn view<'a>(
+    devices: Option<&'a Result<Vec<String>, String>>,
+    config: &'a Config,
+    analysis: &'a AnalysisState,
+) -> Element<'a, SettingsMessage> {
+    let picked = config.output_device.as_deref();
+    let gain = config.replay_gain;
+    let choice = |label: String, device: Option<&str>| {
+        let chosen = device == picked;
+        button(text(label))
+            .width(Length::Fill)
+            .style(if chosen {
+                button::primary
+            } else {
+                button::secondary
+            })
+            .on_press_maybe(
+                (!chosen).then(|| SettingsMessage::PickDevice(device.map(String::from))),
+            )
+            .into()
+    };
+
+    let mut rows: Vec<Element<_>> = vec![choice("Default device".to_string(), None)];
+    match devices {
+        None => rows.push(text("Looking for devices...").style(theme::muted).into()),
+        Some(Err(err)) => rows.push(
+            text(format!("Unable to list devices: {err}"))
+                .style(theme::muted)
+                .into(),
+        ),
+        Some(Ok(devices)) => {
+            rows.extend(
+                devices
+                    .iter()
+                    .map(|device| choice(device.clone(), Some(device))),
+            );
+            // Picked one is kept even when it's unplugged
+            if let Some(picked) = picked.filter(|picked| !devices.iter().any(|d| d == picked)) {
+                rows.push(choice(format!("{picked} (not connected)"), Some(picked)));
+            }
+        }
+    }
+
+    let header = row![
+        text("Settings").size(20),
+        horizontal_space(),
+        button("x").on_press(SettingsMessage::Close)
+    ];
+
+    let devices_header = row![
+        text("Output device"),
+        horizontal_space(),
+        button("Refresh")
+            .style(button::secondary)
+            .on_press(SettingsMessage::Refresh),
+    ]
+    .align_y(Alignment::Center);
+
+    let modes = GainMode::ALL.map(|mode| {
+        let chosen = mode == gain.mode;
+        button(text(mode.to_string()))
+            .style(if chosen {
+                button::primary
+            } else {
+                button::secondary
+            })
+            .on_press_maybe((!chosen).then_some(SettingsMessage::SetGainMode(mode)))
+            .into()
+    });
+    let replay_gain = row![
+        text("ReplayGain"),
+        horizontal_space(),
+        row(modes).spacing(5),
+    ]
+    .align_y(Alignment::Center);
+
+    let preamp = row![
+        text(format!("Preamp {:+.1} dB", gain.preamp)).style(if gain.mode == GainMode::Off {
+            theme::muted
+        } else {
+            text::default
+        }),
+        horizontal_space(),
+        button("-")
+            .style(button::secondary)
+            .on_press(SettingsMessage::SetPreamp(gain.preamp - PREAMP_STEP)),
+        button("+")
+            .style(button::secondary)
+            .on_press(SettingsMessage::SetPreamp(gain.preamp + PREAMP_STEP)),
+    ]
+    .spacing(5)
+    .align_y(Alignment::Center);
+
+    let analysis_status: Element<_> = match analysis {
+        AnalysisState::Off => text("").into(),
+        AnalysisState::Looking => text("Looking for tracks to measure...")
+            .style(theme::muted)
+            .into(),
+        AnalysisState::Running { done, total } => column![
+            progress_bar(0.0..=*total as f32, *done as f32).height(10),
+            text(format!("Measured {done} of {total} tracks")).style(theme::muted),
+        ]
+        .spacing(5)
+        .into(),
+        AnalysisState::Done => text("All tracks are measured").style(theme::muted).into(),
+        AnalysisState::Failed(err) => text(format!("Analysis stopped: {err}"))
+            .style(theme::muted)
+            .into(),
+    };
+
+    let analysis = column![
+        text("Loudness analysis"),
+        checkbox("Measure tracks in background", config.analyze_loudness)
+            .on_toggle(SettingsMessage::AnalyzeLoudness),
+        checkbox(
+            "Write measured ReplayGain into files that have none",
+            config.write_gain_tags
+        )
+        .on_toggle(SettingsMessage::WriteGainTags),
+        analysis_status,
+    ]
+    .spacing(10);
+
+    let content = column![
+        header,
+        devices_header,
+        scrollable(Column::with_children(rows).spacing(5)).height(200),
+        text("Picked device is remembered by name. When it goes away, default device plays until it's picked again")
+            .size(12)
+            .style(theme::muted),
+        replay_gain,
+        preamp,
+        text("Tracks without ReplayGain tags play as they are, unless analysis measured them. Gain is lowered when a track would clip")
+            .size(12)
+            .style(theme::muted),
+        analysis,
+    ]
+    .spacing(10);
+
+    container(content)
+        .padding(15)
+        .width(600)
+        .style(container::rounded_box)
+        .into()
+}
@@ -0,0 +1,824 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+};
+
+use sqlx::{sqlite::SqlitePoolOptions, SqlitePool};
+use uuid::Uuid;
+
+use crate::{
+    backup::{self, Import},
+    db::{self, DbError},
+    identity::Identity,
+    loudness::Measured,
+    models::{playlist_model::PlaylistModel, session_model::SessionModel, track_model::TrackModel},
+    playlist::Playlist,
+};
+
+/// Where library keeps tracks, playlists, stats and session. App talks to
+/// storage only through this, so it can run against a db in memory.
+/// Playlist edits return all playlists after the edit
+pub trait LibraryStore: Clone + Send + Sync + 'static {
+    /// Brings schema up to date and makes sure the liked playlist exists
+    fn migrate(&self) -> impl Future<Output = Result<(), DbError>> + Send;
+
+    /// Copy of the whole store into a new file in `dir`, named starting with `kind`
+    fn back_up(
+        &self,
+        dir: &Path,
+        kind: &str,
+    ) -> impl Future<Output = Result<PathBuf, DbError>> + Send;
+
+    // Tracks
+
+    /// Syncs tracks with files found by scan, see `db::update_track_state`
+    fn sync_tracks(
+        &self,
+        paths: &[PathBuf],
+        unread: &[PathBuf],
+    ) -> impl Future<Output = Result<(), DbError>> + Send;
+
+    fn tracks(&self) -> impl Future<Output = Result<Vec<TrackModel>, DbError>> + Send;
+
+    fn track(&self, uuid: Uuid)
+        -> impl Future<Output = Result<Option<TrackModel>, DbError>> + Send;
+
+    fn set_track_paths(
+        &self,
+        paths: &[(Uuid, PathBuf)],
+    ) -> impl Future<Output = Result<(), DbError>> + Send;
+
+    fn delete_tracks(&self, uuids: &[Uuid]) -> impl Future<Output = Result<(), DbError>> + Send;
+
+    fn identities(&self) -> impl Future<Output = Result<HashMap<Uuid, Identity>, DbError>> + Send;
+
+    /// See `db::get_unidentified`
+    fn unidentified(
+        &self,
+    ) -> impl Future<Output = Result<Vec<(Uuid, PathBuf, Option<i64>)>, DbError>> + Send;
+
+    /// See `db::save_identities`
+    fn save_identities(
+        &self,
+        results: &[(Uuid, Option<Identity>, i64)],
+    ) -> impl Future<Output = Result<(), DbError>> + Send;
+
+    /// See `db::get_loudness`
+    fn loudness(
+        &self,
+    ) -> impl Future<Output = Result<HashMap<Uuid, Option<Measured>>, DbError>> + Send;
+
+    fn save_loudness(
+        &self,
+        results: &[(Uuid, Option<Measured>)],
+    ) -> impl Future<Output = Result<(), DbError>> + Send;
+
+    // Playlists
+
+    fn playlists(&self) -> impl Future<Output = Result<Vec<PlaylistModel>, DbError>> + Send;
+
+    fn playlist_tracks(
+        &self,
+        playlist: Uuid,
+    ) -> impl Future<Output = Result<Vec<TrackModel>, DbError>> + Send;
+
+    fn create_playlist(
+        &self,
+        title: String,
+        tracks: Vec<Uuid>,
+    ) -> impl Future<Output = Result<Vec<PlaylistModel>, DbError>> + Send;
+
+    fn insert_into_playlist(
+        &self,
+        playlist: Playlist,
+        track: Uuid,
+    ) -> impl Future<Output = Result<Vec<PlaylistModel>, DbError>> + Send;
+
+    fn append_to_playlist(
+        &self,
+        playlist: Playlist,
+        tracks: Vec<Uuid>,
+    ) -> impl Future<Output = Result<Vec<PlaylistModel>, DbError>> + Send;
+
+    fn delete_from_playlist(
+        &self,
+        playlist: Playlist,
+        track: Uuid,
+    ) -> impl Future<Output = Result<Vec<PlaylistModel>, DbError>> + Send;
+
+    fn delete_many_from_playlist(
+        &self,
+        playlist: Playlist,
+        tracks: Vec<Uuid>,
+    ) -> impl Future<Output = Result<Vec<PlaylistModel>, DbError>> + Send;
+
+    // Stats
+
+    /// Rating of 0 clears it
+    fn set_rating(
+        &self,
+        uuid: Uuid,
+        rating: u8,
+    ) -> impl Future<Output = Result<(), DbError>> + Send;
+
+    /// Folds duplicates into the kept track, see `db::merge_tracks`
+    fn merge_tracks(
+        &self,
+        keep: Uuid,
+        others: &[Uuid],
+    ) -> impl Future<Output = Result<(), DbError>> + Send;
+
+    fn import_user_data(&self, import: &Import)
+        -> impl Future<Output = Result<(), DbError>> + Send;
+
+    // Session
+
+    fn session(&self) -> impl Future<Output = Result<Option<SessionModel>, DbError>> + Send;
+
+    fn save_session(
+        &self,
+        session: SessionModel,
+    ) -> impl Future<Output = Result<(), DbError>> + Send;
+}
+
+/// Store in a SQLite file, or in memory for tests
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    /// Connects on first use, so app can show its window before db is opened
+    pub fn connect_lazy(url: &str) -> Result<Self, DbError> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(5)
+            .connect_lazy(url)?;
+
+        Ok(Self { pool })
+    }
+
+    /// Empty db that lives as long as the store. Every connection to memory
+    /// is its own db, so there is only one
+    pub async fn memory() -> Result<Self, DbError> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await?;
+
+        Ok(Self { pool })
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+}
+
+impl LibraryStore for SqliteStore {
+    async fn migrate(&self) -> Result<(), DbError> {
+        db::init(&self.pool).await
+    }
+
+    async fn back_up(&self, dir: &Path, kind: &str) -> Result<PathBuf, DbError> {
+        backup::back_up(&self.pool, dir, kind).await
+    }
+
+    async fn sync_tracks(&self, paths: &[PathBuf], unread: &[PathBuf]) -> Result<(), DbError> {
+        db::update_track_state(&self.pool, paths, unread).await
+    }
+
+    async fn tracks(&self) -> Result<Vec<TrackModel>, DbError> {
+        db::get_tracks(&self.pool).await
+    }
+
+    async fn track(&self, uuid: Uuid) -> Result<Option<TrackModel>, DbError> {
+        db::get_track(&self.pool, uuid).await
+    }
+
+    async fn set_track_paths(&self, paths: &[(Uuid, PathBuf)]) -> Result<(), DbError> {
+        db::set_track_paths(&self.pool, paths).await
+    }
+
+    async fn delete_tracks(&self, uuids: &[Uuid]) -> Result<(), DbError> {
+        db::delete_tracks(&self.pool, uuids).await
+    }
+
+    async fn identities(&self) -> Result<HashMap<Uuid, Identity>, DbError> {
+        db::get_identities(&self.pool).await
+    }
+
+    async fn unidentified(&self) -> Result<Vec<(Uuid, PathBuf, Option<i64>)>, DbError> {
+        db::get_unidentified(&self.pool).await
+    }
+
+    async fn save_identities(
+        &self,
+        results: &[(Uuid, Option<Identity>, i64)],
+    ) -> Result<(), DbError> {
+        db::save_identities(&self.pool, results).await
+    }
+
+    async fn loudness(&self) -> Result<HashMap<Uuid, Option<Measured>>, DbError> {
+        db::get_loudness(&self.pool).await
+    }
+
+    async fn save_loudness(&self, results: &[(Uuid, Option<Measured>)]) -> Result<(), DbError> {
+        db::save_loudness(&self.pool, results).await
+    }
+
+    async fn playlists(&self) -> Result<Vec<PlaylistModel>, DbError> {
+        db::get_playlists(&self.pool).await
+    }
+
+    async fn playlist_tracks(&self, playlist: Uuid) -> Result<Vec<TrackModel>, DbError> {
+        db::get_tracks_from_playlist(&self.pool, playlist).await
+    }
+
+    async fn create_playlist(
+        &self,
+        title: String,
+        tracks: Vec<Uuid>,
+    ) -> Result<Vec<PlaylistModel>, DbError> {
+        db::create_playlist(&self.pool, title, tracks).await
+    }
+
+    async fn insert_into_playlist(
+        &self,
+        playlist: Playlist,
+        track: Uuid,
+    ) -> Result<Vec<PlaylistModel>, DbError> {
+        db::insert_into_playlist(&self.pool, playlist, track).await
+    }
+
+    async fn append_to_playlist(
+        &self,
+        playlist: Playlist,
+        tracks: Vec<Uuid>,
+    ) -> Result<Vec<PlaylistModel>, DbError> {
+        db::append_to_playlist(&self.pool, playlist, tracks).await
+    }
+
+    async fn delete_from_playlist(
+        &self,
+        playlist: Playlist,
+        track: Uuid,
+    ) -> Result<Vec<PlaylistModel>, DbError> {
+        db::delete_from_playlist(&self.pool, playlist, track).await
+    }
+
+    async fn delete_many_from_playlist(
+        &self,
+        playlist: Playlist,
+        tracks: Vec<Uuid>,
+    ) -> Result<Vec<PlaylistModel>, DbError> {
+        db::delete_many_from_playlist(&self.pool, playlist, tracks).await
+    }
+
+    async fn set_rating(&self, uuid: Uuid, rating: u8) -> Result<(), DbError> {
+        db::set_rating(&self.pool, uuid, rating).await
+    }
+
+    async fn merge_tracks(&self, keep: Uuid, others: &[Uuid]) -> Result<(), DbError> {
+        db::merge_tracks(&self.pool, keep, others).await
+    }
+
+    async fn import_user_data(&self, import: &Import) -> Result<(), DbError> {
+        db::import_user_data(&self.pool, import).await
+    }
+
+    async fn session(&self) -> Result<Option<SessionModel>, DbError> {
+        db::get_session(&self.pool).await
+    }
+
+    async fn save_session(&self, session: SessionModel) -> Result<(), DbError> {
+        db::save_session(&self.pool, session).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use iced::futures::StreamExt;
+
+    use crate::{
+        backup::Stats,
+        db::MIGRATOR,
+        identity,
+        playlist::LIKED,
+        test_utils::{self, TempDir},
+        track::{Track, TrackError},
+    };
+
+    use super::*;
+
+    async fn store() -> SqliteStore {
+        let store = SqliteStore::memory().await.unwrap();
+        store.migrate().await.unwrap();
+        store
+    }
+
+    /// Paths that don't exist, tracks get rows without identity
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names
+            .iter()
+            .map(|name| PathBuf::from(format!("/music/{name}")))
+            .collect()
+    }
+
+    async fn uuid_of(store: &SqliteStore, name: &str) -> Uuid {
+        let path = format!("/music/{name}");
+        let track = store.tracks().await.unwrap();
+        let track = track.iter().find(|t| t.path == path).unwrap();
+        track.uuid.parse().unwrap()
+    }
+
+    async fn playlist(store: &SqliteStore, title: &str) -> Playlist {
+        store
+            .playlists()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|p| Playlist::try_from(p).unwrap())
+            .find(|p| p.title == title)
+            .unwrap()
+    }
+
+    async fn user_tables(store: &SqliteStore) -> Vec<String> {
+        sqlx::query_scalar(
+            "SELECT name FROM sqlite_master
+             WHERE type = 'table' AND name NOT LIKE '\\_%' ESCAPE '\\' AND name NOT LIKE 'sqlite%'
+             ORDER BY name",
+        )
+        .fetch_all(store.pool())
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn migrates_old_library() {
+        let store = SqliteStore::memory().await.unwrap();
+        let pool = store.pool();
+
+        // Library as first versions left it: tracks and JSON playlists,
+        // no other tables and no record of applied migrations
+        sqlx::query(
+            "CREATE TABLE tracks (
+                uuid            TEXT PRIMARY KEY NOT NULL,
+                path            TEXT NOT NULL,
+                play_count      INTEGER NOT NULL CHECK(play_count >= 0),
+                play_minutes    REAL NOT NULL CHECK(play_minutes >= 0.0)
+            );
+            CREATE TABLE playlists (
+                uuid            TEXT PRIMARY KEY NOT NULL,
+                title           TEXT NOT NULL,
+                tracks          JSON NOT NULL
+            );",
+        )
+        .execute(pool)
+        .await
+        .unwrap();
+
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        for (uuid, path, plays) in [(first, "/music/a.mp3", 3), (second, "/music/b.flac", 7)] {
+            sqlx::query("INSERT INTO tracks VALUES ($1, $2, $3, $4)")
+                .bind(uuid.to_string())
+                .bind(path)
+                .bind(plays)
+                .bind(plays as f64 * 2.5)
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+
+        let road = Uuid::new_v4();
+        let tracks = serde_json::to_value(vec![second, first]).unwrap();
+        sqlx::query("INSERT INTO playlists VALUES ($1, $2, $3)")
+            .bind(road.to_string())
+            .bind("Road")
+            .bind(tracks)
+            .execute(pool)
+            .await
+            .unwrap();
+
+        store.migrate().await.unwrap();
+
+        // Reversible migrations are listed once for up and once for down
+        let ups: Vec<i64> = MIGRATOR
+            .iter()
+            .filter(|m| m.migration_type.is_up_migration())
+            .map(|m| m.version)
+            .collect();
+        let applied: Vec<i64> =
+            sqlx::query_scalar("SELECT version FROM _sqlx_migrations ORDER BY version")
+                .fetch_all(pool)
+                .await
+                .unwrap();
+        assert_eq!(applied, ups);
+
+        let tracks = store.tracks().await.unwrap();
+        assert_eq!(tracks.len(), 2);
+        let a = tracks.iter().find(|t| t.uuid == first.to_string()).unwrap();
+        assert_eq!(a.path, "/music/a.mp3");
+        assert_eq!(a.play_count, 3);
+        assert_eq!(a.play_minutes, 7.5);
+        assert!(tracks.iter().all(|t| t.added_at.is_some()));
+
+        let playlist = playlist(&store, "Road").await;
+        assert_eq!(playlist.uuid, road);
+        assert_eq!(playlist.tracks, vec![second, first]);
+
+        let songs = store.playlist_tracks(road).await.unwrap();
+        assert_eq!(songs.len(), 2);
+        assert_eq!(songs[0].uuid, second.to_string());
+
+        // Second start finds nothing to do
+        store.migrate().await.unwrap();
+        let liked: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM playlists WHERE title = $1")
+            .bind(LIKED)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        assert_eq!(liked, 1);
+    }
+
+    #[tokio::test]
+    async fn migrations_go_down_and_up() {
+        let store = store().await;
+        assert_eq!(
+            user_tables(&store).await,
+            [
+                "playlists",
+                "session",
+                "track_identity",
+                "track_identity_failed",
+                "track_info",
+                "track_loudness",
+                "track_rating",
+                "tracks"
+            ]
+        );
+
+        MIGRATOR.undo(store.pool(), 0).await.unwrap();
+        assert!(user_tables(&store).await.is_empty());
+
+        store.migrate().await.unwrap();
+        assert_eq!(user_tables(&store).await.len(), 8);
+    }
+
+    #[tokio::test]
+    async fn scan_keeps_tracks_that_are_still_there() {
+        let store = store().await;
+        store
+            .sync_tracks(&paths(&["a.mp3", "b.mp3"]), &[])
+            .await
+            .unwrap();
+        let a = uuid_of(&store, "a.mp3").await;
+        store.set_rating(a, 4).await.unwrap();
+
+        store
+            .sync_tracks(&paths(&["a.mp3", "b.mp3", "c.mp3"]), &[])
+            .await
+            .unwrap();
+        let tracks = store.tracks().await.unwrap();
+        assert_eq!(tracks.len(), 3);
+        assert_eq!(uuid_of(&store, "a.mp3").await, a);
+        let track = store.track(a).await.unwrap().unwrap();
+        assert_eq!(track.rating, Some(4));
+    }
+
+    #[tokio::test]
+    async fn scan_removes_tracks_whose_files_are_gone() {
+        let store = store().await;
+
+        // Quotes and SQL in names are just names
+        let names = [
+            "Don't Stop.mp3",
+            "x'); DROP TABLE tracks; --.mp3",
+            "plain.flac",
+        ];
+        store.sync_tracks(&paths(&names), &[]).await.unwrap();
+        assert_eq!(store.tracks().await.unwrap().len(), 3);
+        let gone = uuid_of(&store, "plain.flac").await;
+        store.set_rating(gone, 5).await.unwrap();
+
+        store.sync_tracks(&paths(&names[..1]), &[]).await.unwrap();
+        let tracks = store.tracks().await.unwrap();
+        assert_eq!(tracks.len(), 1);
+        assert_eq!(tracks[0].path, "/music/Don't Stop.mp3");
+        assert!(store.track(gone).await.unwrap().is_none());
+
+        // More files than SQLite takes as parameters of one statement
+        let many: Vec<PathBuf> = (0..40_000)
+            .map(|i| PathBuf::from(format!("/music/{i}.mp3")))
+            .collect();
+        store.sync_tracks(&many, &[]).await.unwrap();
+        assert_eq!(store.tracks().await.unwrap().len(), 40_000);
+
+        // Folder that couldn't be read keeps its tracks
+        store
+            .sync_tracks(&[], &[PathBuf::from("/music")])
+            .await
+            .unwrap();
+        assert_eq!(store.tracks().await.unwrap().len(), 40_000);
+    }
+
+    #[tokio::test]
+    async fn tracks_under_unread_folders_are_kept() {
+        let store = store().await;
+        store
+            .sync_tracks(&paths(&["a/1.mp3", "b/1.mp3", "c.mp3"]), &[])
+            .await
+            .unwrap();
+
+        store
+            .sync_tracks(&paths(&["c.mp3"]), &[PathBuf::from("/music/a")])
+            .await
+            .unwrap();
+        let mut kept: Vec<String> = store
+            .tracks()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|track| track.path)
+            .collect();
+        kept.sort();
+        assert_eq!(kept, ["/music/a/1.mp3", "/music/c.mp3"]);
+    }
+
+    #[tokio::test]
+    async fn playlist_edits() {
+        let store = store().await;
+        store
+            .sync_tracks(&paths(&["a.mp3", "b.mp3", "c.mp3"]), &[])
+            .await
+            .unwrap();
+        let a = uuid_of(&store, "a.mp3").await;
+        let b = uuid_of(&store, "b.mp3").await;
+        let c = uuid_of(&store, "c.mp3").await;
+
+        let playlists = store.create_playlist("Mix".into(), vec![b]).await.unwrap();
+        assert_eq!(playlists.len(), 2); // With liked
+
+        let mix = playlist(&store, "Mix").await;
+        store.insert_into_playlist(mix, a).await.unwrap();
+        let mix = playlist(&store, "Mix").await;
+        store.append_to_playlist(mix, vec![c, b]).await.unwrap();
+        let mix = playlist(&store, "Mix").await;
+        assert_eq!(mix.tracks, vec![b, a, c, b]);
+
+        // One entry goes, the first one
+        store.delete_from_playlist(mix, b).await.unwrap();
+        let mix = playlist(&store, "Mix").await;
+        assert_eq!(mix.tracks, vec![a, c, b]);
+
+        let songs: Vec<String> = store
+            .playlist_tracks(mix.uuid)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|t| t.uuid)
+            .collect();
+        assert_eq!(songs, [a, c, b].map(|u| u.to_string()));
+
+        store
+            .delete_many_from_playlist(mix, vec![a, b])
+            .await
+            .unwrap();
+        let mix = playlist(&store, "Mix").await;
+        assert_eq!(mix.tracks, vec![c]);
+
+        // Entries of tracks that left library are skipped
+        store.sync_tracks(&paths(&["a.mp3"]), &[]).await.unwrap();
+        assert!(store.playlist_tracks(mix.uuid).await.unwrap().is_empty());
+        assert!(store.playlist_tracks(Uuid::new_v4()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stats_updates() {
+        let store = store().await;
+        store
+            .sync_tracks(&paths(&["a.mp3", "b.mp3"]), &[])
+            .await
+            .unwrap();
+        let a = uuid_of(&store, "a.mp3").await;
+        let b = uuid_of(&store, "b.mp3").await;
+        let rating = |track: Option<TrackModel>| track.unwrap().rating;
+
+        store.set_rating(a, 9).await.unwrap();
+        assert_eq!(rating(store.track(a).await.unwrap()), Some(5));
+        store.set_rating(a, 0).await.unwrap();
+        assert_eq!(rating(store.track(a).await.unwrap()), None);
+
+        let stats = |play_count, rating| Stats {
+            play_count,
+            play_minutes: play_count as f64 * 3.0,
+            added_at: Some(1),
+            rating,
+        };
+        let import = Import {
+            stats: vec![(a, stats(4, Some(2))), (b, stats(6, None))],
+            playlists: vec![(LIKED.to_string(), vec![b])],
+            unmatched: 0,
+        };
+        // Same import twice is the same as once
+        store.import_user_data(&import).await.unwrap();
+        store.import_user_data(&import).await.unwrap();
+        let track = store.track(a).await.unwrap().unwrap();
+        assert_eq!(track.play_count, 4);
+        assert_eq!(track.added_at, Some(1));
+        assert_eq!(track.rating, Some(2));
+        assert_eq!(playlist(&store, LIKED).await.tracks, vec![b]);
+
+        // Merge adds up plays and leaves the rating found first. Broken
+        // playlists don't stop it
+        sqlx::query("INSERT INTO playlists VALUES ('not a uuid', 'Road', '[]')")
+            .execute(store.pool())
+            .await
+            .unwrap();
+        store.set_rating(b, 5).await.unwrap();
+        store.merge_tracks(a, &[b]).await.unwrap();
+        let kept = store.track(a).await.unwrap().unwrap();
+        assert_eq!(kept.play_count, 10);
+        assert_eq!(kept.play_minutes, 30.0);
+        assert_eq!(kept.rating, Some(2));
+        let merged = store.track(b).await.unwrap().unwrap();
+        assert_eq!((merged.play_count, merged.rating), (0, None));
+        assert_eq!(playlist(&store, LIKED).await.tracks, vec![a]);
+    }
+
+    #[tokio::test]
+    async fn loudness_is_kept_until_track_is_gone() {
+        let store = store().await;
+        store
+            .sync_tracks(&paths(&["a.flac", "b.flac"]), &[])
+            .await
+            .unwrap();
+        let a = uuid_of(&store, "a.flac").await;
+        let b = uuid_of(&store, "b.flac").await;
+        assert!(store.loudness().await.unwrap().is_empty());
+
+        let measured = Measured {
+            loudness: -9.5,
+            peak: 1.02,
+            album_loudness: Some(-10.25),
+            album_peak: Some(1.04),
+        };
+        store
+            .save_loudness(&[(a, Some(measured)), (b, None)])
+            .await
+            .unwrap();
+        let loudness = store.loudness().await.unwrap();
+        assert_eq!(loudness.len(), 2);
+        assert_eq!(loudness[&a], Some(measured));
+        assert_eq!(loudness[&b], None);
+
+        store.delete_tracks(&[b]).await.unwrap();
+        store.sync_tracks(&paths(&["b.flac"]), &[]).await.unwrap();
+        assert!(store.loudness().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unreadable_files_are_remembered_until_track_is_gone() {
+        let store = store().await;
+        let failed = || async {
+            sqlx::query_scalar::<_, String>("SELECT uuid FROM track_identity_failed ORDER BY uuid")
+                .fetch_all(store.pool())
+                .await
+                .unwrap()
+        };
+
+        // Scan leaves hashing to the background job
+        store
+            .sync_tracks(&paths(&["a.mp3", "b.mp3"]), &[])
+            .await
+            .unwrap();
+        let a = uuid_of(&store, "a.mp3").await;
+        let b = uuid_of(&store, "b.mp3").await;
+        assert!(failed().await.is_empty());
+        assert_eq!(store.unidentified().await.unwrap().len(), 2);
+
+        store
+            .save_identities(&[(a, None, 5), (b, None, 6), (Uuid::new_v4(), None, 7)])
+            .await
+            .unwrap();
+        let mut both = vec![a.to_string(), b.to_string()];
+        both.sort();
+        assert_eq!(failed().await, both);
+        let mut unidentified = store.unidentified().await.unwrap();
+        unidentified.sort_by_key(|(_, _, failed)| *failed);
+        assert_eq!(unidentified[0], (a, PathBuf::from("/music/a.mp3"), Some(5)));
+        assert!(store.identities().await.unwrap().is_empty());
+
+        store.delete_tracks(&[b]).await.unwrap();
+        assert_eq!(failed().await, vec![a.to_string()]);
+        store.delete_tracks(&[a]).await.unwrap();
+        assert!(failed().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn moved_files_keep_their_track() {
+        let store = store().await;
+        let dir = TempDir::new();
+        let [a, b, moved] = ["a.wav", "b.wav", "moved.wav"].map(|name| dir.0.join(name));
+        test_utils::tone(&a, 440.0, Duration::from_millis(200), 0.5);
+        test_utils::tone(&b, 440.0, Duration::from_millis(300), 0.5);
+        let path_of = |uuid: Uuid| {
+            let store = store.clone();
+            async move { store.track(uuid).await.unwrap().unwrap().path }
+        };
+
+        store
+            .sync_tracks(&[a.clone(), b.clone()], &[])
+            .await
+            .unwrap();
+        assert!(store.identities().await.unwrap().is_empty());
+        assert!(identity::run(store.clone())
+            .collect::<Vec<_>>()
+            .await
+            .is_empty());
+        let identities = store.identities().await.unwrap();
+        assert_eq!(identities.len(), 2);
+        let (uuid, _) = identities
+            .iter()
+            .min_by_key(|(_, identity)| identity.duration_ms)
+            .unwrap();
+
+        fs::rename(&a, &moved).unwrap();
+        store
+            .sync_tracks(&[b.clone(), moved.clone()], &[])
+            .await
+            .unwrap();
+        assert_eq!(store.tracks().await.unwrap().len(), 2);
+        assert_eq!(path_of(*uuid).await, moved.to_string_lossy());
+    }
+
+    #[tokio::test]
+    async fn backups_copy_the_library() {
+        let dir = TempDir::new();
+        let url = format!("sqlite://{}?mode=rwc", dir.0.join("library.sql").display());
+        let store = SqliteStore::connect_lazy(&url).unwrap();
+        store.migrate().await.unwrap();
+        store
+            .sync_tracks(&paths(&["a.mp3", "b.mp3"]), &[])
+            .await
+            .unwrap();
+
+        let path = store
+            .back_up(&dir.0.join("backups"), "manual")
+            .await
+            .unwrap();
+        let url = format!("sqlite://{}", path.display());
+        let backup = SqliteStore::connect_lazy(&url).unwrap();
+        assert_eq!(backup.tracks().await.unwrap().len(), 2);
+        assert!(!path.with_extension("partial").exists());
+    }
+
+    #[tokio::test]
+    async fn memory_library_is_not_backed_up() {
+        let store = store().await;
+        let dir = TempDir::new();
+        assert!(store.back_up(&dir.0, "manual").await.is_err());
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn broken_rows_are_errors() {
+        let store = store().await;
+        sqlx::query("INSERT INTO tracks VALUES ('not a uuid', '/music/a.mp3', 0, 0.0)")
+            .execute(store.pool())
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO playlists VALUES ('not a uuid', 'Road', '[]')")
+            .execute(store.pool())
+            .await
+            .unwrap();
+
+        let track = store.tracks().await.unwrap().pop().unwrap();
+        assert!(matches!(
+            Track::try_from(track),
+            Err(TrackError::Db(DbError::Corrupt(_)))
+        ));
+
+        let road = store.playlists().await.unwrap();
+        let road = road.into_iter().find(|p| p.title == "Road").unwrap();
+        assert!(matches!(Playlist::try_from(road), Err(DbError::Corrupt(_))));
+    }
+
+    #[tokio::test]
+    async fn session_round_trip() {
+        let store = store().await;
+        assert!(store.session().await.unwrap().is_none());
+
+        let session = SessionModel {
+            current_track: Some(Uuid::new_v4()),
+            position: Duration::from_secs(42),
+            queue: vec![Uuid::new_v4()],
+            ..Default::default()
+        };
+        store.save_session(session.clone()).await.unwrap();
+        store.save_session(session.clone()).await.unwrap();
+        assert_eq!(store.session().await.unwrap(), Some(session));
+    }
+}
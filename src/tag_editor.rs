@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+
+use iced::{
+    widget::{
+        button, checkbox, column, container, horizontal_space, row, text, text_input, Column,
+    },
+    Alignment, Element, Length, Task,
+};
+use uuid::Uuid;
+
+use crate::{
+    tags::{CoverChange, Field, TagChanges, TagValues},
+    theme,
+};
+
+/// Edits tags of one or many tracks. With many tracks every field can be
+/// kept as it is in each file, which is the default when files differ
+#[derive(Debug, Clone)]
+pub struct TagEditor {
+    pub tracks: Vec<(Uuid, PathBuf)>,
+    fields: Vec<FieldEdit>, // Same order as `Field::ALL`
+    cover: CoverChange,
+    covers: usize, // How many of the files have a cover
+    pub saving: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+struct FieldEdit {
+    field: Field,
+    value: String,
+    keep: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum TagEditorMessage {
+    FieldChanged(Field, String),
+    KeepToggled(Field, bool),
+    PickCover,
+    CoverPicked(Option<PathBuf>),
+    RemoveCover,
+    KeepCover,
+    Save,
+    Cancel,
+}
+
+impl TagEditor {
+    /// `values` are tags read from each of `tracks`, in the same order
+    pub fn new(tracks: Vec<(Uuid, PathBuf)>, values: Vec<TagValues>) -> Self {
+        let fields = Field::ALL
+            .iter()
+            .map(|field| {
+                let mut found = values.iter().map(|v| v.fields.get(field));
+                let first = found.next().flatten();
+                let same = found.all(|value| value == first);
+
+                FieldEdit {
+                    field: *field,
+                    value: first.filter(|_| same).cloned().unwrap_or_default(),
+                    keep: !same,
+                }
+            })
+            .collect();
+
+        Self {
+            tracks,
+            fields,
+            cover: CoverChange::Keep,
+            covers: values.iter().filter(|v| v.has_cover).count(),
+            saving: false,
+            error: None,
+        }
+    }
+
+    fn is_multi(&self) -> bool {
+        self.tracks.len() > 1
+    }
+
+    /// Fields that are not kept, with cover change
+    pub fn changes(&self) -> TagChanges {
+        TagChanges {
+            fields: self
+                .fields
+                .iter()
+                .filter(|edit| !edit.keep)
+                .map(|edit| (edit.field, edit.value.clone()))
+                .collect(),
+            cover: self.cover.clone(),
+        }
+    }
+
+    /// Save and cancel are up to the player
+    pub fn update(&mut self, message: TagEditorMessage) -> Task<TagEditorMessage> {
+        match message {
+            TagEditorMessage::FieldChanged(field, value) => {
+                if let Some(edit) = self.fields.iter_mut().find(|e| e.field == field) {
+                    edit.value = value;
+                    edit.keep = false;
+                }
+                Task::none()
+            }
+            TagEditorMessage::KeepToggled(field, keep) => {
+                if let Some(edit) = self.fields.iter_mut().find(|e| e.field == field) {
+                    edit.keep = keep;
+                }
+                Task::none()
+            }
+            TagEditorMessage::PickCover => Task::perform(
+                async {
+                    rfd::AsyncFileDialog::new()
+                        .add_filter("Images", &["jpg", "jpeg", "png"])
+                        .pick_file()
+                        .await
+                        .map(|file| file.path().to_path_buf())
+                },
+                TagEditorMessage::CoverPicked,
+            ),
+            TagEditorMessage::CoverPicked(path) => {
+                if let Some(path) = path {
+                    self.cover = CoverChange::Set(path);
+                }
+                Task::none()
+            }
+            TagEditorMessage::RemoveCover => {
+                self.cover = CoverChange::Remove;
+                Task::none()
+            }
+            TagEditorMessage::KeepCover => {
+                self.cover = CoverChange::Keep;
+                Task::none()
+            }
+            TagEditorMessage::Save | TagEditorMessage::Cancel => Task::none(),
+        }
+    }
+
+    pub fn view(&self) -> Element<'_, TagEditorMessage> {
+        let title = if self.is_multi() {
+            format!("Edit tags of {} tracks", self.tracks.len())
+        } else {
+            "Edit tags".to_string()
+        };
+
+        let fields = self.fields.iter().map(|edit| {
+            let field = edit.field;
+            let placeholder = if edit.keep { "(keep existing)" } else { "" };
+
+            let mut line = row![
+                text(field.label()).width(110),
+                text_input(placeholder, if edit.keep { "" } else { &edit.value })
+                    .on_input(move |value| TagEditorMessage::FieldChanged(field, value)),
+            ]
+            .spacing(10)
+            .align_y(Alignment::Center);
+
+            if self.is_multi() {
+                line = line.push(
+                    checkbox("Keep", edit.keep)
+                        .on_toggle(move |keep| TagEditorMessage::KeepToggled(field, keep)),
+                );
+            }
+
+            line.into()
+        });
+
+        let cover_state = match &self.cover {
+            CoverChange::Keep => format!("{} of {} have a cover", self.covers, self.tracks.len()),
+            CoverChange::Remove => "Cover will be removed".to_string(),
+            CoverChange::Set(path) => format!("New cover: {}", path.display()),
+        };
+
+        let cover = row![
+            text("Cover").width(110),
+            text(cover_state).width(Length::Fill).style(theme::muted),
+            button("Choose...").on_press(TagEditorMessage::PickCover),
+            button("Remove").on_press(TagEditorMessage::RemoveCover),
+            button("Keep").on_press_maybe(
+                (self.cover != CoverChange::Keep).then_some(TagEditorMessage::KeepCover)
+            ),
+        ]
+        .spacing(10)
+        .align_y(Alignment::Center);
+
+        let footer = row![
+            horizontal_space(),
+            button("Cancel")
+                .style(button::secondary)
+                .on_press_maybe((!self.saving).then_some(TagEditorMessage::Cancel)),
+            button(if self.saving { "Saving..." } else { "Save" })
+                .on_press_maybe((!self.saving).then_some(TagEditorMessage::Save)),
+        ]
+        .spacing(10);
+
+        let mut content = column![
+            text(title).size(20),
+            Column::with_children(fields).spacing(5),
+            cover
+        ]
+        .spacing(10);
+
+        if let Some(error) = &self.error {
+            content = content.push(text(error).style(text::danger));
+        }
+
+        container(content.push(footer))
+            .padding(15)
+            .width(600)
+            .style(container::rounded_box)
+            .into()
+    }
+}
@@ -0,0 +1,345 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    path::{Path, PathBuf},
+};
+
+use lofty::{
+    config::{ParseOptions, WriteOptions},
+    file::{AudioFile, FileType, TaggedFileExt},
+    id3::v2::{Frame, FrameId, Id3v2Tag, PopularimeterFrame},
+    mpeg::MpegFile,
+    picture::{Picture, PictureType},
+    probe::Probe,
+    tag::{Accessor, ItemKey, ItemValue, Tag, TagExt, TagItem, TagType},
+};
+
+use crate::replaygain::ReplayGain;
+
+// Most players read POPM frames of this "user" and map stars the same way
+const POPM_EMAIL: &str = "Windows Media Player 9 Series";
+const POPM_STARS: [u8; 6] = [0, 1, 64, 128, 196, 255];
+
+/// Writes 0-5 star rating into ID3v2 tag of mp3 files as POPM frame and
+/// FMPS_Rating text, and into Vorbis comments of FLAC and Ogg files as
+/// FMPS_RATING. Rating of 0 removes them. Other formats are left as they are
+pub fn write_rating(path: &Path, rating: u8) -> Result<(), String> {
+    let rating = rating.min(5);
+    let file_type = Probe::open(path)
+        .map_err(|e| e.to_string())?
+        .guess_file_type()
+        .map_err(|e| e.to_string())?
+        .file_type();
+    match file_type {
+        Some(FileType::Mpeg) => write_popm(path, rating),
+        Some(FileType::Flac | FileType::Vorbis | FileType::Opus | FileType::Speex) => {
+            write_fmps(path, rating)
+        }
+        _ => Ok(()),
+    }
+}
+
+fn write_popm(path: &Path, rating: u8) -> Result<(), String> {
+    let mut file = File::open(path).map_err(|e| e.to_string())?;
+    let mpeg = MpegFile::read_from(&mut file, ParseOptions::new()).map_err(|e| e.to_string())?;
+    let mut tag = mpeg.id3v2().cloned().unwrap_or_else(Id3v2Tag::new);
+
+    let popm = FrameId::new("POPM").map_err(|e| e.to_string())?;
+    let _ = tag.remove(&popm).count();
+    let _ = tag.remove_user_text("FMPS_Rating");
+
+    if rating > 0 {
+        let frame = PopularimeterFrame::new(POPM_EMAIL.to_string(), POPM_STARS[rating as usize], 0);
+        tag.insert(Frame::Popularimeter(frame));
+        tag.insert_user_text(
+            "FMPS_Rating".to_string(),
+            format!("{:.1}", rating as f32 / 5.0),
+        );
+    }
+
+    tag.save_to_path(path, WriteOptions::default())
+        .map_err(|e| e.to_string())
+}
+
+fn write_fmps(path: &Path, rating: u8) -> Result<(), String> {
+    let file = Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map_err(|e| e.to_string())?;
+    let mut tag = file
+        .tag(TagType::VorbisComments)
+        .cloned()
+        .unwrap_or_else(|| Tag::new(TagType::VorbisComments));
+
+    // Key has no mapping in lofty, so it's put in unchecked
+    let key = ItemKey::Unknown("FMPS_RATING".to_string());
+    tag.remove_key(&key);
+    if rating > 0 {
+        let value = ItemValue::Text(format!("{:.1}", rating as f32 / 5.0));
+        tag.insert_unchecked(TagItem::new(key, value));
+    }
+
+    tag.save_to_path(path, WriteOptions::default())
+        .map_err(|e| e.to_string())
+}
+
+/// Fields the tag editor can change
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Field {
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Track,
+    Disc,
+    Year,
+    Genre,
+    Comment,
+}
+
+/// Text of every field that is set in a file
+#[derive(Debug, Clone, Default)]
+pub struct TagValues {
+    pub fields: HashMap<Field, String>,
+    pub has_cover: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum CoverChange {
+    #[default]
+    Keep,
+    Remove,
+    Set(PathBuf), // Image file to use as front cover
+}
+
+/// What to write. Fields that are not in the map stay as they are,
+/// empty values remove the field
+#[derive(Debug, Clone, Default)]
+pub struct TagChanges {
+    pub fields: HashMap<Field, String>,
+    pub cover: CoverChange,
+}
+
+impl Field {
+    pub const ALL: [Field; 9] = [
+        Field::Title,
+        Field::Artist,
+        Field::Album,
+        Field::AlbumArtist,
+        Field::Track,
+        Field::Disc,
+        Field::Year,
+        Field::Genre,
+        Field::Comment,
+    ];
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Field::Title => "Title",
+            Field::Artist => "Artist",
+            Field::Album => "Album",
+            Field::AlbumArtist => "Album artist",
+            Field::Track => "Track",
+            Field::Disc => "Disc",
+            Field::Year => "Year",
+            Field::Genre => "Genre",
+            Field::Comment => "Comment",
+        }
+    }
+
+    fn read(&self, tag: &Tag) -> Option<String> {
+        match self {
+            Field::Title => tag.title().map(|s| s.to_string()),
+            Field::Artist => tag.artist().map(|s| s.to_string()),
+            Field::Album => tag.album().map(|s| s.to_string()),
+            Field::AlbumArtist => tag.get_string(&ItemKey::AlbumArtist).map(|s| s.to_string()),
+            Field::Track => tag.track().map(|n| n.to_string()),
+            Field::Disc => tag.disk().map(|n| n.to_string()),
+            Field::Year => tag.year().map(|n| n.to_string()),
+            Field::Genre => tag.genre().map(|s| s.to_string()),
+            Field::Comment => tag.comment().map(|s| s.to_string()),
+        }
+    }
+
+    fn write(&self, tag: &mut Tag, value: &str) -> Result<(), String> {
+        let value = value.trim();
+        let number = || {
+            value
+                .parse::<u32>()
+                .map_err(|_| format!("{} must be a number, got {value:?}", self.label()))
+        };
+
+        if value.is_empty() {
+            match self {
+                Field::Title => tag.remove_title(),
+                Field::Artist => tag.remove_artist(),
+                Field::Album => tag.remove_album(),
+                Field::AlbumArtist => tag.remove_key(&ItemKey::AlbumArtist),
+                Field::Track => tag.remove_track(),
+                Field::Disc => tag.remove_disk(),
+                Field::Year => tag.remove_year(),
+                Field::Genre => tag.remove_genre(),
+                Field::Comment => tag.remove_comment(),
+            }
+            return Ok(());
+        }
+
+        match self {
+            Field::Title => tag.set_title(value.to_string()),
+            Field::Artist => tag.set_artist(value.to_string()),
+            Field::Album => tag.set_album(value.to_string()),
+            Field::AlbumArtist => {
+                tag.insert_text(ItemKey::AlbumArtist, value.to_string());
+            }
+            Field::Track => tag.set_track(number()?),
+            Field::Disc => tag.set_disk(number()?),
+            Field::Year => tag.set_year(number()?),
+            Field::Genre => tag.set_genre(value.to_string()),
+            Field::Comment => tag.set_comment(value.to_string()),
+        }
+        Ok(())
+    }
+}
+
+pub fn read_tags(path: &Path) -> Result<TagValues, String> {
+    let file = Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map_err(|e| e.to_string())?;
+
+    let Some(tag) = file.primary_tag().or_else(|| file.first_tag()) else {
+        return Ok(TagValues::default());
+    };
+
+    let fields = Field::ALL
+        .iter()
+        .filter_map(|field| Some((*field, field.read(tag)?)))
+        .collect();
+
+    Ok(TagValues {
+        fields,
+        has_cover: tag.get_picture_type(PictureType::CoverFront).is_some(),
+    })
+}
+
+/// Writes changes into the main tag of the file, which is ID3v2 for mp3,
+/// Vorbis comments for flac and ogg, MP4 atoms for m4a and so on
+pub fn write_tags(path: &Path, changes: &TagChanges) -> Result<(), String> {
+    // Read the picture first, a missing image should not leave a half written file
+    let picture = match &changes.cover {
+        CoverChange::Set(image) => {
+            let mut image = File::open(image).map_err(|e| format!("{image:?}: {e}"))?;
+            let mut picture = Picture::from_reader(&mut image).map_err(|e| e.to_string())?;
+            picture.set_pic_type(PictureType::CoverFront);
+            Some(picture)
+        }
+        CoverChange::Keep | CoverChange::Remove => None,
+    };
+
+    let mut file = Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map_err(|e| e.to_string())?;
+
+    if file.primary_tag().is_none() {
+        file.insert_tag(Tag::new(file.primary_tag_type()));
+    }
+    let tag = file
+        .primary_tag_mut()
+        .ok_or("File format has no tags".to_string())?;
+
+    for (field, value) in &changes.fields {
+        field.write(tag, value)?;
+    }
+
+    if changes.cover != CoverChange::Keep {
+        tag.remove_picture_type(PictureType::CoverFront);
+    }
+    if let Some(picture) = picture {
+        tag.push_picture(picture);
+    }
+
+    file.save_to_path(path, WriteOptions::default())
+        .map_err(|e| e.to_string())
+}
+
+/// Writes gains as "-6.54 dB" and peaks as plain numbers, the way other
+/// taggers do, into the main tag. Album values are removed when there are none
+pub fn write_replay_gain(path: &Path, gain: &ReplayGain) -> Result<(), String> {
+    let mut file = Probe::open(path)
+        .and_then(|probe| probe.read())
+        .map_err(|e| e.to_string())?;
+
+    if file.primary_tag().is_none() {
+        file.insert_tag(Tag::new(file.primary_tag_type()));
+    }
+    let tag = file
+        .primary_tag_mut()
+        .ok_or("File format has no tags".to_string())?;
+
+    let values = [
+        (ItemKey::ReplayGainTrackGain, gain.track_gain, true),
+        (ItemKey::ReplayGainTrackPeak, gain.track_peak, false),
+        (ItemKey::ReplayGainAlbumGain, gain.album_gain, true),
+        (ItemKey::ReplayGainAlbumPeak, gain.album_peak, false),
+    ];
+    for (key, value, is_gain) in values {
+        let Some(value) = value else {
+            tag.remove_key(&key);
+            continue;
+        };
+        let text = if is_gain {
+            format!("{value:+.2} dB")
+        } else {
+            format!("{value:.6}")
+        };
+        tag.insert_text(key, text);
+    }
+
+    file.save_to_path(path, WriteOptions::default())
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use crate::test_utils::{self, TempDir};
+
+    use super::*;
+
+    /// FLAC with stream info, padding and no frames, enough to hold tags
+    fn flac(path: &Path) {
+        let mut bytes = b"fLaC".to_vec();
+        bytes.extend([0x00, 0, 0, 34]); // Stream info
+        bytes.extend(4096u16.to_be_bytes());
+        bytes.extend(4096u16.to_be_bytes());
+        bytes.extend([0; 6]); // Frame sizes
+        bytes.extend(((44_100u64 << 44) | (1 << 41) | (15 << 36)).to_be_bytes());
+        bytes.extend([0; 16]); // MD5
+        bytes.extend([0x81, 0, 0, 16]); // Last block, padding
+        bytes.extend([0; 16]);
+        fs::write(path, bytes).unwrap();
+    }
+
+    fn fmps(path: &Path) -> Option<String> {
+        let file = Probe::open(path).unwrap().read().unwrap();
+        let tag = file.tag(TagType::VorbisComments)?;
+        tag.get_string(&ItemKey::Unknown("FMPS_RATING".to_string()))
+            .map(str::to_string)
+    }
+
+    #[test]
+    fn ratings_go_where_the_format_has_a_place_for_them() {
+        let dir = TempDir::new();
+        let path = dir.0.join("a.flac");
+        flac(&path);
+        write_rating(&path, 4).unwrap();
+        assert_eq!(fmps(&path).as_deref(), Some("0.8"));
+        write_rating(&path, 0).unwrap();
+        assert_eq!(fmps(&path), None);
+
+        let wav = dir.0.join("b.wav");
+        test_utils::tone(&wav, 440.0, Duration::from_millis(100), 0.5);
+        let before = fs::read(&wav).unwrap();
+        write_rating(&wav, 5).unwrap();
+        assert_eq!(fs::read(&wav).unwrap(), before);
+    }
+}
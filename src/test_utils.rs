@@ -0,0 +1,80 @@
+//! Helpers for tests that need files on disk
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rodio::{source::SineWave, Sink, Source};
+use uuid::Uuid;
+
+use crate::{
+    output::{Output, WavOutput},
+    track::Track,
+};
+
+/// Folder of its own under temp, removed with everything in it on drop
+pub struct TempDir(pub PathBuf);
+
+impl TempDir {
+    pub fn new() -> Self {
+        let dir = std::env::temp_dir().join(format!("player-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Whether sink got to the end of what it had before `timeout`
+pub fn finishes(sink: &Sink, timeout: Duration) -> bool {
+    let start = Instant::now();
+    while !sink.empty() {
+        if start.elapsed() > timeout {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(5));
+    }
+    true
+}
+
+/// Stereo sine at 44.1 kHz with given peak, made with the file output itself
+pub fn tone(path: &Path, frequency: f32, length: Duration, amplitude: f32) {
+    let output = WavOutput::create(path, 2, 44_100).unwrap();
+    let sine = SineWave::new(frequency)
+        .take_duration(length)
+        .amplify(amplitude);
+    output.sink().append(sine);
+    assert!(finishes(output.sink(), Duration::from_secs(10)));
+}
+
+/// Three minute mp3 track named `name`, with no tags or stats. Other fields
+/// are set with struct update syntax
+pub fn track(name: &str) -> Track {
+    Track {
+        uuid: Uuid::new_v4(),
+        name: name.to_string(),
+        artist: None,
+        album: None,
+        disc_number: None,
+        track_number: None,
+        year: None,
+        genre: None,
+        bitrate: None,
+        format: "MP3".to_string(),
+        play_count: 0,
+        rating: None,
+        added_at: None,
+        duration_str: "3:00".to_string(),
+        duration: Duration::from_secs(180),
+        path: PathBuf::from(format!("/music/{name}.mp3")),
+        playlists: None,
+        show_actions: false,
+    }
+}
@@ -0,0 +1,183 @@
+use std::{fmt, path::PathBuf};
+
+use iced::{
+    theme::Palette,
+    widget::{button, container, text},
+    Color, Theme,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThemeMode {
+    Light,
+    Dark,
+    #[default]
+    System,
+    Custom, // Palette from `theme.toml`
+}
+
+/// Look of the whole app, `[appearance]` table in config
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Appearance {
+    pub theme: ThemeMode,
+    pub scale: f32,     // Multiplies size of everything
+    pub font_size: f32, // Default text size, read on start
+}
+
+/// User palette. Colours are hex strings like `#1e1e2e`
+#[derive(Debug, Clone, Deserialize)]
+struct PaletteFile {
+    name: Option<String>,
+    background: String,
+    text: String,
+    primary: String,
+    success: String,
+    danger: String,
+}
+
+impl ThemeMode {
+    pub const ALL: [ThemeMode; 4] = [
+        ThemeMode::Light,
+        ThemeMode::Dark,
+        ThemeMode::System,
+        ThemeMode::Custom,
+    ];
+}
+
+impl fmt::Display for ThemeMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ThemeMode::Light => "Light",
+            ThemeMode::Dark => "Dark",
+            ThemeMode::System => "System",
+            ThemeMode::Custom => "Custom",
+        })
+    }
+}
+
+impl Default for Appearance {
+    fn default() -> Self {
+        Self {
+            theme: ThemeMode::default(),
+            scale: 1.0,
+            font_size: 16.0,
+        }
+    }
+}
+
+impl Appearance {
+    /// Keeps broken values in config from making the app unusable
+    pub fn scale_factor(&self) -> f64 {
+        self.scale.clamp(0.5, 3.0) as f64
+    }
+
+    pub fn font_size(&self) -> f32 {
+        self.font_size.clamp(8.0, 40.0)
+    }
+
+    /// Custom mode without a usable theme file follows the system
+    pub fn theme(&self, system_dark: bool, custom: Option<&Theme>) -> Theme {
+        match (self.theme, custom) {
+            (ThemeMode::Light, _) => Theme::Light,
+            (ThemeMode::Dark, _) => Theme::Dark,
+            (ThemeMode::Custom, Some(custom)) => custom.clone(),
+            (ThemeMode::System | ThemeMode::Custom, _) if system_dark => Theme::Dark,
+            (ThemeMode::System | ThemeMode::Custom, _) => Theme::Light,
+        }
+    }
+}
+
+/// `theme.toml` next to the config file
+pub fn custom_theme_path() -> PathBuf {
+    Config::path().with_file_name("theme.toml")
+}
+
+/// Reads user palette. Missing file is not an error, there is just no custom theme
+pub fn load_custom() -> Option<Theme> {
+    let path = custom_theme_path();
+    let content = std::fs::read_to_string(&path).ok()?;
+
+    let parse = || -> Result<Theme, String> {
+        let file: PaletteFile = toml::from_str(&content).map_err(|e| e.to_string())?;
+        let color = |hex: &str| Color::parse(hex).ok_or(format!("Bad colour {hex:?}"));
+
+        let palette = Palette {
+            background: color(&file.background)?,
+            text: color(&file.text)?,
+            primary: color(&file.primary)?,
+            success: color(&file.success)?,
+            danger: color(&file.danger)?,
+        };
+
+        let name = file.name.unwrap_or_else(|| "Custom".to_string());
+        Ok(Theme::custom(name, palette))
+    };
+
+    parse()
+        .map_err(|e| eprintln!("Unable to read theme {path:?}: {e}"))
+        .ok()
+}
+
+/// Blocks on the desktop settings service, run it off the ui thread
+pub fn system_is_dark() -> bool {
+    matches!(dark_light::detect(), dark_light::Mode::Dark)
+}
+
+/// Secondary text, like details next to a track title
+pub fn muted(theme: &Theme) -> text::Style {
+    text::Style {
+        color: Some(theme.palette().text.scale_alpha(0.6)),
+    }
+}
+
+/// Background of a track row, highlighted when selected
+pub fn track_row(selected: bool) -> impl Fn(&Theme) -> container::Style {
+    move |theme| {
+        let palette = theme.extended_palette();
+        if selected {
+            container::Style::default()
+                .background(palette.primary.weak.color)
+                .color(palette.primary.weak.text)
+        } else {
+            container::Style::default()
+        }
+    }
+}
+
+/// Title of a track row. Takes text colour of the row so it stays readable when selected
+pub fn track_title(selected: bool) -> impl Fn(&Theme, button::Status) -> button::Style {
+    move |theme, status| {
+        let palette = theme.extended_palette();
+        let text_color = if selected {
+            palette.primary.weak.text
+        } else {
+            palette.background.base.text
+        };
+
+        let style = button::Style {
+            text_color,
+            ..button::Style::default()
+        };
+
+        match status {
+            button::Status::Hovered => button::Style {
+                text_color: text_color.scale_alpha(0.8),
+                ..style
+            },
+            _ => style,
+        }
+    }
+}
+
+/// Error popup in the corner of the window
+pub fn toast(theme: &Theme) -> container::Style {
+    let palette = theme.extended_palette();
+    container::Style::default()
+        .background(palette.danger.base.color)
+        .color(palette.danger.base.text)
+        .border(iced::border::rounded(5))
+}
@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+
+use iced::{
+    alignment::{Horizontal, Vertical},
+    time,
+    widget::{button, container, row, text, Column},
+    Alignment, Element, Length, Subscription,
+};
+
+use crate::theme;
+
+// How long a toast stays up unless closed
+const SHOWN_FOR: Duration = Duration::from_secs(5);
+// Older ones are dropped when more come at once
+const MAX_SHOWN: usize = 4;
+
+#[derive(Debug, Clone)]
+pub enum ToastMessage {
+    Dismiss(usize),
+    Expire(Instant),
+}
+
+/// Errors shown to user for a moment, newest at the bottom
+#[derive(Debug, Default)]
+pub struct Toasts {
+    shown: Vec<(String, Instant)>, // Text and when it came
+}
+
+impl Toasts {
+    /// Same text as one already up only restarts its time
+    pub fn push(&mut self, message: impl ToString) {
+        let message = message.to_string();
+        self.shown.retain(|(shown, _)| *shown != message);
+        self.shown.push((message, Instant::now()));
+
+        let extra = self.shown.len().saturating_sub(MAX_SHOWN);
+        self.shown.drain(..extra);
+    }
+
+    pub fn update(&mut self, message: ToastMessage) {
+        match message {
+            ToastMessage::Dismiss(i) => {
+                if i < self.shown.len() {
+                    self.shown.remove(i);
+                }
+            }
+            ToastMessage::Expire(now) => {
+                self.shown.retain(|(_, at)| now - *at < SHOWN_FOR);
+            }
+        }
+    }
+
+    /// Ticks only while something is shown
+    pub fn subscription(&self) -> Subscription<ToastMessage> {
+        if self.shown.is_empty() {
+            Subscription::none()
+        } else {
+            time::every(Duration::from_secs(1)).map(ToastMessage::Expire)
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shown.is_empty()
+    }
+
+    pub fn view(&self) -> Element<'_, ToastMessage> {
+        let toasts = self.shown.iter().enumerate().map(|(i, (message, _))| {
+            container(
+                row![
+                    text(message.as_str()).width(Length::Fill),
+                    button("x")
+                        .style(button::text)
+                        .on_press(ToastMessage::Dismiss(i)),
+                ]
+                .spacing(10)
+                .align_y(Alignment::Center),
+            )
+            .padding([5, 10])
+            .width(400)
+            .style(theme::toast)
+            .into()
+        });
+
+        container(Column::with_children(toasts).spacing(5))
+            .padding(20)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .align_x(Horizontal::Right)
+            .align_y(Vertical::Bottom)
+            .into()
+    }
+}
@@ -1,39 +1,170 @@
-use std::{
-    path::{Path, PathBuf},
-    time::Duration,
-};
+use std::{fmt, path::PathBuf, str::FromStr, time::Duration};
 
 use iced::{
-    overlay,
-    widget::{button, checkbox, container, horizontal_space, row, text, Column},
-    Element, Length, Task,
+    widget::{button, column, container, row, text, Column},
+    Alignment, Element, Length, Task,
+};
+use lofty::{
+    error::LoftyError,
+    file::{AudioFile, TaggedFileExt},
+    probe::Probe,
+    tag::Accessor,
 };
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::playlist::{self, Playlist};
+use crate::{
+    columns::ColumnLayout, db::DbError, models::track_model::TrackModel, playlist::Playlist,
+    queue::QueuePosition, theme, track_list::ROW_HEIGHT,
+};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Track {
     pub uuid: Uuid,
     pub name: String,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub disc_number: Option<u32>,
+    pub track_number: Option<u32>,
+    pub year: Option<u32>,
+    pub genre: Option<String>,
+    pub bitrate: Option<u32>, // kbps
+    pub format: String,
+    pub play_count: i64,
+    pub rating: Option<u8>,
+    pub added_at: Option<i64>,
     pub duration_str: String,
     pub duration: Duration,
     pub path: PathBuf,
     pub playlists: Option<Vec<Playlist>>,
+    pub show_actions: bool,
+}
+
+/// Why a track couldn't be made from its row
+#[derive(Debug)]
+pub enum TrackError {
+    Db(DbError),      // Row itself is broken
+    File(LoftyError), // File couldn't be read
+}
+
+impl fmt::Display for TrackError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackError::Db(err) => err.fmt(f),
+            TrackError::File(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TrackError {}
+
+impl From<DbError> for TrackError {
+    fn from(value: DbError) -> Self {
+        TrackError::Db(value)
+    }
+}
+
+impl From<LoftyError> for TrackError {
+    fn from(value: LoftyError) -> Self {
+        TrackError::File(value)
+    }
+}
+
+impl TryFrom<TrackModel> for Track {
+    type Error = TrackError;
+
+    fn try_from(value: TrackModel) -> Result<Self, Self::Error> {
+        let uuid = Uuid::from_str(&value.uuid).map_err(|e| {
+            DbError::Corrupt(format!(
+                "track {:?} has uuid {:?}: {e}",
+                value.path, value.uuid
+            ))
+        })?;
+        let path = PathBuf::from(&value.path);
+        let file_name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| DbError::Corrupt(format!("track {uuid} has path {:?}", value.path)))?
+            .to_string();
+
+        let track_metadata = Probe::open(&path)?.read()?;
+
+        let duration = track_metadata.properties().duration();
+        let duration_str = format!("{}:{}", duration.as_secs() / 60, duration.as_secs() % 60);
+        let format = path
+            .extension()
+            .map(|ext| ext.to_string_lossy().to_uppercase())
+            .unwrap_or_default();
+
+        let tag = track_metadata
+            .primary_tag()
+            .or_else(|| track_metadata.first_tag());
+
+        Ok(Self {
+            uuid,
+            name: tag
+                .and_then(|tag| tag.title())
+                .map(|s| s.to_string())
+                .unwrap_or(file_name),
+            artist: tag.and_then(|tag| tag.artist()).map(|s| s.to_string()),
+            album: tag.and_then(|tag| tag.album()).map(|s| s.to_string()),
+            disc_number: tag.and_then(|tag| tag.disk()),
+            track_number: tag.and_then(|tag| tag.track()),
+            year: tag.and_then(|tag| tag.year()),
+            genre: tag.and_then(|tag| tag.genre()).map(|s| s.to_string()),
+            bitrate: track_metadata.properties().audio_bitrate(),
+            format,
+            play_count: value.play_count,
+            rating: value.rating.map(|r| r.clamp(1, 5) as u8),
+            added_at: value.added_at,
+            duration_str,
+            duration,
+            path,
+            playlists: None,
+            show_actions: false,
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum TrackMessage {
     ChooseTrack,
+    Select,
     OpenPlaylistMenu(Vec<Playlist>),
     ClosePlaylistMenu,
     ToggleInPlaylist(Playlist),
-    AddToQueue,
+    ToggleActions,
+    AddToQueue(QueuePosition),
+    QueueAlbum(QueuePosition),
+    QueueArtist(QueuePosition),
+    ToggleLiked,
+    Rate(u8),
+    EditTags,
     TrackEnd(Result<(), String>),
 }
 
 impl Track {
+    /// Reads metadata from the file again, after tags were changed
+    pub fn reload(&self) -> Result<Track, TrackError> {
+        Track::try_from(TrackModel {
+            uuid: self.uuid.to_string(),
+            path: self.path.to_string_lossy().to_string(),
+            play_count: self.play_count,
+            play_minutes: 0.0, // Not kept in `Track`
+            added_at: self.added_at,
+            rating: self.rating.map(i64::from),
+        })
+    }
+
+    /// Case-insensitive search over name, artist and album
+    pub fn matches(&self, query: &str) -> bool {
+        let query = query.to_lowercase();
+        [Some(&self.name), self.artist.as_ref(), self.album.as_ref()]
+            .into_iter()
+            .flatten()
+            .any(|field| field.to_lowercase().contains(&query))
+    }
+
     pub fn update(&mut self, message: TrackMessage) -> Task<TrackMessage> {
         match message {
             TrackMessage::ChooseTrack => {
@@ -42,6 +173,7 @@ impl Track {
                 println!("{path:#?}");
                 Task::none()
             }
+            TrackMessage::Select => Task::none(),
             TrackMessage::OpenPlaylistMenu(playlists) => {
                 self.playlists = Some(playlists);
                 Task::none()
@@ -50,34 +182,123 @@ impl Track {
                 self.playlists = None;
                 Task::none()
             }
-            TrackMessage::ToggleInPlaylist(playlist) => {
+            TrackMessage::ToggleInPlaylist(_playlist) => Task::none(),
+            TrackMessage::ToggleActions => {
+                self.show_actions = !self.show_actions;
                 Task::none()
             }
-            TrackMessage::AddToQueue => {
-                println!("Added to queue");
+            TrackMessage::AddToQueue(_)
+            | TrackMessage::QueueAlbum(_)
+            | TrackMessage::QueueArtist(_) => {
+                self.show_actions = false;
+                Task::none()
+            }
+            TrackMessage::ToggleLiked | TrackMessage::Rate(_) | TrackMessage::EditTags => {
                 Task::none()
             }
             TrackMessage::TrackEnd(_res) => Task::none(),
         }
     }
 
-    pub fn view(&self) -> Element<TrackMessage> {
-        let name = button(text(&self.name))
-            .on_press(TrackMessage::ChooseTrack)
-            .width(Length::FillPortion(6));
+    /// Clicking the name selects the track, selection itself is kept by the player.
+    /// Rows own their data and have a fixed height so the list can cache and skip them
+    pub fn view(
+        &self,
+        selected: bool,
+        liked: bool,
+        layout: &ColumnLayout,
+    ) -> Element<'static, TrackMessage> {
+        let name = button(text(self.name.clone()))
+            .on_press(TrackMessage::Select)
+            .style(theme::track_title(selected))
+            .width(Length::Fill);
 
-        let duration = text(&self.duration_str)
-            .width(Length::FillPortion(1))
-            .center();
+        let cells = layout.row(self, name.into());
+
+        let add_button =
+            container(button("+").on_press(TrackMessage::AddToQueue(QueuePosition::End)))
+                .width(Length::FillPortion(1))
+                .center_x(Length::Fill);
 
-        let add_button = container(button("+").on_press(TrackMessage::AddToQueue))
+        let actions_button = container(button("...").on_press(TrackMessage::ToggleActions))
             .width(Length::FillPortion(1))
             .center_x(Length::Fill);
 
-        let add_to_liked = container(button("<3").on_press(TrackMessage::OpenPlaylistMenu(vec![])))
+        // Filled when the track is in the liked playlist
+        let like = button("<3")
+            .on_press(TrackMessage::ToggleLiked)
+            .style(if liked {
+                button::danger
+            } else {
+                button::secondary
+            });
+        let add_to_liked = container(like)
             .width(Length::FillPortion(1))
             .center_x(Length::Fill);
 
+        let buttons = row![add_button, actions_button, add_to_liked];
+
+        container(row![cells, buttons].align_y(Alignment::Center))
+            .height(ROW_HEIGHT)
+            .center_y(ROW_HEIGHT)
+            .style(theme::track_row(selected))
+            .into()
+    }
+
+    /// Stars to click, the one that is set clears rating
+    fn rating_view(&self) -> Element<'_, TrackMessage> {
+        let current = self.rating.unwrap_or(0);
+        let stars = (1..=5).map(|stars| {
+            button(text(stars.to_string()))
+                .style(if stars <= current {
+                    button::primary
+                } else {
+                    button::secondary
+                })
+                .on_press(TrackMessage::Rate(if stars == current { 0 } else { stars }))
+                .into()
+        });
+
+        row(stars).spacing(2).into()
+    }
+
+    /// Actions and playlist menus opened from the row. Shown apart from the list
+    /// so rows keep their height
+    pub fn menu(&self) -> Option<Element<'_, TrackMessage>> {
+        if !self.show_actions && self.playlists.is_none() {
+            return None;
+        }
+
+        let mut actions_container = Column::new();
+        if self.show_actions {
+            let action = |label, enabled: bool, message| {
+                button(label).on_press_maybe(enabled.then_some(message))
+            };
+
+            let (album, artist) = (self.album.is_some(), self.artist.is_some());
+            use QueuePosition::{End, Next};
+
+            actions_container = column![
+                action("Play next", true, TrackMessage::AddToQueue(Next)),
+                action("Add to queue", true, TrackMessage::AddToQueue(End)),
+                action("Play album next", album, TrackMessage::QueueAlbum(Next)),
+                action("Add album to queue", album, TrackMessage::QueueAlbum(End)),
+                action("Play artist next", artist, TrackMessage::QueueArtist(Next)),
+                action(
+                    "Add artist to queue",
+                    artist,
+                    TrackMessage::QueueArtist(End)
+                ),
+                action(
+                    "Add to playlist...",
+                    true,
+                    TrackMessage::OpenPlaylistMenu(vec![])
+                ),
+                action("Edit tags...", true, TrackMessage::EditTags),
+                self.rating_view(),
+            ];
+        }
+
         let mut playlist_container: Vec<Element<'_, TrackMessage>> = vec![];
         if let Some(playlists) = &self.playlists {
             for playlist in playlists {
@@ -89,12 +310,26 @@ impl Track {
             }
         }
 
-        let playlist_container = container(Column::from_vec(playlist_container));
-
-        let buttons = row![add_button, add_to_liked];
+        let close = button("x").on_press(if self.show_actions {
+            TrackMessage::ToggleActions
+        } else {
+            TrackMessage::ClosePlaylistMenu
+        });
 
-        let content = row![name, duration, buttons, playlist_container].into();
+        let content = row![
+            text(&self.name).width(Length::Fill).style(theme::muted),
+            actions_container,
+            Column::from_vec(playlist_container),
+            close
+        ]
+        .spacing(10);
 
-        return content;
+        Some(
+            container(content)
+                .padding(5)
+                .width(Length::Fill)
+                .style(container::rounded_box)
+                .into(),
+        )
     }
 }
@@ -0,0 +1,181 @@
+use std::{collections::HashSet, ops::Range};
+
+use iced::{
+    widget::{column, lazy, scrollable, Column, Space},
+    Element, Length, Task,
+};
+use uuid::Uuid;
+
+use crate::{
+    columns::{ColumnLayout, TrackColumn},
+    selection::Selection,
+    track::{Track, TrackMessage},
+};
+
+/// Every row has the same height, so rows in view can be found from scroll offset alone
+pub const ROW_HEIGHT: f32 = 40.0;
+// Extra rows rendered around the viewport. The rendered range also moves in
+// steps of this size, so small scrolls reuse cached rows
+const OVERSCAN: usize = 10;
+const DEFAULT_HEIGHT: f32 = 768.0;
+
+/// Rows of the track list in the order they are shown and the part of it
+/// that is scrolled into view. Only that part is turned into widgets
+#[derive(Debug, Clone)]
+pub struct TrackList {
+    rows: Vec<usize>, // Indices into the list of tracks
+    revision: u64,    // Bumped on every refresh, drops cached rows
+    offset: f32,
+    height: f32,
+}
+
+#[derive(Debug, Clone)]
+pub enum TrackListMessage {
+    Scrolled(scrollable::Viewport),
+    Track(usize, Uuid, TrackMessage),
+}
+
+/// Everything rendered rows depend on. Rows are only rebuilt when it changes
+#[derive(Hash)]
+struct RowsKey {
+    revision: u64,
+    range: Range<usize>,
+    selected: Vec<bool>,
+    liked: Vec<bool>,
+    columns: Vec<(TrackColumn, u32)>, // Column and its width bits
+}
+
+impl Default for TrackList {
+    fn default() -> Self {
+        Self {
+            rows: vec![],
+            revision: 0,
+            offset: 0.0,
+            height: DEFAULT_HEIGHT,
+        }
+    }
+}
+
+impl TrackList {
+    /// Filters by search and sorts by layout. Has to be called whenever any of them
+    /// or the tracks themselves change
+    pub fn refresh(&mut self, tracks: &[Track], search: &str, layout: &ColumnLayout) {
+        let mut rows: Vec<usize> = tracks
+            .iter()
+            .enumerate()
+            .filter(|(_, track)| search.is_empty() || track.matches(search))
+            .map(|(i, _)| i)
+            .collect();
+
+        if !layout.sort.is_empty() {
+            rows.sort_by(|a, b| layout.compare(&tracks[*a], &tracks[*b]));
+        }
+
+        self.rows = rows;
+        self.revision += 1;
+    }
+
+    pub fn rows(&self) -> &[usize] {
+        &self.rows
+    }
+
+    pub fn scrolled(&mut self, viewport: scrollable::Viewport) {
+        self.offset = viewport.absolute_offset().y;
+        self.height = viewport.bounds().height;
+    }
+
+    /// Window height is an upper bound for the list until it is scrolled
+    pub fn resized(&mut self, height: f32) {
+        self.height = height;
+    }
+
+    /// Scrolls just enough to bring row at `pos` into view
+    pub fn reveal<Message>(&mut self, pos: usize) -> Task<Message> {
+        let top = pos as f32 * ROW_HEIGHT;
+        let offset = if top < self.offset {
+            top
+        } else if top + ROW_HEIGHT > self.offset + self.height {
+            top + ROW_HEIGHT - self.height
+        } else {
+            return Task::none();
+        };
+
+        // Scrollable doesn't report offsets set from code
+        self.offset = offset;
+        scrollable::scroll_to(list_id(), scrollable::AbsoluteOffset { x: 0.0, y: offset })
+    }
+
+    /// Rows to render, viewport and overscan rounded out to whole steps
+    fn range(&self) -> Range<usize> {
+        // Scrollable clamps its offset the same way when the list gets shorter
+        let max_offset = self.rows.len() as f32 * ROW_HEIGHT - self.height;
+        let offset = self.offset.min(max_offset).max(0.0);
+
+        let first = (offset / ROW_HEIGHT) as usize;
+        let shown = (self.height / ROW_HEIGHT).ceil() as usize;
+
+        let start = first.saturating_sub(OVERSCAN) / OVERSCAN * OVERSCAN;
+        let end = (first + shown + OVERSCAN).div_ceil(OVERSCAN) * OVERSCAN;
+
+        start.min(self.rows.len())..end.min(self.rows.len())
+    }
+
+    pub fn view<'a>(
+        &self,
+        tracks: &'a [Track],
+        selection: &Selection,
+        liked: &HashSet<Uuid>,
+        layout: &'a ColumnLayout,
+    ) -> Element<'a, TrackListMessage> {
+        let range = self.range();
+        let rows = &self.rows[range.clone()];
+
+        let key = RowsKey {
+            revision: self.revision,
+            range: range.clone(),
+            selected: rows
+                .iter()
+                .map(|i| selection.is_selected(&tracks[*i].uuid))
+                .collect(),
+            liked: rows
+                .iter()
+                .map(|i| liked.contains(&tracks[*i].uuid))
+                .collect(),
+            columns: layout
+                .columns
+                .iter()
+                .map(|spec| (spec.column, spec.width.to_bits()))
+                .collect(),
+        };
+
+        let rows = rows.to_vec();
+        let visible = lazy(key, move |key| {
+            let flags = key.selected.iter().zip(&key.liked);
+            let rows = rows.iter().zip(flags).map(|(i, (selected, liked))| {
+                let (i, uuid) = (*i, tracks[*i].uuid);
+                tracks[i]
+                    .view(*selected, *liked, layout)
+                    .map(move |message| TrackListMessage::Track(i, uuid, message))
+            });
+
+            Column::with_children(rows)
+        });
+
+        let above = range.start as f32 * ROW_HEIGHT;
+        let below = (self.rows.len() - range.end) as f32 * ROW_HEIGHT;
+
+        scrollable(column![
+            Space::with_height(above),
+            visible,
+            Space::with_height(below)
+        ])
+        .id(list_id())
+        .on_scroll(TrackListMessage::Scrolled)
+        .height(Length::Fill)
+        .into()
+    }
+}
+
+fn list_id() -> scrollable::Id {
+    scrollable::Id::new("track_list")
+}
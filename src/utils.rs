@@ -1,13 +1,53 @@
-use std::path::PathBuf;
+use std::{path::Path, process::Command, time::Duration};
 
-pub fn path_buf_vec_to_string(paths: &[PathBuf]) -> String {
-    let mut res = String::new();
-    for path in paths {
-        res.push_str(&format!("'{}', ", path.to_str().unwrap()));
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 3600 {
+        format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+    } else {
+        format!("{}:{:02}", secs / 60, secs % 60)
     }
+}
+
+/// Opens the folder containing `path` in the system file manager
+pub fn reveal_in_file_manager(path: &Path) -> std::io::Result<()> {
+    if cfg!(target_os = "macos") {
+        Command::new("open").arg("-R").arg(path).spawn()?;
+    } else if cfg!(target_os = "windows") {
+        Command::new("explorer")
+            .arg(format!("/select,{}", path.display()))
+            .spawn()?;
+    } else {
+        let dir = path.parent().unwrap_or(path);
+        Command::new("xdg-open").arg(dir).spawn()?;
+    }
+
+    Ok(())
+}
+
+/// Unix time as `YYYY-MM-DD` in UTC
+pub fn format_date(unix: i64) -> String {
+    // Days to civil date, see http://howardhinnant.github.io/date_algorithms.html
+    let z = unix.div_euclid(86400) + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
 
-    res.pop();
-    res.pop();
+    format!("{year:04}-{month:02}-{day:02}")
+}
 
-    return res;
+/// Background jobs shouldn't take time from playback or the window. On Linux
+/// this only lowers the calling thread
+pub fn lower_priority() {
+    // SAFETY: nice only changes the priority of the calling thread and takes
+    // no pointers, a failure just leaves the priority as it was
+    #[cfg(target_os = "linux")]
+    unsafe {
+        libc::nice(10);
+    }
 }